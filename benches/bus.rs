@@ -0,0 +1,59 @@
+// Bus read/write throughput on a busy loop that mixes RAM, PPU register, and PRG accesses
+// Run with `cargo bench --bench bus`
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes::bus::Bus;
+use nes::cpu::CPU;
+
+const INSTRUCTIONS: u64 = 10_000;
+
+// $8000: LDX #$00
+// $8002: LDA $0200,X
+//        STA $0300,X
+//        LDA $2002
+//        LDA $8000,X
+//        INX
+//        BNE $8002
+//        JMP $8000
+const PROGRAM: [u8; 20] = [
+    0xA2, 0x00,
+    0xBD, 0x00, 0x02,
+    0x9D, 0x00, 0x03,
+    0xAD, 0x02, 0x20,
+    0xBD, 0x00, 0x80,
+    0xE8,
+    0xD0, 0xF0,
+    0x4C, 0x00, 0x80,
+];
+
+fn busy_loop_cpu() -> CPU {
+    let mut image = vec![0; 0x10000];
+    image[0x8000..0x8000 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+    image[0xFFFC] = 0x00;
+    image[0xFFFD] = 0x80;
+
+    let mut cpu = CPU::new(Bus::new_headless(image).unwrap());
+    cpu.reset();
+    cpu
+}
+
+fn bus_busy_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+
+    group.bench_function("busy_loop", |b| {
+        let mut cpu = busy_loop_cpu();
+        b.iter(|| {
+            let mut remaining = INSTRUCTIONS;
+            cpu.run_with_callback(|_| {
+                remaining -= 1;
+                remaining > 0
+            });
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bus_busy_loop);
+criterion_main!(benches);
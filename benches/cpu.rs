@@ -0,0 +1,119 @@
+// CPU core throughput on small synthetic programs, run headlessly on a flat memory image
+// Throughput is reported in CPU cycles per second, the real console runs at about 1.79M
+// Run with `cargo bench --bench cpu`
+//
+// Baseline measured on the development machine, after per-dot PPU stepping landed:
+//   cpu/tight_loop   16.8M cycles/s     addressing/zero_page   233M/s
+//   cpu/memory_loop  19.9M cycles/s     addressing/absolute    121M/s
+//   cpu/branch_loop  15.0M cycles/s     addressing/indirect_x   82M/s
+//                                       addressing/indirect_y   24M/s, pays for a page cross cycle
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes::asm::{assemble, ORIGIN};
+use nes::bus::Bus;
+use nes::cpu::{AddressingMode, Mem, CPU};
+
+const CYCLES: u64 = 100_000;
+// Operand resolutions per iteration of the addressing mode benches
+const RESOLUTIONS: u64 = 1_000;
+
+const TIGHT_LOOP: &str = "
+    INX
+    INY
+    CLC
+    ADC #$01
+    JMP $8000
+";
+
+const MEMORY_LOOP: &str = "
+    LDX #$00
+    LDA $0200,X     ; $8002
+    STA $0300,X
+    INC $0400,X
+    LDA ($10),Y
+    INX
+    BNE $8002
+    JMP $8000
+";
+
+const BRANCH_LOOP: &str = "
+    INX
+    TXA
+    AND #$01
+    BEQ $800A
+    BMI $800A
+    BVS $800A
+    BNE $8000       ; $800A
+    BPL $8000
+";
+
+// Load bytes at the assembler's origin and point the reset vector at them
+fn cpu_with_program(program: &[u8]) -> CPU {
+    let origin = ORIGIN as usize;
+    let mut image = vec![0; 0x10000];
+    image[origin..origin + program.len()].copy_from_slice(program);
+    image[0xFFFC..0xFFFE].copy_from_slice(&ORIGIN.to_le_bytes());
+
+    let mut cpu = CPU::new(Bus::new_headless(image).unwrap());
+    cpu.reset();
+    cpu
+}
+
+fn cpu_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(CYCLES));
+
+    for (name, program) in [
+        ("tight_loop", TIGHT_LOOP),
+        ("memory_loop", MEMORY_LOOP),
+        ("branch_loop", BRANCH_LOOP),
+    ] {
+        group.bench_function(name, |b| {
+            let mut cpu = cpu_with_program(&assemble(program).unwrap());
+            b.iter(|| cpu.run_cycles(CYCLES as usize))
+        });
+    }
+
+    group.finish();
+}
+
+// Resolve an operand address over and over, only (zp),Y crosses a page and so also ticks the bus
+fn addressing_modes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("addressing");
+    group.throughput(Throughput::Elements(RESOLUTIONS));
+
+    for (name, mode) in [
+        ("zero_page", AddressingMode::ZeroPage),
+        ("zero_page_x", AddressingMode::ZeroPageX),
+        ("zero_page_y", AddressingMode::ZeroPageY),
+        ("absolute", AddressingMode::Absolute),
+        ("absolute_x", AddressingMode::AbsoluteX),
+        ("absolute_y", AddressingMode::AbsoluteY),
+        ("indirect_x", AddressingMode::IndirectX),
+        ("indirect_y", AddressingMode::IndirectY),
+    ] {
+        group.bench_function(name, |b| {
+            // Operand bytes $10 $02, and pointers to $02F0 at $10 for (zp),Y and at $30 for (zp,X)
+            let mut cpu = cpu_with_program(&[0x10, 0x02]);
+            cpu.bus.mem_write(0x0010, 0xF0);
+            cpu.bus.mem_write(0x0011, 0x02);
+            cpu.bus.mem_write(0x0030, 0xF0);
+            cpu.bus.mem_write(0x0031, 0x02);
+            cpu.register_x = 0x20;
+            cpu.register_y = 0x20;
+
+            b.iter(|| {
+                let mut sum = 0u16;
+                for _ in 0..RESOLUTIONS {
+                    sum = sum.wrapping_add(cpu.get_absolute_address(&mode, 0x8000, true));
+                }
+                sum
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, cpu_throughput, addressing_modes);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+// How closely each way of waiting out a frame hits its deadline
+// Every iteration waits for a deadline WAIT ahead, so the spread of the reported times is the frame jitter
+// Run with `cargo bench --bench pacing`
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use nes::pacing::wait_until;
+
+// Long enough that the spin-wait mode sleeps before it spins
+const WAIT: Duration = Duration::from_millis(4);
+
+fn frame_pacing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pacing");
+    group.sample_size(50);
+
+    for (name, spin) in [("sleep", false), ("spin_wait", true)] {
+        group.bench_function(name, |b| b.iter(|| wait_until(Instant::now() + WAIT, spin)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, frame_pacing);
+criterion_main!(benches);
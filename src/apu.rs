@@ -1 +1,875 @@
-// Responsible for generating specific five-channel based sounds, that made NES chiptunes so recognizable
\ No newline at end of file
+// The APU generates the NES's sound from five channels: two pulse waves, a triangle wave, noise, and DMC samples.
+// It is clocked once per CPU cycle and its mixed output is sampled down to the host's audio rate.
+// DMC playback is not implemented yet, so that channel stays silent.
+
+//  Registers:
+//  $4000-$4003  Pulse 1         Duty/Volume, Sweep, Timer Low, Length/Timer High
+//  $4004-$4007  Pulse 2         Same layout as Pulse 1
+//  $4008-$400B  Triangle        Linear Counter, Unused, Timer Low, Length/Timer High
+//  $400C-$400F  Noise           Volume, Unused, Mode/Period, Length
+//  $4010-$4013  DMC             Not implemented
+//  $4015        Status          Channel enables on write, length counter status on read
+//  $4017        Frame Counter   Sequencer mode and IRQ inhibit
+
+const CPU_CLOCK: f64 = 1_789_773.0;
+pub const SAMPLE_RATE: f64 = 44_100.0;
+
+// Length counter load values, indexed by the top five bits of the length register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// 12.5%, 25%, 50%, and 25% negated
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Noise timer periods in APU cycles (NTSC)
+const NOISE_PERIODS: [u16; 16] = [2, 4, 8, 16, 32, 48, 64, 80, 101, 127, 190, 254, 381, 508, 1017, 2034];
+
+// Frame counter steps in CPU cycles, quarter frames clock the linear counter and half frames the length counters
+const FOUR_STEP_SEQUENCE: [u32; 4] = [7457, 14913, 22371, 29829];
+const FIVE_STEP_SEQUENCE: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+
+lazy_static! {
+    // Mixer output for pulse1 + pulse2, 0-30
+    static ref PULSE_TABLE: [f32; 31] = std::array::from_fn(|n| {
+        if n == 0 { 0.0 } else { 95.52 / (8128.0 / n as f32 + 100.0) }
+    });
+
+    // Mixer output for 3 * triangle + 2 * noise + DMC, 0-202
+    static ref TND_TABLE: [f32; 203] = std::array::from_fn(|n| {
+        if n == 0 { 0.0 } else { 163.67 / (24329.0 / n as f32 + 100.0) }
+    });
+}
+
+// Extra sound channels on the cartridge (VRC6, MMC5, Sunsoft 5B), mixed in after the console's own channels
+// The sample is on the same scale as the APU's mix, so a full volume channel sits around 1.0
+pub trait ExpansionAudio {
+    fn sample(&self) -> f32;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::DMC];
+}
+
+pub struct APU {
+    pub cycles: u64,
+    sample_timer: f64,
+    samples: Vec<(u64, f32)>,
+
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+
+    frame_cycle: u32,
+    five_step_mode: bool,
+
+    master_volume: f32,
+    muted: [bool; 5],
+
+    // Sound from the cartridge, plugged in by mappers that have it
+    expansion: Option<Box<dyn ExpansionAudio>>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            cycles: 0,
+            sample_timer: 0.0,
+            samples: Vec::new(),
+            pulse1: Pulse::new(),
+            pulse2: Pulse::new(),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            frame_cycle: 0,
+            five_step_mode: false,
+            master_volume: 1.0,
+            muted: [false; 5],
+            expansion: None,
+        }
+    }
+
+    // Advance one CPU cycle, producing a sample each time a host sample period has elapsed
+    pub fn step(&mut self) {
+        self.cycles += 1;
+
+        // The triangle timer runs at the CPU rate, everything else once per APU cycle (every other CPU cycle)
+        self.triangle.clock_timer();
+        if self.cycles & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer();
+        }
+
+        self.clock_frame_counter();
+
+        self.sample_timer += SAMPLE_RATE;
+        if self.sample_timer >= CPU_CLOCK {
+            self.sample_timer -= CPU_CLOCK;
+            self.samples.push((self.cycles, self.output()));
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4015 => {
+                self.pulse1.set_enabled(data & 0b0001 != 0);
+                self.pulse2.set_enabled(data & 0b0010 != 0);
+                self.triangle.set_enabled(data & 0b0100 != 0);
+                self.noise.set_enabled(data & 0b1000 != 0);
+            }
+            0x4017 => {
+                self.five_step_mode = data & 0x80 != 0;
+                self.frame_cycle = 0;
+
+                // Switching to the five step sequence clocks everything immediately
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // $4015 read, one bit per channel whose length counter is still running
+    pub fn read_status(&self) -> u8 {
+        (self.pulse1.length_counter > 0) as u8
+            | ((self.pulse2.length_counter > 0) as u8) << 1
+            | ((self.triangle.length_counter > 0) as u8) << 2
+            | ((self.noise.length_counter > 0) as u8) << 3
+    }
+
+    // Mix all channels into a single sample
+    // Muting happens here, after the channels have run, so it never changes their timing
+    pub fn output(&self) -> f32 {
+        let pulse1 = self.channel_output(Channel::Pulse1, self.pulse1.output());
+        let pulse2 = self.channel_output(Channel::Pulse2, self.pulse2.output());
+        let triangle = self.channel_output(Channel::Triangle, self.triangle.output());
+        let noise = self.channel_output(Channel::Noise, self.noise.output());
+        let dmc = 0;
+
+        // The console's mixer is non-linear, each group of channels is looked up in its own table
+        let pulse_out = PULSE_TABLE[(pulse1 + pulse2) as usize];
+        let tnd_out = TND_TABLE[(3 * triangle + 2 * noise + dmc) as usize];
+
+        let expansion_out = self.expansion.as_ref().map_or(0.0, |expansion| expansion.sample());
+
+        ((pulse_out + tnd_out + expansion_out) * self.master_volume).clamp(-1.0, 1.0)
+    }
+
+    // Mix a cartridge sound source in with the console's channels, None unplugs it
+    pub fn set_expansion_audio(&mut self, expansion: Option<Box<dyn ExpansionAudio>>) {
+        self.expansion = expansion;
+    }
+
+    // Scale applied to the mixed output, 1.0 is full volume
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume;
+    }
+
+    // Power cycle, the channels and frame sequencer start over
+    // The volume and mute choices belong to the front-end and the expansion sound to the cartridge, both stay
+    pub fn power_on(&mut self) {
+        *self = APU {
+            master_volume: self.master_volume,
+            muted: self.muted,
+            expansion: self.expansion.take(),
+            ..APU::new()
+        };
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_channel_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // Mute every channel except this one, or unmute everything when it is already the only one playing
+    // The mutes only affect the mix, the game's $4015 enables are untouched
+    pub fn solo_channel(&mut self, channel: Channel) {
+        let soloed = Channel::ALL.iter().all(|&other| self.is_channel_muted(other) == (other != channel));
+        for other in Channel::ALL {
+            self.muted[other as usize] = !soloed && other != channel;
+        }
+    }
+
+    // Take every sample produced since the last drain, tagged with the CPU cycle it was produced on
+    // The cycle count never resets, so timestamps keep increasing across frames
+    pub fn drain_samples(&mut self) -> Vec<(u64, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn channel_output(&self, channel: Channel, output: u8) -> u8 {
+        if self.is_channel_muted(channel) { 0 } else { output }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        if self.five_step_mode {
+            match self.frame_cycle {
+                c if c == FIVE_STEP_SEQUENCE[0] || c == FIVE_STEP_SEQUENCE[2] => self.clock_quarter_frame(),
+                c if c == FIVE_STEP_SEQUENCE[1] => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                c if c == FIVE_STEP_SEQUENCE[4] => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                    self.frame_cycle = 0;
+                }
+                _ => {}
+            }
+        } else {
+            match self.frame_cycle {
+                c if c == FOUR_STEP_SEQUENCE[0] || c == FOUR_STEP_SEQUENCE[2] => self.clock_quarter_frame(),
+                c if c == FOUR_STEP_SEQUENCE[1] => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                c if c == FOUR_STEP_SEQUENCE[3] => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                    self.frame_cycle = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_length_counter();
+        self.pulse2.clock_length_counter();
+        self.triangle.clock_length_counter();
+        self.noise.clock_length_counter();
+    }
+}
+
+// Volume for the pulse and noise channels, either a constant or a decay from 15 down to 0
+// Register bits: --LC VVVV, L loops the decay, C selects constant volume, V is the volume or the decay period
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.period = data & 0x0F;
+    }
+
+    // Clocked every quarter frame, a pending start restarts the decay at 15
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant { self.period } else { self.decay }
+    }
+}
+
+// Square wave with four duty cycles
+pub struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    envelope: Envelope,
+    length_halt: bool,
+    pub length_counter: u8,
+    timer_period: u16,
+    timer: u16,
+}
+
+impl Pulse {
+    fn new() -> Self {
+        Pulse {
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            envelope: Envelope::new(),
+            length_halt: false,
+            length_counter: 0,
+            timer_period: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                // The length counter halt shares its bit with the envelope loop flag
+                self.length_halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.duty_step = 0;
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length_counter(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    // Periods under 8 would be ultrasonic and are silenced by the hardware
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.timer_period < 8 || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// 32 step triangle wave with no volume control
+pub struct Triangle {
+    enabled: bool,
+    control: bool,
+    linear_reload: u8,
+    linear_reload_flag: bool,
+    linear_counter: u8,
+    pub length_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Triangle {
+    fn new() -> Self {
+        Triangle {
+            enabled: false,
+            control: false,
+            linear_reload: 0,
+            linear_reload_flag: false,
+            linear_counter: 0,
+            length_counter: 0,
+            timer_period: 0,
+            timer: 0,
+            step: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.linear_reload = data & 0x7F;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.linear_reload_flag = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // The sequencer only advances while both counters are running
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length_counter > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Writing $400B only flags a reload, the counter picks it up on the next quarter frame
+    // The flag stays set while the control bit is, which holds the counter at its reload value
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload_flag {
+            self.linear_counter = self.linear_reload;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload_flag = false;
+        }
+    }
+
+    // The control flag doubles as the length counter halt
+    fn clock_length_counter(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.linear_counter == 0 {
+            0
+        } else {
+            TRIANGLE_SEQUENCE[self.step as usize]
+        }
+    }
+}
+
+// Pseudo-random noise from a 15 bit linear feedback shift register
+pub struct Noise {
+    enabled: bool,
+    envelope: Envelope,
+    length_halt: bool,
+    pub length_counter: u8,
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            enabled: false,
+            envelope: Envelope::new(),
+            length_halt: false,
+            length_counter: 0,
+            short_mode: false,
+            timer_period: NOISE_PERIODS[0],
+            timer: 0,
+            shift_register: 1,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                // The length counter halt shares its bit with the envelope loop flag
+                self.length_halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.timer_period = NOISE_PERIODS[(data & 0x0F) as usize];
+            }
+            3 => {
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // Short mode takes feedback from bit 6 instead of bit 1, giving a 93 step metallic loop
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length_counter(&mut self) {
+        if !self.length_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_timestamps_advance_by_the_sample_period() {
+        let mut apu = APU::new();
+        let mut last = 0;
+        for _ in 0..3 {
+            for _ in 0..10_000 {
+                apu.step();
+            }
+
+            // 1789773 / 44100 is about 40.6 CPU cycles per sample, and draining never restarts the count
+            let samples = apu.drain_samples();
+            assert!(samples.len() >= 246 && samples.len() <= 247, "{} samples", samples.len());
+            for (cycle, _) in samples {
+                assert!(cycle - last == 40 || cycle - last == 41 || last == 0, "{} after {}", cycle, last);
+                last = cycle;
+            }
+        }
+        assert!(last <= apu.cycles);
+    }
+
+    // Pulse 1 at constant volume 15 and the triangle, both with their length counters halted
+    fn pulse_and_triangle(enabled: u8) -> APU {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, enabled);
+        for (addr, data) in [(0x4000, 0xBF), (0x4002, 0x80), (0x4003, 0x00), (0x4008, 0xFF), (0x400A, 0x40), (0x400B, 0x00)] {
+            apu.write_register(addr, data);
+        }
+        apu
+    }
+
+    fn run(apu: &mut APU, cycles: usize) -> Vec<f32> {
+        for _ in 0..cycles {
+            apu.step();
+        }
+        apu.drain_samples().into_iter().map(|(_, sample)| sample).collect()
+    }
+
+    #[test]
+    fn muting_pulse_1_leaves_the_triangle_playing() {
+        let mut both = pulse_and_triangle(0x05);
+        let mut muted = pulse_and_triangle(0x05);
+        muted.set_channel_muted(Channel::Pulse1, true);
+        let mut triangle_only = pulse_and_triangle(0x04);
+
+        let both = run(&mut both, 20_000);
+        let muted_samples = run(&mut muted, 20_000);
+        assert_eq!(muted_samples, run(&mut triangle_only, 20_000));
+        assert_ne!(muted_samples, both);
+
+        // The triangle still sounds, its samples move around rather than sitting at silence
+        let loudest = muted_samples.iter().cloned().fold(0.0, f32::max);
+        assert!(loudest > 0.0);
+        assert!(muted_samples.iter().any(|&sample| sample != loudest));
+
+        // Muting is only in the mix, pulse 1 keeps running underneath
+        assert!(muted.pulse1.length_counter > 0);
+        assert_eq!(muted.read_status() & 0x01, 0x01);
+    }
+
+    #[test]
+    fn master_volume_scales_and_clamps_the_mix() {
+        let mut full = pulse_and_triangle(0x05);
+        let mut half = pulse_and_triangle(0x05);
+        half.set_master_volume(0.5);
+        let mut loud = pulse_and_triangle(0x05);
+        loud.set_master_volume(100.0);
+
+        let full = run(&mut full, 20_000);
+        for (half, full) in run(&mut half, 20_000).iter().zip(full.iter()) {
+            assert!((half - full * 0.5).abs() < 1e-6);
+        }
+        assert!(run(&mut loud, 20_000).iter().all(|&sample| sample <= 1.0));
+    }
+
+    fn length_counter(apu: &APU, channel: usize) -> u8 {
+        [apu.pulse1.length_counter, apu.pulse2.length_counter, apu.triangle.length_counter, apu.noise.length_counter][channel]
+    }
+
+    #[test]
+    fn halt_bit_freezes_the_length_counter() {
+        // Control register and halt bit of each channel, pulse 1, pulse 2, triangle, and noise
+        for (channel, (control, halt)) in [(0x4000u16, 0x20u8), (0x4004, 0x20), (0x4008, 0x80), (0x400C, 0x20)].into_iter().enumerate() {
+            let mut apu = APU::new();
+            apu.write_register(0x4015, 0x0F);
+            apu.write_register(control, halt);
+            // Length index 1 loads 254
+            apu.write_register(control + 3, 0x08);
+            assert_eq!(length_counter(&apu, channel), 254);
+
+            run(&mut apu, 60_000);
+            assert_eq!(length_counter(&apu, channel), 254, "channel {}", channel);
+
+            // Released, it counts down from the next half frame clock, 14913 cycles into the sequence
+            apu.write_register(control, 0x00);
+            apu.write_register(0x4017, 0x00);
+            run(&mut apu, 14_912);
+            assert_eq!(length_counter(&apu, channel), 254, "channel {}", channel);
+            apu.step();
+            assert_eq!(length_counter(&apu, channel), 253, "channel {}", channel);
+
+            // Halting again stops it where it is
+            apu.write_register(control, halt);
+            run(&mut apu, 60_000);
+            assert_eq!(length_counter(&apu, channel), 253, "channel {}", channel);
+            assert_eq!(apu.read_status(), 1 << channel);
+        }
+    }
+
+    #[test]
+    fn an_expired_length_counter_silences_the_channel() {
+        // Length index 3 loads 2, gone after two half frame clocks
+        let mut apu = pulse_and_triangle(0x01);
+        apu.write_register(0x4000, 0x9F);
+        apu.write_register(0x4003, 0x18);
+        assert_eq!(apu.pulse1.length_counter, 2);
+        assert!(run(&mut apu, 20_000).iter().any(|&sample| sample > 0.0));
+
+        run(&mut apu, 20_000);
+        assert_eq!(apu.read_status(), 0);
+        assert!(run(&mut apu, 20_000).iter().all(|&sample| sample == 0.0));
+
+        // The triangle needs its linear counter too, a length alone is silent
+        let mut apu = pulse_and_triangle(0x04);
+        apu.write_register(0x4008, 0x80);
+        run(&mut apu, 20_000);
+        assert!(apu.triangle.length_counter > 0);
+        let samples = run(&mut apu, 20_000);
+        assert!(samples.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn soloed_triangle_is_the_whole_mix() {
+        // Noise at constant volume 15 joins the pulse and triangle
+        let noisy = |enabled: u8| {
+            let mut apu = pulse_and_triangle(enabled);
+            for (addr, data) in [(0x400C, 0x3F), (0x400E, 0x00), (0x400F, 0x00)] {
+                apu.write_register(addr, data);
+            }
+            apu
+        };
+
+        let mut soloed = noisy(0x0F);
+        soloed.solo_channel(Channel::Triangle);
+        assert!(Channel::ALL.iter().all(|&channel| soloed.is_channel_muted(channel) == (channel != Channel::Triangle)));
+
+        let solo_samples = run(&mut soloed, 20_000);
+        assert_eq!(solo_samples, run(&mut noisy(0x04), 20_000));
+        assert_ne!(solo_samples, run(&mut noisy(0x0F), 20_000));
+        // The game's channels keep running, pulse 2 was never given a length
+        assert_eq!(soloed.read_status(), 0x0D);
+
+        // Soloing it again brings everything back
+        soloed.solo_channel(Channel::Triangle);
+        assert!(Channel::ALL.iter().all(|&channel| !soloed.is_channel_muted(channel)));
+    }
+
+    // An APU whose channels sit at fixed output levels, 0-15 each
+    fn levels(pulse1: u8, pulse2: u8, triangle: u8, noise: u8) -> APU {
+        let mut apu = APU::new();
+        for (pulse, level) in [(&mut apu.pulse1, pulse1), (&mut apu.pulse2, pulse2)] {
+            pulse.length_counter = 1;
+            pulse.timer_period = 8;
+            // The first step of the negated 25% duty is high
+            pulse.duty = 3;
+            pulse.envelope.constant = true;
+            pulse.envelope.period = level;
+        }
+        apu.triangle.length_counter = 1;
+        apu.triangle.linear_counter = 1;
+        apu.triangle.step = 15 - triangle;
+        apu.noise.length_counter = 1;
+        apu.noise.shift_register = 0;
+        apu.noise.envelope.constant = true;
+        apu.noise.envelope.period = noise;
+        apu
+    }
+
+    #[test]
+    fn mixer_follows_the_non_linear_tables() {
+        for (pulse1, pulse2, triangle, noise) in [(15, 0, 0, 0), (15, 15, 0, 0), (0, 0, 15, 0), (0, 0, 0, 15), (7, 3, 9, 4), (15, 15, 15, 15)] {
+            let pulse = (pulse1 + pulse2) as f32;
+            let tnd = (3 * triangle + 2 * noise) as f32;
+            let pulse_out = if pulse == 0.0 { 0.0 } else { 95.52 / (8128.0 / pulse + 100.0) };
+            let tnd_out = if tnd == 0.0 { 0.0 } else { 163.67 / (24329.0 / tnd + 100.0) };
+
+            let mixed = levels(pulse1, pulse2, triangle, noise).output();
+            assert!((mixed - (pulse_out + tnd_out)).abs() < 1e-6, "{} {} {} {}: {}", pulse1, pulse2, triangle, noise, mixed);
+        }
+
+        // Two pulses at full volume are quieter than twice one, the mix compresses
+        let one = levels(15, 0, 0, 0).output();
+        let two = levels(15, 15, 0, 0).output();
+        assert!(two < 2.0 * one && two > one);
+    }
+
+    // Envelope outputs after each of the given number of clocks
+    fn envelope_outputs(envelope: &mut Envelope, clocks: usize) -> Vec<u8> {
+        (0..clocks)
+            .map(|_| {
+                envelope.clock();
+                envelope.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn envelope_decays_from_15_and_loops() {
+        let mut envelope = Envelope::new();
+        envelope.write(0x00);
+        envelope.start = true;
+        let decay: Vec<u8> = (0..=15).rev().collect();
+        assert_eq!(envelope_outputs(&mut envelope, 16), decay);
+        assert_eq!(envelope_outputs(&mut envelope, 3), [0, 0, 0]);
+
+        // With the loop flag it wraps back to 15
+        envelope.write(0x20);
+        envelope.start = true;
+        let looped = envelope_outputs(&mut envelope, 18);
+        assert_eq!(looped[..16], decay[..]);
+        assert_eq!(looped[16..], [15, 14]);
+
+        // A period of 2 takes three clocks per step
+        envelope.write(0x02);
+        envelope.start = true;
+        assert_eq!(envelope_outputs(&mut envelope, 7), [15, 15, 15, 14, 14, 14, 13]);
+
+        // Constant volume ignores the decay
+        envelope.write(0x1A);
+        assert_eq!(envelope_outputs(&mut envelope, 4), [10, 10, 10, 10]);
+    }
+
+    #[test]
+    fn length_writes_restart_the_envelope() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0x0F);
+        for (control, length) in [(0x4000, 0x4003), (0x4004, 0x4007), (0x400C, 0x400F)] {
+            apu.write_register(control, 0x00);
+            apu.write_register(length, 0x08);
+        }
+        assert!(apu.pulse1.envelope.start && apu.pulse2.envelope.start && apu.noise.envelope.start);
+
+        // The first quarter frame clock reloads the decay at 15
+        run(&mut apu, 7457);
+        assert_eq!((apu.pulse1.envelope.output(), apu.pulse2.envelope.output(), apu.noise.envelope.output()), (15, 15, 15));
+        run(&mut apu, 7456);
+        assert_eq!(apu.pulse1.envelope.output(), 14);
+    }
+
+    struct ConstantSource(f32);
+
+    impl ExpansionAudio for ConstantSource {
+        fn sample(&self) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn expansion_source_is_summed_into_the_mix() {
+        let mut apu = levels(4, 0, 0, 0);
+        let console = apu.output();
+
+        apu.set_expansion_audio(Some(Box::new(ConstantSource(0.25))));
+        assert!((apu.output() - (console + 0.25)).abs() < 1e-6);
+
+        // Master volume scales the whole mix, expansion included
+        apu.set_master_volume(0.5);
+        assert!((apu.output() - (console + 0.25) * 0.5).abs() < 1e-6);
+
+        // It belongs to the cartridge, so a power cycle keeps it
+        apu.set_master_volume(1.0);
+        apu.power_on();
+        assert!((apu.output() - 0.25).abs() < 1e-6);
+
+        apu.set_expansion_audio(None);
+        assert_eq!(apu.output(), 0.0);
+    }
+}
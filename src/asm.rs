@@ -0,0 +1,217 @@
+// A minimal 6502 assembler for building test programs, so they can be written as text instead of hand-assembled bytes
+// One instruction per line, no labels, operands in hex with a $ prefix, and `;` starts a comment:
+//
+//   LDX #$00
+//   LDA $0200,X
+//   STA ($10),Y
+//   BNE $8002
+//
+// Branch operands are the absolute target, assuming the program is loaded at ORIGIN.
+// Mnemonics are looked up in the opcode table, unofficial ones keep their `*` prefix, e.g. `*LAX $10`.
+
+use crate::cpu::AddressingMode;
+use crate::opcodes::{OPCode, CPU_OPCodeS};
+
+// Where assembled programs are expected to be loaded
+pub const ORIGIN: u16 = 0x8000;
+
+// An operand as written, before it is matched against an opcode
+enum Operand {
+    Implied,
+    Immediate(u8),
+    ZeroPage(u8, Option<char>),
+    Absolute(u16, Option<char>),
+    Indirect(u16),
+    IndirectX(u8),
+    IndirectY(u8),
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut program = Vec::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let pc = ORIGIN.wrapping_add(program.len() as u16);
+        let bytes = assemble_line(line, pc).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        program.extend(bytes);
+    }
+
+    Ok(program)
+}
+
+fn assemble_line(line: &str, pc: u16) -> Result<Vec<u8>, String> {
+    let (mnemonic, operand) = match line.split_once(char::is_whitespace) {
+        Some((mnemonic, operand)) => (mnemonic, operand.trim()),
+        None => (line, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let operand = parse_operand(&operand.to_ascii_uppercase())?;
+
+    let find = |mode: AddressingMode, len: u8| -> Option<&'static OPCode> {
+        CPU_OPCodeS.iter().find(|op| op.name == mnemonic && op.mode == mode && op.len == len)
+    };
+    let missing = || format!("{} does not take that operand", mnemonic);
+
+    let bytes = match operand {
+        Operand::Implied => vec![find(AddressingMode::NoneAddressing, 1).ok_or_else(missing)?.code],
+        Operand::Immediate(value) => vec![find(AddressingMode::Immediate, 2).ok_or_else(missing)?.code, value],
+        Operand::ZeroPage(addr, index) => {
+            let mode = match index {
+                None => AddressingMode::ZeroPage,
+                Some('X') => AddressingMode::ZeroPageX,
+                _ => AddressingMode::ZeroPageY,
+            };
+            vec![find(mode, 2).ok_or_else(missing)?.code, addr]
+        }
+        Operand::Absolute(addr, index) => {
+            let mode = match index {
+                None => AddressingMode::Absolute,
+                Some('X') => AddressingMode::AbsoluteX,
+                _ => AddressingMode::AbsoluteY,
+            };
+            let [lo, hi] = addr.to_le_bytes();
+            if let Some(op) = find(mode, 3) {
+                vec![op.code, lo, hi]
+            } else if index.is_some() {
+                return Err(missing());
+            } else if let Some(op) = find(AddressingMode::NoneAddressing, 2) {
+                // Branches store the target relative to the next instruction
+                let offset = addr.wrapping_sub(pc.wrapping_add(2)) as i16;
+                if !(-128..=127).contains(&offset) {
+                    return Err(format!("Branch target ${:04X} is out of range", addr));
+                }
+                vec![op.code, offset as u8]
+            } else {
+                // JMP and JSR take their address without an addressing mode in the table
+                let op = CPU_OPCodeS.iter()
+                    .find(|op| op.name == mnemonic && op.mode == AddressingMode::NoneAddressing && op.len == 3 && op.code != 0x6c)
+                    .ok_or_else(missing)?;
+                vec![op.code, lo, hi]
+            }
+        }
+        Operand::Indirect(addr) if mnemonic == "JMP" => {
+            let [lo, hi] = addr.to_le_bytes();
+            vec![0x6c, lo, hi]
+        }
+        Operand::Indirect(_) => return Err(missing()),
+        Operand::IndirectX(addr) => vec![find(AddressingMode::IndirectX, 2).ok_or_else(missing)?.code, addr],
+        Operand::IndirectY(addr) => vec![find(AddressingMode::IndirectY, 2).ok_or_else(missing)?.code, addr],
+    };
+
+    Ok(bytes)
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if text.is_empty() || text == "A" {
+        return Ok(Operand::Implied);
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return parse_u8(value).map(Operand::Immediate);
+    }
+
+    if let Some(inner) = text.strip_prefix('(') {
+        if let Some(addr) = inner.strip_suffix(",X)") {
+            return parse_u8(addr).map(Operand::IndirectX);
+        }
+        if let Some(addr) = inner.strip_suffix("),Y") {
+            return parse_u8(addr).map(Operand::IndirectY);
+        }
+        if let Some(addr) = inner.strip_suffix(')') {
+            return parse_u16(addr).map(Operand::Indirect);
+        }
+        return Err(format!("Cannot parse operand {}", text));
+    }
+
+    let (addr, index) = match text.split_once(',') {
+        Some((addr, "X")) => (addr, Some('X')),
+        Some((addr, "Y")) => (addr, Some('Y')),
+        Some(_) => return Err(format!("Cannot parse operand {}", text)),
+        None => (text, None),
+    };
+
+    // Two hex digits address the zero page, four anywhere
+    match addr.len() {
+        3 => parse_u8(addr).map(|addr| Operand::ZeroPage(addr, index)),
+        5 => parse_u16(addr).map(|addr| Operand::Absolute(addr, index)),
+        _ => Err(format!("Addresses are $XX or $XXXX, not {}", addr)),
+    }
+}
+
+fn parse_u8(text: &str) -> Result<u8, String> {
+    let digits = text.strip_prefix('$').ok_or_else(|| format!("Expected a $ hex value, not {}", text))?;
+    u8::from_str_radix(digits, 16).map_err(|_| format!("{} is not a byte", text))
+}
+
+fn parse_u16(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix('$').ok_or_else(|| format!("Expected a $ hex value, not {}", text))?;
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not an address", text))
+}
+
+// A CPU on a flat 64KB image with the assembled program at ORIGIN and the reset vector pointing at it,
+// for tests elsewhere in the crate
+#[cfg(test)]
+pub(crate) fn test_cpu(source: &str) -> crate::cpu::CPU {
+    let program = assemble(source).unwrap();
+    let mut image = vec![0; 0x10000];
+    image[ORIGIN as usize..ORIGIN as usize + program.len()].copy_from_slice(&program);
+    image[0xFFFC..0xFFFE].copy_from_slice(&ORIGIN.to_le_bytes());
+
+    let mut cpu = crate::cpu::CPU::new(crate::bus::Bus::new_headless(image).unwrap());
+    cpu.reset();
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_addressing_mode_assembles_to_its_opcode() {
+        let program = assemble("
+            LDA #$01      ; immediate
+            STA $10
+            LDX $10,Y
+            LDY $0200,X
+            STA $0300,Y
+            LDA ($20,X)
+            STA ($22),Y
+            ASL A
+            INX
+            JMP ($1234)
+            JSR $9000
+            *LAX $10
+        ").unwrap();
+        assert_eq!(program, [
+            0xA9, 0x01,
+            0x85, 0x10,
+            0xB6, 0x10,
+            0xBC, 0x00, 0x02,
+            0x99, 0x00, 0x03,
+            0xA1, 0x20,
+            0x91, 0x22,
+            0x0A,
+            0xE8,
+            0x6C, 0x34, 0x12,
+            0x20, 0x00, 0x90,
+            0xA7, 0x10,
+        ]);
+    }
+
+    #[test]
+    fn branches_are_relative_to_the_next_instruction() {
+        // Back to $8000 from the branch at $8001, then forward past the NOP
+        assert_eq!(assemble("INX\nBNE $8000\nBEQ $8006\nNOP").unwrap(), [0xE8, 0xD0, 0xFD, 0xF0, 0x01, 0xEA]);
+        assert!(assemble("BNE $9000").unwrap_err().contains("out of range"));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(assemble("NOP\nSTA #$10").unwrap_err(), "Line 2: STA does not take that operand");
+        assert_eq!(assemble("LDA $123").unwrap_err(), "Line 1: Addresses are $XX or $XXXX, not $123");
+        assert!(assemble("LDA 10").is_err());
+    }
+}
@@ -30,43 +30,271 @@
 
 use core::panic;
 use std::{cell::RefCell, rc::Rc};
+use rand::RngCore;
 
-use crate::{cpu::Mem, ppu::PPU, rom};
+use crate::{apu::APU, cheats::Cheat, cpu::Mem, gamepad::{FourScore, Joypad}, mapper::{self, Mapper}, ppu::PPU, profiler::{AccessProfiler, AccessStats}, rom, test_harness::TestHarness, zapper::Zapper};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
+// Sources that can pull the shared IRQ line, one bit each in the bus's irq_sources
+pub const IRQ_APU_FRAME: u8 = 0b001;
+pub const IRQ_DMC: u8 = 0b010;
+pub const IRQ_MAPPER: u8 = 0b100;
+
 pub struct Bus {
     cpu_vram: [u8; 2048],
+    // Cartridge RAM at $6000-$7FFF
+    prg_ram: [u8; 0x2000],
     pub ppu: Rc<RefCell<PPU>>,
+    pub apu: APU,
+    pub joypad1: Joypad,
+    pub joypad2: Joypad,
+    pub joypad3: Joypad,
+    pub joypad4: Joypad,
+    four_score: Option<FourScore>,
+    // Takes the place of the second controller when plugged in
+    pub zapper: Option<Zapper>,
+    // Watches for the blargg test ROM protocol, only plugged in for test runs
+    pub test_harness: Option<TestHarness>,
     rom: rom::Rom,
+    mapper: Box<dyn Mapper>,
+    profiler: Option<AccessProfiler>,
+    flat_memory: Option<Vec<u8>>,
+    journal: Option<Vec<(u16, u8)>>,
+    irq_sources: u8,
+    cheats: Vec<Cheat>,
 }
 
 impl Bus {
     pub fn new(ppu: Rc<RefCell<PPU>>, rom: rom::Rom) -> Self {
+        // A trainer is loaded into cartridge RAM at $7000 before the game starts
+        let mut prg_ram = [0; 0x2000];
+        prg_ram[0x1000..0x1000 + rom.trainer.len()].copy_from_slice(&rom.trainer);
+
+        let mapper = mapper::for_rom(&rom);
+        let mut apu = APU::new();
+        apu.set_expansion_audio(mapper.expansion_audio());
+
         Bus {
             cpu_vram: [0; 2048],
+            prg_ram,
             ppu,
+            apu,
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            joypad3: Joypad::new(),
+            joypad4: Joypad::new(),
+            four_score: None,
+            zapper: None,
+            test_harness: None,
+            mapper,
             rom,
+            profiler: None,
+            flat_memory: None,
+            journal: None,
+            irq_sources: 0,
+            cheats: Vec::new(),
         }
     }
 
-    fn read_prom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
+    // Power cycle, RAM is cleared and every chip on the bus starts over
+    // What the front-end plugged in stays: the zapper, Four Score, test harness, profiler, and cheats
+    // The PPU is shared with the machine, which powers it on itself
+    pub fn power_on(&mut self) {
+        self.cpu_vram = [0; 2048];
+        self.prg_ram = [0; 0x2000];
+        self.prg_ram[0x1000..0x1000 + self.rom.trainer.len()].copy_from_slice(&self.rom.trainer);
+        self.apu.power_on();
+        for joypad in [&mut self.joypad1, &mut self.joypad2, &mut self.joypad3, &mut self.joypad4] {
+            joypad.power_on();
+        }
+        if self.four_score.is_some() {
+            self.four_score = Some(FourScore::new());
+        }
+        self.mapper = mapper::for_rom(&self.rom);
+        self.apu.set_expansion_audio(self.mapper.expansion_audio());
+        self.irq_sources = 0;
+    }
+
+    // Fill work RAM with random bytes, like the undefined contents real RAM holds at power on
+    pub fn randomize_ram(&mut self, rng: &mut impl RngCore) {
+        rng.fill_bytes(&mut self.cpu_vram);
+    }
 
-        if self.rom.p_rom.len() == 0x4000 && addr >= 0x4000 {
-            // Mirror if needed
-            addr = addr % 0x4000;
+    // A bus backed by a flat 64KB memory image instead of a cartridge, for running bare CPU test programs
+    // Every address reads and writes the image directly, so code and vectors can be placed anywhere
+    pub fn new_headless(image: Vec<u8>) -> Result<Self, String> {
+        if image.len() != 0x10000 {
+            return Err(format!("Memory image must be 64KB, got {} bytes", image.len()));
         }
 
-        return self.rom.p_rom[addr as usize];
+        let rom = rom::Rom {
+            p_rom: Vec::new(),
+            c_rom: Vec::new(),
+            trainer: Vec::new(),
+            mapper: 0,
+            mirroring: rom::Mirroring::HORIZONTAL,
+            battery: false,
+            region: crate::config::Region::NTSC,
+        };
+        let mut bus = Bus::new(Rc::new(RefCell::new(PPU::new(Vec::new(), rom.mirroring))), rom);
+        bus.flat_memory = Some(image);
+        Ok(bus)
     }
-}
 
-impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    // Start counting reads and writes per page
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(AccessProfiler::new());
+    }
+
+    // Access counts since the profiler was enabled, None while profiling is off
+    pub fn access_stats(&self) -> Option<AccessStats> {
+        self.profiler.as_ref().map(|profiler| profiler.stats())
+    }
+
+    // Plug in a Four Score so joypad3 and joypad4 are read after joypad1 and joypad2
+    pub fn enable_four_score(&mut self) {
+        self.four_score = Some(FourScore::new());
+    }
+
+    pub fn has_four_score(&self) -> bool {
+        self.four_score.is_some()
+    }
+
+    // Patch PRG-ROM reads with a Game Genie or raw AAAA:VV code
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        self.cheats.push(Cheat::parse(code)?);
+        Ok(())
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+
+    // The IRQ line is the OR of every source, it stays asserted until each one has been cleared
+    pub fn assert_irq(&mut self, source: u8) {
+        self.irq_sources |= source;
+    }
+
+    pub fn clear_irq(&mut self, source: u8) {
+        self.irq_sources &= !source;
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.irq_sources != 0
+    }
+
+    // Record the old value of every RAM byte written from here on, for undoing an instruction
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    // The (address, old value) pairs written since start_journal, journaling stops until it is started again
+    pub fn take_journal(&mut self) -> Vec<(u16, u8)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    // Put back a byte taken from the journal, without going through the write side effects
+    pub fn restore_byte(&mut self, addr: u16, data: u8) {
+        if let Some(memory) = self.flat_memory.as_mut() {
+            memory[addr as usize] = data;
+        } else if addr <= RAM_MIRRORS_END {
+            self.cpu_vram[(addr & 0x07FF) as usize] = data;
+        }
+    }
+
+    // Advance the PPU and APU alongside the CPU, three PPU dots per CPU cycle
+    // The PPU is borrowed once for the whole batch rather than once per cycle, the two never read each other
+    // so the PPU can run all of its dots first
+    pub fn tick(&mut self, cycles: u8) {
+        self.ppu.borrow_mut().step_dots(cycles as usize * 3);
+        for _ in 0..cycles {
+            self.apu.step();
+        }
+    }
+
+    // Read a byte without triggering any read side effects
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(memory) = &self.flat_memory {
+            return memory[addr as usize];
+        }
+
+        match addr {
+            RAM ..= RAM_MIRRORS_END => self.cpu_vram[(addr & 0x07FF) as usize],
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
+                let ppu_addr = PPU_REGISTERS + (addr & 0x7);
+                self.ppu.borrow().read_register(ppu_addr)
+            }
+            0x4015 => self.apu.read_status(),
+            0x4017 if self.zapper.is_some() => self.read_zapper(),
+            0x4016 | 0x4017 if self.four_score.is_some() => self.read_four_score(addr, true),
+            0x4016 => self.joypad1.peek(),
+            0x4017 => self.joypad2.peek(),
+            _ => self.read(addr),
+        }
+    }
+
+    // Copy the bytes from start to end (inclusive) using non-destructive peeks
+    pub fn dump_range(&self, start: u16, end: u16) -> Vec<u8> {
+        (start as u32..=end as u32).map(|addr| self.peek(addr as u16)).collect()
+    }
+
+    // Format the range start to end (inclusive) as 16 bytes per line with an ASCII sidebar
+    // 0000: 48 45 4C 4C 4F 00 00 00 00 00 00 00 00 00 00 00  |HELLO...........|
+    pub fn hexdump(&self, start: u16, end: u16) -> String {
+        let bytes = self.dump_range(start, end);
+        let mut out = String::new();
+
+        for (line, chunk) in bytes.chunks(16).enumerate() {
+            let addr = start as usize + line * 16;
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+
+            out.push_str(&format!("{:04X}: {:47}  |{}|\n", addr, hex.join(" "), ascii));
+        }
+
+        out
+    }
+
+    fn read_prom(&self, addr: u16) -> u8 {
+        self.mapper.cpu_read(&self.rom.p_rom, addr)
+    }
+
+    fn read_zapper(&self) -> u8 {
+        match self.zapper.as_ref() {
+            Some(zapper) => zapper.read(&self.ppu.borrow().framebuffer),
+            None => 0,
+        }
+    }
+
+    // $4016 reads players 1 and 3, $4017 players 2 and 4
+    fn read_four_score(&self, addr: u16, peek: bool) -> u8 {
+        let Some(four_score) = self.four_score.as_ref() else { return 0 };
+        let (port, first, second) = if addr == 0x4016 {
+            (0, &self.joypad1, &self.joypad3)
+        } else {
+            (1, &self.joypad2, &self.joypad4)
+        };
+
+        if peek {
+            four_score.peek(port, first, second)
+        } else {
+            four_score.read(port, first, second)
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        if let Some(memory) = &self.flat_memory {
+            return memory[addr as usize];
+        }
+
         match addr {
             RAM ..= RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0x07FF;
@@ -75,9 +303,17 @@ impl Mem for Bus {
             // PPU ($2000 - $3FFF)
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
                 let ppu_addr = PPU_REGISTERS + (addr &0x7);
-                return self.ppu.borrow().read_register(ppu_addr)
+                return self.ppu.borrow_mut().cpu_read(ppu_addr)
             },
 
+            0x4015 => self.apu.read_status(),
+
+            // Controllers
+            0x4017 if self.zapper.is_some() => self.read_zapper(),
+            0x4016 | 0x4017 if self.four_score.is_some() => self.read_four_score(addr, false),
+            0x4016 => self.joypad1.read(),
+            0x4017 => self.joypad2.read(),
+
             // APU and I/O Registers ($4000–$401F)
             0x4000..=0x401F => {
                 // Return 0xFF for unimplemented APU/I/O reads
@@ -85,7 +321,12 @@ impl Mem for Bus {
             }
 
             // ROM reads ($8000–$FFFF)
-            0x8000..=0xFFFF => self.read_prom(addr),
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+
+            0x8000..=0xFFFF => {
+                let data = self.read_prom(addr);
+                self.cheats.iter().fold(data, |data, cheat| cheat.apply(addr, data))
+            }
 
             // All other regions (PPU registers, expansion ROM)
             _ => {
@@ -93,8 +334,43 @@ impl Mem for Bus {
             }
         }
     }
+}
+
+impl Mem for Bus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        if let Some(profiler) = &self.profiler {
+            profiler.record_read(addr);
+        }
+
+        self.read(addr)
+    }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record_write(addr);
+        }
+
+        if self.journal.is_some() && (self.flat_memory.is_some() || addr <= RAM_MIRRORS_END) {
+            let old = self.peek(addr);
+            if let Some(journal) = self.journal.as_mut() {
+                journal.push((addr, old));
+            }
+        }
+
+        if let Some(harness) = self.test_harness.as_mut() {
+            harness.observe_write(addr, data);
+        }
+
+        if let Some(memory) = self.flat_memory.as_mut() {
+            memory[addr as usize] = data;
+            return;
+        }
+
+        // Cartridge RAM
+        if (0x6000..=0x7FFF).contains(&addr) {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+        }
+
         match addr {
             RAM ..= RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0x07FF;
@@ -104,47 +380,243 @@ impl Mem for Bus {
                 let ppu_addr = PPU_REGISTERS + (addr & 0x7); 
                 self.ppu.borrow_mut().write_register(ppu_addr, data);
             }
-            // Blargg Test Specific Addresses
-            0x6000 => {
-                match data {
-                    0x00 => {
-                        println!("blargg test PASSED!");
-                        std::process::exit(0); // graceful exit
-                    }
-                    0x80 => {
-                        println!("Running")
-                    }
-                    fail_code => {
-                        println!("blargg test FAILED with code {:02X}", fail_code);
-                        // Optionally read $6004..$60XX and print failure message
-                        let mut msg = Vec::new();
-                        let mut addr = 0x6004;
-                        loop {
-                            let byte = self.mem_read(addr);
-                            if byte == 0 || addr > 0x60FF { break; }
-                            msg.push(byte);
-                            addr += 1;
-                        }
-                        if let Ok(message) = String::from_utf8(msg) {
-                            println!("Failure reason: {}", message);
-                        }
-                        std::process::exit(1);
-                    }
+            // OAM DMA, copy a full page of CPU memory into sprite memory
+            0x4014 => {
+                let mut buffer: [u8; 256] = [0; 256];
+                let hi: u16 = (data as u16) << 8;
+                for i in 0..256u16 {
+                    buffer[i as usize] = self.mem_read(hi + i);
                 }
+
+                self.ppu.borrow_mut().write_oam_dma(&buffer);
             }
-            0x6004..=0x7000 => {
-                // Only print printable ASCII characters, skip nulls and control chars
-                if data.is_ascii_graphic() || data == b' ' {
-                    print!("{}", data as char);
-                } else if data == b'\n' || data == b'\r' {
-                    print!("{}", data as char);
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            // Controller strobe is shared by both ports
+            0x4016 => {
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+                if let Some(four_score) = self.four_score.as_mut() {
+                    four_score.write(data);
                 }
-                // Do not print \x00 or other non-printable bytes
             }
-            0x8000..=0xFFFF => {panic!("Attempted to write to ROM at address {:04X}", addr);}
+            // Mapper registers, the ROM itself can't be written
+            0x8000..=0xFFFF => self.mapper.cpu_write(addr, data),
             _ => {
 
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_image;
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+
+    fn bus(program: &[u8]) -> Bus {
+        let rom = rom::Rom::from_bytes(&test_image(program)).unwrap();
+        Bus::new(Rc::new(RefCell::new(PPU::new(rom.c_rom.clone(), rom.mirroring))), rom)
+    }
+
+    #[test]
+    fn hexdump_formats_ram_through_its_mirrors() {
+        let mut bus = bus(&[0xA9, 0x42]);
+        for (i, &byte) in b"HELLO".iter().enumerate() {
+            bus.mem_write(0x0010 + i as u16, byte);
+        }
+        bus.mem_write(0x001F, 0x7F);
+
+        assert_eq!(bus.dump_range(0x0810, 0x0814), b"HELLO");
+        assert_eq!(
+            bus.hexdump(0x0010, 0x0021),
+            "0010: 48 45 4C 4C 4F 00 00 00 00 00 00 00 00 00 00 7F  |HELLO...........|\n\
+             0020: 00 00                                            |..|\n"
+        );
+
+        // Cartridge space reads through the mapper
+        assert_eq!(bus.dump_range(0x8000, 0x8003), [0xA9, 0x42, 0xEA, 0xEA]);
+        assert_eq!(bus.dump_range(0xFFFC, 0xFFFF), [0x00, 0x80, 0xF0, 0xFF]);
+    }
+
+    #[test]
+    fn access_profiler_counts_each_page() {
+        let mut bus = bus(&[]);
+        assert!(bus.access_stats().is_none());
+
+        bus.enable_profiler();
+        bus.mem_write(0x0010, 1);
+        bus.mem_write(0x0110, 1);
+        bus.mem_write(0x6000, 1);
+        bus.mem_read(0x0010);
+        bus.mem_read(0x8000);
+        bus.mem_read(0xFFFC);
+        bus.mem_read(0xFFFD);
+        // Debugger peeks are not accesses
+        bus.peek(0x8000);
+        bus.hexdump(0x0000, 0x00FF);
+
+        let stats = bus.access_stats().unwrap();
+        assert_eq!((stats.reads[0x00], stats.writes[0x00]), (1, 1));
+        assert_eq!((stats.reads[0x01], stats.writes[0x01]), (0, 1));
+        assert_eq!(stats.writes[0x60], 1);
+        assert_eq!((stats.reads[0x80], stats.reads[0xFF]), (1, 2));
+        assert_eq!(stats.reads.iter().sum::<u64>(), 4);
+
+        let regions = stats.by_region();
+        assert_eq!(regions[0], ("RAM", 1, 2));
+        assert_eq!(regions[4], ("SRAM", 0, 1));
+        assert_eq!(regions[5], ("PRG-ROM", 3, 0));
+    }
+
+    #[test]
+    fn headless_bus_runs_a_flat_image() {
+        let program = assemble("LDA #$42\nSTA $0200\nLDX $0200\nINX\nSTX $C100\nSTX $2000").unwrap();
+        let mut image = vec![0; 0x10000];
+        image[0xC000..0xC000 + program.len()].copy_from_slice(&program);
+        image[0xFFFC..0xFFFE].copy_from_slice(&[0x00, 0xC0]);
+
+        let mut cpu = CPU::new(Bus::new_headless(image).unwrap());
+        cpu.reset();
+        assert_eq!(cpu.register_pc, 0xC000);
+        for _ in 0..6 {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.register_x, 0x43);
+        // Every address is plain memory, even the ROM and PPU register ranges
+        assert_eq!(cpu.mem_read(0xC100), 0x43);
+        assert_eq!(cpu.mem_read(0x2000), 0x43);
+        assert_eq!(cpu.mem_read(0x0A00), 0x00);
+        assert_eq!(cpu.register_pc, 0xC000 + program.len() as u16);
+
+        assert!(Bus::new_headless(vec![0; 0x8000]).is_err());
+    }
+
+    #[test]
+    fn irq_line_stays_high_until_every_source_clears() {
+        let mut bus = bus(&[]);
+        assert!(!bus.irq_line());
+
+        bus.assert_irq(IRQ_APU_FRAME);
+        bus.assert_irq(IRQ_MAPPER);
+        bus.clear_irq(IRQ_APU_FRAME);
+        assert!(bus.irq_line());
+        // Clearing a source that never asserted changes nothing
+        bus.clear_irq(IRQ_DMC);
+        assert!(bus.irq_line());
+        bus.clear_irq(IRQ_MAPPER);
+        assert!(!bus.irq_line());
+    }
+
+    #[test]
+    fn cpu_polls_the_combined_irq_line() {
+        // The IRQ handler at $9000 counts in X
+        let mut image = vec![0xEA; 0x10000];
+        image[0x8000] = 0x58; // CLI
+        image[0x9000] = 0xE8; // INX
+        image[0x9001] = 0x58; // CLI
+        image[0xFFFC..0xFFFE].copy_from_slice(&[0x00, 0x80]);
+        image[0xFFFE..].copy_from_slice(&[0x00, 0x90]);
+        let mut cpu = CPU::new(Bus::new_headless(image).unwrap());
+        cpu.reset();
+        cpu.step();
+
+        cpu.bus.assert_irq(IRQ_APU_FRAME | IRQ_MAPPER);
+        cpu.step();
+        assert_eq!((cpu.register_pc, cpu.register_x), (0x9001, 1));
+        assert!(cpu.flags.int());
+
+        // One source acknowledged, the other keeps interrupting as soon as interrupts are enabled again
+        cpu.bus.clear_irq(IRQ_APU_FRAME);
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.register_pc, cpu.register_x), (0x9001, 2));
+
+        cpu.bus.clear_irq(IRQ_MAPPER);
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.register_pc, cpu.register_x), (0x9003, 2));
+    }
+
+    // Point the PPU at $2000 with 11 22 33 stored there, then run one indexed read of $2007
+    fn indexed_data_read(read: &str) -> CPU {
+        let program = assemble(&format!("
+            LDA #$20
+            STA $2006
+            LDA #$00
+            STA $2006
+            LDA #$11
+            STA $2007
+            LDA #$22
+            STA $2007
+            LDA #$33
+            STA $2007
+            LDA #$20
+            STA $2006
+            LDA #$00
+            STA $2006
+            {}", read)).unwrap();
+        let mut cpu = CPU::new(bus(&program));
+        cpu.reset();
+        for _ in 0..16 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn page_crossing_read_of_2007_makes_a_dummy_read() {
+        // $20FF,X reads the unfixed $2007 first, then $2107, a mirror of $2007
+        let cpu = indexed_data_read("LDX #$08\nLDA $20FF,X");
+        // The dummy read took the stale buffer, so the real read returns $2000's byte
+        assert_eq!(cpu.register_a, 0x11);
+        assert_eq!(cpu.bus.ppu.borrow().vram_addr, 0x2002);
+
+        // Without a page cross there is only the one read
+        let cpu = indexed_data_read("LDX #$07\nLDA $2000,X");
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(cpu.bus.ppu.borrow().vram_addr, 0x2001);
+    }
+
+    // Keeps every register write it is sent, reads fall through to NROM
+    struct RecordingMapper(Rc<RefCell<Vec<(u16, u8)>>>);
+
+    impl Mapper for RecordingMapper {
+        fn cpu_read(&self, prg: &[u8], addr: u16) -> u8 {
+            mapper::Nrom.cpu_read(prg, addr)
+        }
+
+        fn cpu_write(&mut self, addr: u16, data: u8) {
+            self.0.borrow_mut().push((addr, data));
+        }
+    }
+
+    #[test]
+    fn rom_writes_go_to_the_mapper() {
+        let mut bus = bus(&[0xA9, 0x42]);
+        // NROM ignores them and the ROM is unchanged
+        bus.mem_write(0x8000, 0x07);
+        assert_eq!(bus.mem_read(0x8000), 0xA9);
+
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        bus.mapper = Box::new(RecordingMapper(writes.clone()));
+        bus.mem_write(0x8000, 0x07);
+        bus.mem_write(0xFFFF, 0x01);
+        assert_eq!(*writes.borrow(), [(0x8000, 0x07), (0xFFFF, 0x01)]);
+        assert_eq!(bus.mem_read(0x8001), 0x42);
+    }
+
+    #[test]
+    fn cpu_reads_of_write_only_ppu_registers_see_open_bus() {
+        let mut bus = bus(&[]);
+        bus.mem_write(0x2000, 0x80);
+        bus.mem_write(0x2005, 0x11);
+        // The control byte is kept, but reading it back gives the last value on the PPU bus
+        assert_eq!(bus.ppu.borrow().control, 0x80);
+        assert_eq!(bus.mem_read(0x2000), 0x11);
+        // Mirrors through $3FFF behave the same
+        assert_eq!(bus.mem_read(0x3FF8), 0x11);
+        assert_eq!(bus.mem_read(0x2001), 0x11);
+    }
+}
@@ -0,0 +1,122 @@
+// Corrections for ROM dumps whose iNES header has the wrong mirroring or mapper, enabled with --db
+// Entries are keyed by the CRC-32 of PRG and CHR ROM, the header is left out of it so the sum names the game data.
+// The built-in table can be extended from a TOML file with `--db corrections.toml`, in the same format:
+//
+//   [[cart]]
+//   name = "Some Game (USA)"
+//   crc32 = "1A2B3C4D"
+//   mirroring = "vertical"
+//   mapper = 2
+
+use serde::Deserialize;
+
+use crate::rom::{Mirroring, Rom};
+
+// Games whose dumps circulate with a bad header, in the format above
+// The CRC is of the game data alone, as No-Intro lists it, so it matches however the header was damaged
+const BUILTIN: &str = r#"
+[[cart]]
+name = "Super Mario Bros. (World)"
+crc32 = "3337EC46"
+mirroring = "vertical"
+mapper = 0
+"#;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DbEntry {
+    pub name: String,
+    // Hex, as databases and checksum tools print it
+    pub crc32: String,
+    pub mirroring: Option<Mirroring>,
+    pub mapper: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CartDb {
+    #[serde(default)]
+    pub cart: Vec<DbEntry>,
+}
+
+impl CartDb {
+    pub fn builtin() -> CartDb {
+        CartDb::parse(BUILTIN).unwrap()
+    }
+
+    pub fn parse(text: &str) -> Result<CartDb, String> {
+        let db: CartDb = toml::from_str(text).map_err(|e| format!("Invalid cartridge database: {}", e))?;
+        for entry in &db.cart {
+            u32::from_str_radix(&entry.crc32, 16).map_err(|_| format!("{} has an invalid crc32 {}", entry.name, entry.crc32))?;
+        }
+        Ok(db)
+    }
+
+    // Add the entries from a TOML file to this database
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        self.cart.extend(CartDb::parse(&text)?.cart);
+        Ok(())
+    }
+
+    pub fn lookup(&self, crc: u32) -> Option<&DbEntry> {
+        self.cart.iter().find(|entry| u32::from_str_radix(&entry.crc32, 16) == Ok(crc))
+    }
+
+    // Override the header fields of a known bad dump, returns a description of what changed
+    pub fn correct(&self, rom: &mut Rom) -> Option<String> {
+        let entry = self.lookup(rom.crc32())?;
+        let mut changes = Vec::new();
+
+        if let Some(mirroring) = entry.mirroring.filter(|&mirroring| mirroring != rom.mirroring) {
+            changes.push(format!("mirroring {:?} -> {:?}", rom.mirroring, mirroring));
+            rom.mirroring = mirroring;
+        }
+        if let Some(mapper) = entry.mapper.filter(|&mapper| mapper != rom.mapper) {
+            changes.push(format!("mapper {} -> {}", rom.mapper, mapper));
+            rom.mapper = mapper;
+        }
+
+        if changes.is_empty() {
+            None
+        } else {
+            Some(format!("Corrected header for {}: {}", entry.name, changes.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::test_image;
+
+    #[test]
+    fn builtin_entries_parse() {
+        let db = CartDb::builtin();
+        assert!(!db.cart.is_empty());
+        assert_eq!(db.lookup(0x3337_EC46).unwrap().mirroring, Some(Mirroring::VERTICAL));
+    }
+
+    #[test]
+    fn matching_crc_overrides_mirroring() {
+        let mut rom = Rom::from_bytes(&test_image(&[0x4C, 0x00, 0x80])).unwrap();
+        assert_eq!(rom.mirroring, Mirroring::HORIZONTAL);
+
+        let db = CartDb::parse(&format!(
+            "[[cart]]\nname = \"Test Cart\"\ncrc32 = \"{:08X}\"\nmirroring = \"vertical\"\n",
+            rom.crc32()
+        ))
+        .unwrap();
+        let correction = db.correct(&mut rom).unwrap();
+        assert_eq!(rom.mirroring, Mirroring::VERTICAL);
+        assert_eq!(correction, "Corrected header for Test Cart: mirroring HORIZONTAL -> VERTICAL");
+
+        // Already corrected, nothing left to change
+        assert_eq!(db.correct(&mut rom), None);
+    }
+
+    #[test]
+    fn other_crcs_are_left_alone() {
+        let mut rom = Rom::from_bytes(&test_image(&[0x4C, 0x00, 0x80])).unwrap();
+        assert_eq!(CartDb::builtin().correct(&mut rom), None);
+        assert_eq!(rom.mirroring, Mirroring::HORIZONTAL);
+    }
+}
@@ -0,0 +1,127 @@
+// Cheat codes that patch what the CPU reads from PRG-ROM, the way a Game Genie sits between the console and the cartridge.
+// Codes are either Game Genie letters (6 or 8 characters) or raw hex, "AAAA:VV" or "AAAA?CC:VV".
+// A compare byte only applies the patch when the ROM holds that value, which keeps it to the right bank.
+
+// Game Genie letters in the order of the 4 bit values they stand for
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl Cheat {
+    pub fn parse(code: &str) -> Result<Cheat, String> {
+        let code = code.trim().to_ascii_uppercase();
+        if code.contains(':') {
+            Cheat::parse_raw(&code)
+        } else {
+            Cheat::parse_game_genie(&code)
+        }
+    }
+
+    // The value the CPU sees for a byte read from PRG-ROM
+    pub fn apply(&self, addr: u16, data: u8) -> u8 {
+        if addr == self.address && self.compare.is_none_or(|compare| compare == data) {
+            self.value
+        } else {
+            data
+        }
+    }
+
+    // The letters scramble the bits of a 15 bit address in $8000-$FFFF, a value, and for 8 letters a compare byte
+    fn parse_game_genie(code: &str) -> Result<Cheat, String> {
+        let n: Vec<u16> = code
+            .chars()
+            .map(|c| GAME_GENIE_LETTERS.find(c).map(|i| i as u16))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| format!("{} is not a Game Genie code", code))?;
+
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie codes are 6 or 8 letters, {} has {}", code, n.len()));
+        }
+
+        let address = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8) | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4) | ((n[1] & 8) << 4)
+            | (n[4] & 7) | (n[3] & 8);
+
+        let (value, compare) = if n.len() == 6 {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[5] & 8);
+            (value as u8, None)
+        } else {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[7] & 8);
+            let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            (value as u8, Some(compare as u8))
+        };
+
+        Ok(Cheat { address, value, compare })
+    }
+
+    // AAAA:VV or AAAA?CC:VV, all hex
+    fn parse_raw(code: &str) -> Result<Cheat, String> {
+        let invalid = || format!("{} is not a valid AAAA:VV or AAAA?CC:VV code", code);
+        let hex_u8 = |text: &str| u8::from_str_radix(text, 16).map_err(|_| invalid());
+
+        let (target, value) = code.split_once(':').ok_or_else(invalid)?;
+        let (address, compare) = match target.split_once('?') {
+            Some((address, compare)) => (address, Some(hex_u8(compare)?)),
+            None => (target, None),
+        };
+
+        let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+        if address < 0x8000 {
+            return Err(format!("{} patches {:04X}, cheats only apply to PRG-ROM at $8000-$FFFF", code, address));
+        }
+
+        Ok(Cheat { address, value: hex_u8(value)?, compare })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+    use crate::ppu::PPU;
+    use crate::rom::{test_image, Rom};
+
+    #[test]
+    fn game_genie_codes_decode() {
+        assert_eq!(Cheat::parse("GOSSIP"), Ok(Cheat { address: 0xD1DD, value: 0x14, compare: None }));
+        assert_eq!(Cheat::parse("zexpygla"), Ok(Cheat { address: 0x94A7, value: 0x02, compare: Some(0x03) }));
+        assert_eq!(Cheat::parse("C123?EA:99"), Ok(Cheat { address: 0xC123, value: 0x99, compare: Some(0xEA) }));
+
+        assert!(Cheat::parse("GOSSIB").is_err());
+        assert!(Cheat::parse("GOSS").is_err());
+        assert!(Cheat::parse("0123:45").is_err());
+    }
+
+    #[test]
+    fn cheats_patch_prg_reads() {
+        // PRG is all $EA apart from the program at $8000
+        let rom = Rom::from_bytes(&test_image(&[0xA9, 0x42])).unwrap();
+        let mut bus = Bus::new(Rc::new(RefCell::new(PPU::new(rom.c_rom.clone(), rom.mirroring))), rom);
+
+        bus.add_cheat("GOSSIP").unwrap();
+        bus.add_cheat("8001:99").unwrap();
+        // The compare byte keeps a code to the bank it was made for
+        bus.add_cheat("8002?EA:55").unwrap();
+        bus.add_cheat("ZEXPYGLA").unwrap();
+
+        assert_eq!(bus.mem_read(0xD1DD), 0x14);
+        assert_eq!(bus.mem_read(0x8000), 0xA9);
+        assert_eq!(bus.mem_read(0x8001), 0x99);
+        assert_eq!(bus.mem_read(0x8002), 0x55);
+        assert_eq!(bus.mem_read(0x94A7), 0xEA);
+
+        bus.clear_cheats();
+        assert_eq!(bus.mem_read(0xD1DD), 0xEA);
+        assert_eq!(bus.mem_read(0x8001), 0x42);
+    }
+}
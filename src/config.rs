@@ -0,0 +1,143 @@
+// User settings loaded from a TOML file at startup, so controls and options can change without recompiling.
+// Every field is optional, anything missing from the file keeps its default.
+//
+// Example config.toml:
+//   scale = 3.0
+//   region = "pal"
+//   volume = 0.5
+//   seed = 1234
+//
+//   [keys]
+//   a = "Z"
+//   b = "X"
+
+use serde::Deserialize;
+
+pub const DEFAULT_PATH: &str = "config.toml";
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    NTSC,
+    PAL,
+}
+
+impl Region {
+    // Milliseconds per frame, rounded like the main loop's sleep
+    pub fn frame_millis(&self) -> u64 {
+        match self {
+            Region::NTSC => 16,
+            Region::PAL => 20,
+        }
+    }
+
+    // CPU cycles per second
+    pub fn cpu_clock(&self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+        }
+    }
+}
+
+// Key names as SDL spells them, e.g. "Return", "Space", "Left Shift"
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub a: String,
+    pub b: String,
+    pub select: String,
+    pub start: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            a: "A".to_string(),
+            b: "S".to_string(),
+            select: "Space".to_string(),
+            start: "Return".to_string(),
+            up: "Up".to_string(),
+            down: "Down".to_string(),
+            left: "Left".to_string(),
+            right: "Right".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub keys: KeyBindings,
+    pub scale: f32,
+    pub region: Region,
+    pub volume: f32,
+    // Fills power-on RAM with seeded random bytes instead of zeros
+    pub seed: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            keys: KeyBindings::default(),
+            scale: 10.0,
+            region: Region::NTSC,
+            volume: 1.0,
+            seed: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| format!("Invalid config: {}", e))
+    }
+
+    // Load the config file, falling back to the defaults when it does not exist
+    pub fn load(path: &str) -> Result<Config, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Could not read config {}: {}", path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_keeps_defaults() {
+        let config = Config::parse("region = \"pal\"\n\n[keys]\na = \"Z\"\n").unwrap();
+        let defaults = Config::default();
+
+        assert_eq!(config.region, Region::PAL);
+        assert_eq!(config.keys.a, "Z");
+        assert_eq!(config.keys.b, defaults.keys.b);
+        assert_eq!(config.keys.start, defaults.keys.start);
+        assert_eq!(config.scale, defaults.scale);
+        assert_eq!(config.volume, defaults.volume);
+        assert_eq!(config.seed, None);
+    }
+
+    #[test]
+    fn empty_or_missing_config_is_all_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!((config.scale, config.region, config.keys.a.as_str()), (10.0, Region::NTSC, "A"));
+
+        let missing = std::env::temp_dir().join("nes-config-test-does-not-exist.toml");
+        let config = Config::load(missing.to_str().unwrap()).unwrap();
+        assert_eq!(config.volume, 1.0);
+    }
+
+    #[test]
+    fn bad_values_are_reported() {
+        assert!(Config::parse("region = \"secam\"").is_err());
+        assert!(Config::parse("scale = \"big\"").is_err());
+    }
+}
@@ -16,11 +16,11 @@
 // Index Y:             General Register
 // Processor Status:    Represents 7 status flags
 
-use std::collections::HashMap;
-use crate::{bus, opcodes::{self, OPCode}};
+use crate::{bus, opcodes::{self, OPCode}, profiler::InstructionProfiler};
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
+const OAM_DMA: u16 = 0x4014;
 
 pub struct CPU {
     pub register_a: u8,
@@ -31,6 +31,20 @@ pub struct CPU {
     pub flags: Flags,
     pub bus: bus::Bus,
     pub cycles: usize,
+    // Panic on an opcode missing from the table, when false it runs as a 1 byte NOP so a buggy ROM can limp along
+    pub strict_opcodes: bool,
+    // Counts executions per PC and opcode once enabled with --profile-instructions
+    pub instruction_profiler: Option<InstructionProfiler>,
+}
+
+// Overall progress of the console, a copy taken at one point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MachineStatus {
+    pub cpu_cycles: usize,
+    pub ppu_frames: usize,
+    pub scanline: isize,
+    pub dot: usize,
+    pub pc: u16,
 }
 
 #[derive(Clone)]
@@ -41,14 +55,14 @@ pub struct Flags {
     | |   | | | | +---- Carry
     | |   | | | +------ Zero
     | |   | | +-------- Interrupt Disable
-    | |   | +---------- Decimal (Not Used)
+    | |   | +---------- Decimal (Stored, but the 2A03 has no BCD mode)
     | |   +------------ Break
     | +---------------- Overflow
     +------------------ Negative
     */
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum AddressingMode {
     Immediate,
     ZeroPage,
@@ -72,16 +86,31 @@ pub trait Mem {
     // Read two data bytes in little endian format at address
     fn mem_read_16(&self, addr: u16) -> u16 {
         let lo = self.mem_read(addr) as u16;
-        let hi = self.mem_read(addr + 1) as u16;
+        let hi = self.mem_read(addr.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
+    // Read two data bytes in little endian format, fetching the high byte from the same page
+    // Matches the 6502 pointer fetches, e.g. $FFFF reads its high byte from $FF00
+    fn mem_read_16_wrapping(&self, addr: u16) -> u16 {
+        let lo = self.mem_read(addr) as u16;
+        let hi = self.mem_read((addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF)) as u16;
+        (hi << 8) | lo
+    }
+
+    // Read a zero page pointer, the high byte wraps within page 0 so a pointer at $FF reads its high byte from $00
+    fn mem_read_zp_16(&self, ptr: u8) -> u16 {
+        let lo = self.mem_read(ptr as u16) as u16;
+        let hi = self.mem_read(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
     // Write two data bytes in little endian format at address
     fn mem_write_16(&mut self, addr: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xFF) as u8;
         self.mem_write(addr, lo);
-        self.mem_write(addr + 1, hi);
+        self.mem_write(addr.wrapping_add(1), hi);
     }
 }
 
@@ -92,12 +121,25 @@ impl Mem for CPU {
 
     fn mem_write(&mut self, addr: u16, data: u8) { 
         self.bus.mem_write(addr, data);
+
+        // OAM DMA halts the CPU for 513 cycles, or 514 when it starts on an odd CPU cycle
+        // The PPU and APU keep running through the stall
+        if addr == OAM_DMA {
+            let stall = if self.cycles % 2 == 1 { 514 } else { 513 };
+            for _ in 0..stall {
+                self.add_cycle();
+            }
+        }
     }
 
     fn mem_read_16(&self, addr: u16) -> u16 {
         return self.bus.mem_read_16(addr)
     }
 
+    fn mem_read_16_wrapping(&self, addr: u16) -> u16 {
+        self.bus.mem_read_16_wrapping(addr)
+    }
+
     fn mem_write_16(&mut self, addr: u16, data: u16) {
         self.bus.mem_write_16(addr, data);
     }
@@ -115,6 +157,8 @@ impl CPU {
             flags: Flags::new(),
             bus: bus,
             cycles: 0,
+            strict_opcodes: true,
+            instruction_profiler: None,
         }
     }
 
@@ -124,23 +168,65 @@ impl CPU {
         self.register_x = 0;
         self.register_y = 0;
         self.register_sp = STACK_RESET;
-        self.flags.bits = 0x24;
+        self.flags = Flags::from_byte(0x24);
         self.cycles = 0;
 
-        self.register_pc = self.mem_read_16(0xFFFC)
+        self.register_pc = self.mem_read_16_wrapping(0xFFFC)
+    }
+
+    // Cycle counts and raster position for tooling, without reaching into the PPU directly
+    pub fn machine_status(&self) -> MachineStatus {
+        let ppu = self.bus.ppu.borrow();
+        let (scanline, dot) = ppu.position();
+        MachineStatus {
+            cpu_cycles: self.cycles,
+            ppu_frames: ppu.frame_count(),
+            scanline,
+            dot,
+            pc: self.register_pc,
+        }
+    }
+
+    // Run instructions until the callback returns false, the callback sees the CPU before each instruction
+    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU) -> bool,
+    {
+        while callback(self) {
+            self.step();
+        }
+    }
+
+    // Run whole instructions until at least budget cycles have passed, returns the cycles actually run
+    // Instructions are never split, so the last one can overshoot the budget by a few cycles
+    pub fn run_cycles(&mut self, budget: usize) -> usize {
+        let start = self.cycles;
+        while self.cycles - start < budget {
+            self.step();
+        }
+        self.cycles - start
     }
 
     // Decode and execute program file
     pub fn step(&mut self) {
-        let ref opcodes: HashMap<u8, &'static opcodes::OPCode> = *opcodes::OPCodes_MAP;
+        // The IRQ line is level triggered, it keeps interrupting while held unless interrupts are disabled
+        if self.bus.irq_line() && !self.flags.int() {
+            self.interrupt_irq();
+        }
 
         // FETCH
         let code = self.mem_read(self.register_pc);
         self.register_pc += 1;
         let pc_before = self.register_pc;
+        if let Some(profiler) = self.instruction_profiler.as_mut() {
+            profiler.record(pc_before - 1, code);
+        }
 
         // DECODE
-        let opcode = opcodes.get(&code).expect(&format!("OPCode {:x} is not recognized", code));
+        let opcode = match self.decode(code) {
+            Some(opcode) => opcode,
+            None => return,
+        };
     
         // EXECUTE
         // Check the opcode with each opcode case
@@ -232,8 +318,9 @@ impl CPU {
             /* XAA */ 0x8b =>                                                   {self.uxaa(&opcode.mode)},
             
             /* NOPs */ 0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa =>               {self.unop()},
-            /* NOPs */ 0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 
-            | 0x92 | 0xb2 | 0xd2 | 0xf2 =>                                      {self.unop()},
+            // KIL/JAM lock up a real 6502, they have no table entry so decode() has already dealt with them
+            /* KIL */ 0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 
+            | 0x92 | 0xb2 | 0xd2 | 0xf2 =>                                      unreachable!(),
             /* NOP read */ 0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 
             | 0xd4 | 0xf4 | 0x0c | 0x1c| 0x3c | 0x5c | 0x7c | 0xdc | 0xfc =>    {self.unop_read(&opcode.mode)},
         }
@@ -245,31 +332,47 @@ impl CPU {
         // Update the cycles
         self.cycles += opcode.cycles as usize;
 
-        // Step through PPU 3 times per CPU Cycle
-        for _ in 0..opcode.cycles {
-            self.bus.ppu.borrow_mut().step();
-            self.bus.ppu.borrow_mut().step();
-            self.bus.ppu.borrow_mut().step();
+        // Step through PPU 3 times and APU once per CPU Cycle
+        self.bus.tick(opcode.cycles);
+    }
+
+    // Service the IRQ line, like BRK but with the Break flag clear in the pushed status
+    fn interrupt_irq(&mut self) {
+        self.stack_push_16(self.register_pc);
+        self.stack_push((self.flags.as_byte() | 0x20) & !0x10);
+        self.flags.set_int(true);
+        self.register_pc = self.mem_read_16_wrapping(0xFFFE);
+
+        for _ in 0..7 {
+            self.add_cycle();
         }
     }
 
     fn add_cycle(&mut self) {
         self.cycles += 1;
-        self.bus.ppu.borrow_mut().step();
-        self.bus.ppu.borrow_mut().step();
-        self.bus.ppu.borrow_mut().step();
+        self.bus.tick(1);
+    }
+
+    // An indexed read that crosses a page takes an extra cycle, spent reading the address before its high byte is fixed
+    // That dummy read is only issued for the I/O registers, where it has side effects such as an extra $2007 read
+    fn cross_page(&mut self, base: u16, addr: u16) {
+        let unfixed = (base & 0xFF00) | (addr & 0x00FF);
+        if (0x2000..=0x401F).contains(&unfixed) {
+            self.mem_read(unfixed);
+        }
+        self.add_cycle();
     }
 
     pub fn trigger_nmi(&mut self) {
         self.stack_push_16(self.register_pc);       // Push Program Counter to Stack
 
-        let mut flags = self.flags.bits;                // Set up Flags for Stack
+        let mut flags = self.flags.as_byte();               // Set up Flags for Stack
         flags |= 0x20;                                      // Set Bit 5 when pushed to stack
-        flags &= 0x10;                                      // Clear Break Flag when pushed to stack
+        flags &= !0x10;                                     // Clear Break Flag when pushed to stack
         self.stack_push(flags);                       // Push Status Register to Stack
         self.flags.set_int(true);                           // Set Interrupt Disable Flag
 
-        self.register_pc = self.mem_read_16(0xFFFA);  // Set Program Counter to NMI Vector
+        self.register_pc = self.mem_read_16_wrapping(0xFFFA);  // Set Program Counter to NMI Vector
 
         for _ in 0..7 {
             self.add_cycle();                               // Add 7 cycles for NMI
@@ -282,6 +385,7 @@ impl CPU {
 
     // Add Register A a value and set flags
     // Helper Method for ADC and SBC
+    // The 2A03 has no BCD circuitry, so this is always binary even with the Decimal flag set
     fn add_to_reg_a(&mut self, data: u8) {
         let sum = self.register_a as u16
             + data as u16 
@@ -298,9 +402,10 @@ impl CPU {
     }
 
     // Subtract a value from the A Register
+    // A - M - (1 - C) is the same as A + !M + C, so the flags come from add_to_reg_a
+    // Shared by SBC, the unofficial SBC, and ISC
     fn sub_from_reg_a(&mut self, data: u8) {
-        self.add_to_reg_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
-        self.update_flags(self.register_a);
+        self.add_to_reg_a(!data);
     }
 
     // AND a value with the A Register
@@ -361,13 +466,13 @@ impl CPU {
             AddressingMode::AbsoluteX => {
                 let base = self.mem_read_16(addr);
                 let addr = base.wrapping_add(self.register_x as u16);
-                if cycle_page && (base & 0xFF00) != (addr & 0xFF00) { self.add_cycle(); }
+                if cycle_page && (base & 0xFF00) != (addr & 0xFF00) { self.cross_page(base, addr); }
                 addr
             }
             AddressingMode::AbsoluteY => {
                 let base = self.mem_read_16(addr);
                 let addr = base.wrapping_add(self.register_y as u16);
-                if cycle_page && (base & 0xFF00) != (addr & 0xFF00) { self.add_cycle(); }
+                if cycle_page && (base & 0xFF00) != (addr & 0xFF00) { self.cross_page(base, addr); }
                 addr
             }
 
@@ -375,18 +480,14 @@ impl CPU {
                 let base = self.mem_read(addr);
 
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
+                self.mem_read_zp_16(ptr)
             }
             AddressingMode::IndirectY => {
                 let base = self.mem_read(addr);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | lo as u16;
+                let deref_base = self.mem_read_zp_16(base);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
-                if cycle_page && (deref_base & 0xFF00) != (deref & 0xFF00) { self.add_cycle(); }
+                if cycle_page && (deref_base & 0xFF00) != (deref & 0xFF00) { self.cross_page(deref_base, deref); }
                 deref
             }
 
@@ -396,6 +497,21 @@ impl CPU {
         }
     }
 
+    // A byte with no table entry (the KIL/JAM opcodes) panics in strict mode, otherwise it is spent
+    // as a 2 cycle NOP and None tells step() there is nothing left to execute
+    fn decode(&mut self, code: u8) -> Option<&'static OPCode> {
+        match opcodes::OPCODE_TABLE[code as usize] {
+            Some(opcode) => Some(opcode),
+            None if !self.strict_opcodes => {
+                println!("OPCode {:x} is not recognized, running it as a NOP at {:04X}", code, self.register_pc.wrapping_sub(1));
+                self.cycles += 2;
+                self.bus.tick(2);
+                None
+            }
+            None => panic!("OPCode {:x} is not recognized", code),
+        }
+    }
+
     fn get_operand_address(&mut self, mode: &AddressingMode, cycle_page: bool) -> u16 {
         match mode {
             AddressingMode::Immediate => self.register_pc,
@@ -502,6 +618,9 @@ impl CPU {
     }
 
     // Test if one or more bits are set at a memory location
+    // Only zero page and absolute exist on the 6502, the opcode table has no other BIT entries
+    // N and V copy bits 7 and 6 of the memory value itself, only Z depends on the AND,
+    // so a value like $80 against A = $00 sets N and Z together
     fn bit(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode, false);
         let data = self.mem_read(addr);
@@ -530,8 +649,8 @@ impl CPU {
     // Force the generation of an interrupt request, pushing status to the stack and loading IRQ interrupt vector at $FFFE/F in the PC
     fn brk(&mut self) {
         self.stack_push_16(self.register_pc);
-        self.stack_push(self.flags.bits);
-        self.register_pc = self.mem_read_16(0xFFFE);
+        self.stack_push(self.flags.as_byte());
+        self.register_pc = self.mem_read_16_wrapping(0xFFFE);
         self.flags.set_bflag(true);
     }
 
@@ -551,6 +670,7 @@ impl CPU {
     }
 
     // Set Decimal Mode to False
+    // The flag is stored and pushed with the status, but the NES ignores it for arithmetic
     fn cld(&mut self) {
         self.flags.set_decimal(false);
     }
@@ -638,17 +758,17 @@ impl CPU {
     }
 
     // Jump to a specific program counter address
+    // The instruction's 5 cycles come from the opcode table like every other instruction
     fn jmp_ind(&mut self) {
         let addr = self.mem_read_16(self.register_pc);
+        self.register_pc = self.read_indirect_buggy(addr);
+    }
 
-        // Fixes a bug on older CPUs
-        let indirect_ref = if addr & 0x00FF == 0x00FF {
-            let lo = self.mem_read(addr);
-            let hi = self.mem_read(addr & 0xFF00);
-            (hi as u16) << 8 | (lo as u16)
-        } else {self.mem_read_16(addr)};
-
-        self.register_pc = indirect_ref;
+    // Where JMP (vector) lands, shared with the trace so both show the same target
+    // Reproduces the 6502 bug where a vector at $xxFF takes its high byte from $xx00,
+    // so JMP ($30FF) reads $30FF and $3000 rather than $30FF and $3100. Vectors inside a page read normally.
+    pub fn read_indirect_buggy(&self, vector: u16) -> u16 {
+        self.mem_read_16_wrapping(vector)
     }
 
     // Jump to the subroutine and store current address on the stack
@@ -734,7 +854,7 @@ impl CPU {
         let mut flags = self.flags.clone();
         flags.set_bflag(true);
         flags.set_uflag(true);
-        self.stack_push(flags.bits);
+        self.stack_push(flags.as_byte());
     }
 
     // Pull an 8 bit value from the stack into the A register
@@ -746,7 +866,7 @@ impl CPU {
 
     // Pull an 8 bit value from the stack into the processor flags
     fn plp(&mut self) {
-        self.flags.bits = self.stack_pop();
+        self.flags = Flags::from_byte(self.stack_pop());
         self.flags.set_bflag(false);
         self.flags.set_uflag(true);
     }
@@ -815,7 +935,7 @@ impl CPU {
 
     // Return from an Interrupt processing routine to the address stored on the stack
     fn rti(&mut self) {
-        self.flags.bits = self.stack_pop();
+        self.flags = Flags::from_byte(self.stack_pop());
         self.flags.set_bflag(false);
         self.flags.set_uflag(true);
 
@@ -832,7 +952,7 @@ impl CPU {
         let addr = self.get_operand_address(&mode, true);
         let data = self.mem_read(addr);
         
-        self.add_to_reg_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        self.sub_from_reg_a(data);
     }
 
     // Set Carry Flag to True
@@ -841,6 +961,7 @@ impl CPU {
     }
 
     // Set Decimal Mode to True
+    // The flag is stored and pushed with the status, but ADC and SBC stay binary on the NES
     fn sed(&mut self) {
         self.flags.set_decimal(true);
     }
@@ -909,16 +1030,29 @@ impl CPU {
 
     // Store A & X & Hi+1
     fn uahx_ay(&mut self) {
-        let addr = self.mem_read_16(self.register_pc) + self.register_y as u16;
-        let data = self.register_a & self.register_x & (addr >> 8) as u8;
-        self.mem_write(addr, data);
+        let base = self.mem_read_16(self.register_pc);
+        self.store_and_high(base, self.register_y, self.register_a & self.register_x);
     }
     
     // Store A & X & Hi+1
     fn uahx_iy(&mut self) {
         let pos = self.mem_read(self.register_pc);
-        let addr = self.mem_read_16(pos as u16) + self.register_y as u16;
-        let data = self.register_a & self.register_x & (addr >> 8) as u8;
+        let base = self.mem_read_zp_16(pos);
+        self.store_and_high(base, self.register_y, self.register_a & self.register_x);
+    }
+
+    // Shared by AHX, SHX, SHY and TAS, which store a register value ANDed with the base address's high byte + 1
+    // When indexing crosses a page the high byte of the target address is replaced by that stored value,
+    // e.g. SHX $12F0,Y with X = $0F and Y = $20 writes $03 to $0310 rather than to $1310
+    fn store_and_high(&mut self, base: u16, index: u8, value: u8) {
+        let addr = base.wrapping_add(index as u16);
+        let data = value & ((base >> 8) as u8).wrapping_add(1);
+
+        let addr = if base & 0xFF00 != addr & 0xFF00 {
+            ((data as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
         self.mem_write(addr, data);
     }
     
@@ -974,11 +1108,13 @@ impl CPU {
 
         data = data.wrapping_sub(1);
         self.mem_write(addr, data);
+
         self.flags.set_carry(data <= self.register_a);
         self.update_flags(self.register_a.wrapping_sub(data));
     }
 
-    // Increment Address the Subtract from A Register
+    // Increment memory then subtract it from the A Register
+    // SBC uses the carry from before the instruction, INC leaves it alone, and Z/N/V end up from the subtraction
     fn uisb(&mut self, mode: &AddressingMode) {
         let data = self.inc(mode);
         self.sub_from_reg_a(data);
@@ -986,7 +1122,7 @@ impl CPU {
     
     // Memory Byte & Stack Pointer, save to A, X, SP
     fn ulas(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode, false);
+        let addr = self.get_operand_address(mode, true);
         let mut data = self.mem_read(addr);
 
         data = data & self.register_sp;
@@ -1017,18 +1153,21 @@ impl CPU {
     }
     
     // Read Address, Do Nothing
+    // The AbsoluteX forms take the same page crossing cycle as a real read
     fn unop_read(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode, false);
+        let addr = self.get_operand_address(mode, true);
         let _data = self.mem_read(addr);
     }
     
-    // Rotate Left and AND with A Register
+    // Rotate memory left then AND it with the A Register
+    // Carry is bit 7 shifted out by the rotate, Z and N come from the AND
     fn urla(&mut self, mode: &AddressingMode) {
         let data = self.rol(mode);
         self.and_with_reg_a(data);
     }
 
-    // Rotate Right and Add with Carry to A Register
+    // Rotate memory right then add it to the A Register
+    // The bit 0 the rotate shifts out is the carry the ADC adds in, and ADC then sets C, V, Z and N
     fn urra(&mut self, mode: &AddressingMode) {
         let data = self.ror(mode);
         self.add_to_reg_a(data);
@@ -1041,7 +1180,7 @@ impl CPU {
         self.mem_write(addr, data);
     }
     
-    // Subtract from Reg A
+    // Subtract from Reg A, behaves exactly like the official SBC immediate
     fn usbc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode, false);
         let data = self.mem_read(addr);
@@ -1050,18 +1189,14 @@ impl CPU {
     
     // X & 2 Byte Address stored in memory
     fn ushx(&mut self) {
-        let addr = self.mem_read_16(self.register_pc) + self.register_y as u16;
-        // todo if cross page boundary { addr &= (self.x as u16) << 8}
-        let data = self.register_x & ((addr >> 8) as u8 + 1);
-        self.mem_write(addr, data);
+        let base = self.mem_read_16(self.register_pc);
+        self.store_and_high(base, self.register_y, self.register_x);
     }
     
     // Y & 2 Byte address stored in memory
     fn ushy(&mut self) {
-        let addr = self.mem_read_16(self.register_pc) + self.register_x as u16;
-        // todo if cross page boundary { addr &= (self.x as u16) << 8}
-        let data = self.register_y & ((addr >> 8) as u8 + 1);
-        self.mem_write(addr, data);
+        let base = self.mem_read_16(self.register_pc);
+        self.store_and_high(base, self.register_x, self.register_y);
     }
     
     // 2 Byte Do Nothing
@@ -1069,13 +1204,15 @@ impl CPU {
         // Do Nothing
     }
     
-    // Shift bits left and then or with A Register
+    // Shift memory left then OR it with the A Register
+    // Carry is bit 7 shifted out by the shift, Z and N come from the OR
     fn uslo(&mut self, mode: &AddressingMode) {
         let data = self.asl(mode);
         self.or_with_reg_a(data);
     }
     
-    // Shift bits right and then XOR with A Register
+    // Shift memory right then XOR it with the A Register
+    // Carry is bit 0 shifted out by the shift, Z and N come from the XOR
     fn usre(&mut self, mode: &AddressingMode) {
         let data = self.lsr(mode);
         self.xor_with_reg_a(data);
@@ -1086,9 +1223,8 @@ impl CPU {
         let data = self.register_a & self.register_x;
         self.register_sp = data;
 
-        let addr = self.mem_read_16(self.register_pc) + self.register_y as u16;
-        let data = ((addr >> 8) as u8 + 1) & self.register_sp;
-        self.mem_write(addr, data);
+        let base = self.mem_read_16(self.register_pc);
+        self.store_and_high(base, self.register_y, self.register_sp);
     }
 
     // Set A to X then AND with a byte of memory
@@ -1107,6 +1243,16 @@ impl Flags {
         Flags { bits: 0x24 }
     }
 
+    // Build a set of flags from a raw status byte
+    pub fn from_byte(bits: u8) -> Self {
+        Flags { bits }
+    }
+
+    // Raw status byte as pushed to the stack or shown in a trace
+    pub fn as_byte(&self) -> u8 {
+        self.bits
+    }
+
     fn set_bit(&mut self, bit: u8, value: bool) {
         if value { self.bits |= 1 << bit; }
         else { self.bits &= !(1 << bit); }
@@ -1116,14 +1262,14 @@ impl Flags {
         (self.bits & (1 << bit)) != 0
     }
 
-    fn carry(&self) -> bool     { self.get_bit(0) }
-    fn zero(&self) -> bool      { self.get_bit(1) }
-    fn int(&self) -> bool       { self.get_bit(2) }
-    fn decimal(&self) -> bool   { self.get_bit(3) }
-    fn bflag(&self) -> bool     { self.get_bit(4) }
-    fn uflag(&self) -> bool     { self.get_bit(5) }
-    fn overflow(&self) -> bool  { self.get_bit(6) }
-    fn negative(&self) -> bool  { self.get_bit(7) }
+    pub fn carry(&self) -> bool     { self.get_bit(0) }
+    pub fn zero(&self) -> bool      { self.get_bit(1) }
+    pub fn int(&self) -> bool       { self.get_bit(2) }
+    pub fn decimal(&self) -> bool   { self.get_bit(3) }
+    pub fn bflag(&self) -> bool     { self.get_bit(4) }
+    pub fn uflag(&self) -> bool     { self.get_bit(5) }
+    pub fn overflow(&self) -> bool  { self.get_bit(6) }
+    pub fn negative(&self) -> bool  { self.get_bit(7) }
 
     fn set_carry(&mut self, value: bool)        { self.set_bit(0, value); }
     fn set_zero(&mut self, value: bool)         { self.set_bit(1, value); }
@@ -1133,4 +1279,404 @@ impl Flags {
     fn set_uflag(&mut self, value: bool)        { self.set_bit(5, value); }
     fn set_overflow(&mut self, value: bool)     { self.set_bit(6, value); }
     fn set_negative(&mut self, value: bool)     { self.set_bit(7, value); }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{test_cpu as cpu, ORIGIN};
+
+    // Run every instruction in the source once
+    fn run(source: &str) -> CPU {
+        let mut cpu = cpu(source);
+        let instructions = source.lines().filter(|line| !line.split(';').next().unwrap().trim().is_empty()).count();
+        for _ in 0..instructions {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn dcp_matches_dec_then_cmp() {
+        // Equal after the decrement, a borrow, and a result with bit 7 set
+        for (a, m) in [(0x10u8, 0x11u8), (0x10, 0x12), (0x80, 0x01), (0x00, 0x00)] {
+            let setup = format!("LDA #${:02X}\nLDX #${:02X}\nSTX $10\n", a, m);
+            let dcp = run(&format!("{}*DCP $10", setup));
+            let reference = run(&format!("{}DEC $10\nCMP $10", setup));
+
+            assert_eq!(dcp.mem_read(0x10), m.wrapping_sub(1));
+            assert_eq!(dcp.mem_read(0x10), reference.mem_read(0x10));
+            assert_eq!(dcp.register_a, reference.register_a);
+            assert_eq!(dcp.flags.as_byte(), reference.flags.as_byte(), "A={:02X} M={:02X}", a, m);
+        }
+
+        let equal = run("LDA #$10\nLDX #$11\nSTX $10\n*DCP $10");
+        assert!(equal.flags.carry() && equal.flags.zero() && !equal.flags.negative());
+        let borrow = run("LDA #$10\nLDX #$12\nSTX $10\n*DCP $10");
+        assert!(!borrow.flags.carry() && !borrow.flags.zero() && borrow.flags.negative());
+    }
+
+    #[test]
+    fn flags_round_trip_through_a_byte() {
+        for byte in [0x00, 0x24, 0xA5, 0xFF] {
+            assert_eq!(Flags::from_byte(byte).as_byte(), byte);
+        }
+
+        let flags = Flags::from_byte(0b1100_0011);
+        assert!(flags.negative() && flags.overflow() && flags.zero() && flags.carry());
+        assert!(!flags.int() && !flags.decimal() && !flags.bflag() && !flags.uflag());
+    }
+
+    #[test]
+    fn reading_16_bits_at_ffff_does_not_overflow() {
+        let mut cpu = cpu("NOP");
+        cpu.mem_write(0xFFFF, 0x34);
+        cpu.mem_write(0x0000, 0x12);
+        cpu.mem_write(0xFF00, 0x56);
+
+        // A plain read carries into $0000, a pointer fetch stays on the page and takes its high byte from $FF00
+        assert_eq!(cpu.mem_read_16(0xFFFF), 0x1234);
+        assert_eq!(cpu.mem_read_16_wrapping(0xFFFF), 0x5634);
+        assert_eq!(cpu.mem_read_16_wrapping(0xFFFC), 0x8000);
+    }
+
+    #[test]
+    fn oam_dma_stalls_the_cpu_while_the_ppu_keeps_running() {
+        // STA absolute takes 4 cycles, so the DMA starts on the same parity the instruction did
+        for (start, stall) in [(7, 514), (8, 513)] {
+            let mut cpu = cpu("LDA #$02\nSTA $4014");
+            cpu.step();
+            cpu.cycles = start;
+
+            let dot = |cpu: &CPU| {
+                let ppu = cpu.bus.ppu.borrow();
+                ppu.scanline * 341 + ppu.cycles as isize
+            };
+            let before = dot(&cpu);
+            cpu.step();
+
+            assert_eq!(cpu.cycles - start, 4 + stall);
+            assert_eq!(dot(&cpu) - before, 3 * (4 + stall) as isize);
+        }
+    }
+
+    #[test]
+    fn decimal_flag_leaves_adc_and_sbc_binary() {
+        // The 2A03 has no BCD circuitry, D is only a flag
+        for (a, m, adc, sbc) in [(0x09u8, 0x01u8, 0x0Au8, 0x08u8), (0x99, 0x01, 0x9A, 0x98), (0x10, 0x01, 0x11, 0x0F), (0x50, 0x50, 0xA0, 0x00), (0xFF, 0x01, 0x00, 0xFE)] {
+            for (op, expected) in [("CLC\nADC", adc), ("SEC\nSBC", sbc)] {
+                let source = format!("LDA #${:02X}\n{} #${:02X}", a, op, m);
+                let decimal = run(&format!("SED\n{}", source));
+                let binary = run(&format!("CLD\n{}", source));
+
+                assert!(decimal.flags.decimal());
+                assert_eq!(decimal.register_a, expected, "{} with A={:02X} M={:02X}", op, a, m);
+                assert_eq!(decimal.flags.as_byte(), binary.flags.as_byte() | 0x08);
+            }
+        }
+    }
+
+    #[test]
+    fn unofficial_sbc_matches_the_official_one() {
+        let values = [0x00u8, 0x01, 0x40, 0x7F, 0x80, 0x81, 0xFE, 0xFF];
+        for a in values {
+            for m in values {
+                for carry in ["CLC", "SEC"] {
+                    let setup = format!("LDA #${:02X}\n{}", a, carry);
+                    let official = run(&format!("{}\nSBC #${:02X}", setup, m));
+                    let unofficial = run(&format!("{}\n*SBC #${:02X}", setup, m));
+
+                    assert_eq!(unofficial.register_a, official.register_a);
+                    assert_eq!(unofficial.flags.as_byte(), official.flags.as_byte(), "A={:02X} M={:02X} {}", a, m, carry);
+                    assert_eq!(unofficial.cycles, official.cycles);
+                }
+            }
+        }
+
+        // 0x50 - 0xB0 overflows into a negative result, 0xD0 - 0x70 overflows into a positive one
+        let overflow = run("LDA #$50\nSEC\n*SBC #$B0");
+        assert_eq!(overflow.register_a, 0xA0);
+        assert!(overflow.flags.overflow() && overflow.flags.negative() && !overflow.flags.carry());
+        let overflow = run("LDA #$D0\nSEC\n*SBC #$70");
+        assert_eq!(overflow.register_a, 0x60);
+        assert!(overflow.flags.overflow() && !overflow.flags.negative() && overflow.flags.carry());
+    }
+
+    #[test]
+    fn zero_page_pointers_wrap_within_page_zero() {
+        // Pointer at $FF: low byte from $FF, high byte from $00 rather than $0100
+        let setup = "LDA #$34\nSTA $FF\nLDA #$12\nSTA $00\nLDA #$56\nSTA $0100\n\
+                     LDA #$AB\nSTA $1234\nLDA #$CD\nSTA $5634\nLDA #$EF\nSTA $1235\n";
+
+        let cpu = run(&format!("{}LDY #$00\nLDA ($FF),Y", setup));
+        assert_eq!(cpu.register_a, 0xAB);
+        let cpu = run(&format!("{}LDY #$01\nLDA ($FF),Y", setup));
+        assert_eq!(cpu.register_a, 0xEF);
+
+        // ($FE,X) with X=1 lands on the same pointer
+        let cpu = run(&format!("{}LDX #$01\nLDA ($FE,X)", setup));
+        assert_eq!(cpu.register_a, 0xAB);
+
+        let cpu = run(setup);
+        assert_eq!(cpu.mem_read_zp_16(0xFF), 0x1234);
+        assert_eq!(cpu.mem_read_16(0x00FF), 0x5634);
+    }
+
+    #[test]
+    fn unofficial_opcode_cycle_counts() {
+        // ($10) points at $12F0, X and Y of $01 stay on the page and $FF crosses it
+        let cycles = |index: u8, instruction: &str| {
+            let setup = format!("LDA #$F0\nSTA $10\nLDA #$12\nSTA $11\nLDX #${:02X}\nLDY #${:02X}", index, index);
+            run(&format!("{}\n{}", setup, instruction)).cycles - run(&setup).cycles
+        };
+
+        // Reads pay one more cycle when the index crosses a page, like LDA
+        for (instruction, base) in [("*LAX $12F0,Y", 4), ("*LAX ($10),Y", 5), ("*LAS $12F0,Y", 4), ("*NOP $12F0,X", 4), ("LDA $12F0,Y", 4)] {
+            assert_eq!(cycles(0x01, instruction), base, "{}", instruction);
+            assert_eq!(cycles(0xFF, instruction), base + 1, "{} across a page", instruction);
+        }
+
+        // Read-modify-writes always take the fixed count
+        for (instruction, fixed) in [("*DCP $12F0,Y", 7), ("*ISB $12F0,Y", 7), ("*SLO ($10),Y", 8), ("*RRA $12F0,X", 7), ("INC $12F0,X", 7)] {
+            assert_eq!(cycles(0x01, instruction), fixed, "{}", instruction);
+            assert_eq!(cycles(0xFF, instruction), fixed, "{} across a page", instruction);
+        }
+
+        // Zero page and absolute forms never cross
+        assert_eq!(cycles(0xFF, "*LAX $10,Y"), 4);
+        assert_eq!(cycles(0xFF, "*NOP $1234"), 4);
+        assert_eq!(cycles(0xFF, "*SRE $1234"), 6);
+    }
+
+    #[test]
+    fn bit_takes_n_and_v_from_memory_and_z_from_the_and() {
+        // A, memory, then N V Z
+        for (a, m, n, v, z) in [
+            (0x01u8, 0x80u8, true, false, true),
+            (0x80, 0x80, true, false, false),
+            (0xFF, 0x40, false, true, false),
+            (0x00, 0xC0, true, true, true),
+            (0x0F, 0x3F, false, false, false),
+            (0xF0, 0x0F, false, false, true),
+        ] {
+            for operand in ["$10", "$0210"] {
+                let cpu = run(&format!("LDX #${:02X}\nSTX $10\nSTX $0210\nLDA #${:02X}\nBIT {}", m, a, operand));
+                let flags = (cpu.flags.negative(), cpu.flags.overflow(), cpu.flags.zero());
+                assert_eq!(flags, (n, v, z), "A={:02X} M={:02X} BIT {}", a, m, operand);
+                // Neither A nor memory changes
+                assert_eq!((cpu.register_a, cpu.mem_read(0x10)), (a, m));
+            }
+        }
+
+        // Only zero page and absolute exist
+        let modes: Vec<_> = opcodes::CPU_OPCodeS.iter().filter(|op| op.name == "BIT").map(|op| op.code).collect();
+        assert_eq!(modes, [0x24, 0x2C]);
+    }
+
+    #[test]
+    fn machine_status_counts_three_dots_per_cycle() {
+        // Dots into the current frame with rendering off, every line is 341 dots and the pre-render line comes first
+        let dot = |status: &MachineStatus| (status.scanline + 1) as usize * 341 + status.dot;
+
+        let mut cpu = cpu("INX\nJMP $8000");
+        let start = cpu.machine_status();
+        assert_eq!(start.pc, ORIGIN);
+
+        let mut before = start;
+        for _ in 0..40_000 {
+            cpu.step();
+            let after = cpu.machine_status();
+            if after.ppu_frames == before.ppu_frames {
+                assert_eq!(dot(&after) - dot(&before), 3 * (after.cpu_cycles - before.cpu_cycles));
+            }
+            before = after;
+        }
+
+        assert_eq!(before.cpu_cycles - start.cpu_cycles, 40_000 / 2 * (2 + 3));
+        assert!(before.ppu_frames >= start.ppu_frames + 3);
+        assert_eq!(before.pc, cpu.register_pc);
+    }
+
+    #[test]
+    fn unstable_stores_and_with_the_high_byte_and_corrupt_on_a_page_cross() {
+        let setup = "LDA #$F0\nSTA $10\nLDA #$12\nSTA $11";
+        // Instruction, A, X, Y, then the address written and the value
+        for (instruction, a, x, y, addr, data) in [
+            // Y = $20 from $12F0 crosses into $13, so the high byte becomes the stored value
+            ("*SHX $12F0,Y", 0x00u8, 0x0Fu8, 0x20u8, 0x0310u16, 0x03u8),
+            ("*SHY $12F0,X", 0x00, 0x20, 0x0F, 0x0310, 0x03),
+            ("*AHX $12F0,Y", 0xFF, 0x0F, 0x20, 0x0310, 0x03),
+            ("*AHX ($10),Y", 0x0F, 0xFF, 0x20, 0x0310, 0x03),
+            // Staying on the page writes where addressed, still ANDed with $12 + 1
+            ("*SHX $1200,Y", 0x00, 0xFF, 0x10, 0x1210, 0x13),
+            ("*SHY $1200,X", 0x00, 0x10, 0xFF, 0x1210, 0x13),
+            ("*AHX $1200,Y", 0xF7, 0x3F, 0x10, 0x1210, 0x13),
+            ("*AHX ($10),Y", 0xF7, 0x3F, 0x01, 0x12F1, 0x13),
+        ] {
+            let cpu = run(&format!("{}\nLDA #${:02X}\nLDX #${:02X}\nLDY #${:02X}\n{}", setup, a, x, y, instruction));
+            assert_eq!(cpu.mem_read(addr), data, "{} with A={:02X} X={:02X} Y={:02X}", instruction, a, x, y);
+            if addr == 0x0310 {
+                assert_eq!(cpu.mem_read(0x1310), 0x00, "{} wrote the uncorrupted address", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn jmp_indirect_wraps_within_the_vector_page() {
+        // $30FF holds the low byte, the high byte comes from $3000 rather than $3100
+        let vectors = "LDA #$40\nSTA $30FF\nLDA #$12\nSTA $3000\nLDA #$34\nSTA $3100\n\
+                       LDA #$78\nSTA $3080\nLDA #$56\nSTA $3081\n";
+
+        let cpu = run(&format!("{}JMP ($30FF)", vectors));
+        assert_eq!(cpu.register_pc, 0x1240);
+
+        // A vector inside the page reads its two bytes normally
+        let cpu = run(&format!("{}JMP ($3080)", vectors));
+        assert_eq!(cpu.register_pc, 0x5678);
+        assert_eq!(run(vectors).cycles + 5, cpu.cycles);
+    }
+
+    // INX, the undefined $02 (KIL), INX
+    fn kil_program() -> CPU {
+        let mut cpu = cpu("INX\nNOP\nINX");
+        cpu.mem_write(0x8001, 0x02);
+        cpu
+    }
+
+    #[test]
+    fn lenient_mode_runs_an_undefined_opcode_as_a_nop() {
+        let mut cpu = kil_program();
+        cpu.strict_opcodes = false;
+        cpu.step();
+        assert!(crate::trace::trace(&mut cpu).starts_with("8001  02        ???"));
+        let cycles = cpu.cycles;
+        cpu.step();
+        assert_eq!(cpu.register_pc, 0x8002);
+        assert_eq!(cpu.cycles, cycles + 2);
+        cpu.step();
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "OPCode 2 is not recognized")]
+    fn strict_mode_panics_on_an_undefined_opcode() {
+        let mut cpu = kil_program();
+        assert!(cpu.strict_opcodes);
+        cpu.step();
+        cpu.step();
+    }
+
+    #[test]
+    fn run_cycles_stops_on_the_first_instruction_boundary_past_the_budget() {
+        let mut cpu = cpu("
+            LDX #$00
+            INX
+            STX $0200
+            INC $10
+            JMP $8002
+        ");
+        assert_eq!(cpu.run_cycles(0), 0);
+
+        let start = cpu.cycles;
+        let ran = cpu.run_cycles(100);
+        assert!((100..=107).contains(&ran), "{}", ran);
+        assert_eq!(cpu.cycles - start, ran);
+        // Instructions are atomic, so the PC is always on one of the loop's instructions
+        assert!([0x8002, 0x8003, 0x8006, 0x8008].contains(&cpu.register_pc));
+        assert!(cpu.mem_read(0x10) <= cpu.register_x);
+
+        // Running on from there behaves like it was never interrupted
+        let ran = cpu.run_cycles(100);
+        assert!((100..=107).contains(&ran), "{}", ran);
+    }
+
+    // What each read-modify-write combo leaves in memory, A, and P, worked out from the two official
+    // instructions it is made of: the shift or INC writes memory and its carry out feeds the ALU step
+    fn combo_reference(op: &str, a: u8, m: u8, p: u8) -> (u8, u8, u8) {
+        let carry_in = p & 0x01;
+        let adc = |a: u8, operand: u8, carry: u8| {
+            let sum = a as u16 + operand as u16 + carry as u16;
+            let result = sum as u8;
+            let overflow = (a ^ result) & (operand ^ result) & 0x80 != 0;
+            (result, sum > 0xFF, Some(overflow))
+        };
+        let (written, a, carry, overflow) = match op {
+            "*SLO" => (m << 1, a | (m << 1), m & 0x80 != 0, None),
+            "*RLA" => ((m << 1) | carry_in, a & ((m << 1) | carry_in), m & 0x80 != 0, None),
+            "*SRE" => (m >> 1, a ^ (m >> 1), m & 0x01 != 0, None),
+            "*RRA" => {
+                let rotated = (m >> 1) | (carry_in << 7);
+                let (a, carry, overflow) = adc(a, rotated, m & 0x01);
+                (rotated, a, carry, overflow)
+            }
+            "*ISB" => {
+                let incremented = m.wrapping_add(1);
+                let (a, carry, overflow) = adc(a, !incremented, carry_in);
+                (incremented, a, carry, overflow)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut p = p & !0x83;
+        if let Some(overflow) = overflow {
+            p = (p & !0x40) | (overflow as u8) << 6;
+        }
+        p |= carry as u8 | ((a == 0) as u8) << 1 | (a & 0x80);
+        (written, a, p)
+    }
+
+    #[test]
+    fn unofficial_rmw_combos_match_the_reference() {
+        let values = [0x00, 0x01, 0x40, 0x7F, 0x80, 0xC3, 0xFF];
+        for op in ["*SLO", "*RLA", "*SRE", "*RRA", "*ISB"] {
+            for a in values {
+                for m in values {
+                    for carry in ["CLC", "SEC"] {
+                        let mut cpu = cpu(&format!("LDA #${:02X}\nSTA $10\nLDA #${:02X}\n{}\n{} $10", m, a, carry, op));
+                        for _ in 0..4 {
+                            cpu.step();
+                        }
+                        let (written, expected_a, expected_p) = combo_reference(op, a, m, cpu.flags.as_byte());
+                        cpu.step();
+
+                        let case = format!("{} A={:02X} M={:02X} {}", op, a, m, carry);
+                        assert_eq!(cpu.mem_read(0x10), written, "{}", case);
+                        assert_eq!(cpu.register_a, expected_a, "{}", case);
+                        assert_eq!(cpu.register_x, 0, "{}", case);
+                        assert_eq!(cpu.flags.as_byte(), expected_p, "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flags_survive_an_nmi_and_rti() {
+        // Carry, decimal, overflow (from $40 + $40) and negative set, interrupts enabled
+        let mut cpu = run("
+            CLI
+            SEC
+            SED
+            CLC
+            LDA #$40
+            ADC #$40
+            SEC
+        ");
+        cpu.mem_write(0xFFFA, 0x00);
+        cpu.mem_write(0xFFFB, 0x90);
+        cpu.mem_write(0x9000, 0x40);
+        let before = cpu.flags.as_byte();
+        assert_eq!(before & 0xCF, 0xC9);
+        let (pc, sp) = (cpu.register_pc, cpu.register_sp);
+
+        cpu.trigger_nmi();
+        // Pushed with bit 5 set and B clear, every other flag as it was
+        assert_eq!(cpu.mem_read(0x0100 + sp.wrapping_sub(2) as u16), (before | 0x20) & !0x10);
+        assert!(cpu.flags.int());
+        assert_eq!(cpu.register_pc, 0x9000);
+
+        cpu.step();
+        assert_eq!(cpu.register_pc, pc);
+        assert_eq!(cpu.register_sp, sp);
+        assert_eq!(cpu.flags.as_byte() & 0xCF, before & 0xCF);
+    }
+}
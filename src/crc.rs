@@ -0,0 +1,17 @@
+// CRC-32 (IEEE) checksum used to fingerprint ROM images and rendered frames
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLYNOMIAL & mask);
+        }
+    }
+
+    !crc
+}
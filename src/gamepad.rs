@@ -1 +1,257 @@
-// Read inputs from a gamer and make it available for game logic
\ No newline at end of file
+// The standard NES controller is read one button at a time through a shift register at $4016/$4017.
+// While the strobe bit is set the register keeps reloading button A, once cleared each read shifts to the next button.
+// Order: A, B, Select, Start, Up, Down, Left, Right
+// Buttons with turbo set are seen as pressed and released in turn while they are held.
+
+use std::cell::Cell;
+
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+const FRAMES_PER_SECOND: u32 = 60;
+
+pub struct Joypad {
+    strobe: bool,
+    button_index: Cell<u8>,
+    pub buttons: u8,
+    // Turbo rate per button in presses per second, 0 when off
+    turbo: [u8; 8],
+    frame: u32,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: Cell::new(0),
+            buttons: 0,
+            turbo: [0; 8],
+            frame: 0,
+        }
+    }
+
+    // Write to $4016, bit 0 is the strobe
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index.set(0);
+        }
+    }
+
+    // Read the next button state, reads past the eighth button return 1
+    pub fn read(&self) -> u8 {
+        let response = self.peek();
+
+        if !self.strobe && self.button_index.get() < 8 {
+            self.button_index.set(self.button_index.get() + 1);
+        }
+
+        response
+    }
+
+    // Current button state without shifting the register
+    pub fn peek(&self) -> u8 {
+        let index = self.button_index.get();
+        if index > 7 {
+            return 1;
+        }
+
+        self.button_bit(index)
+    }
+
+    // State of one button in read order, 0 is A and 7 is Right
+    pub fn button_bit(&self, index: u8) -> u8 {
+        (self.effective_buttons() >> index) & 1
+    }
+
+    // Autofire the button at the given presses per second while it is held, 0 turns turbo off
+    pub fn set_turbo(&mut self, button: u8, rate: u8) {
+        for bit in 0..8 {
+            if button & (1 << bit) != 0 {
+                self.turbo[bit] = rate;
+            }
+        }
+    }
+
+    // Power cycle, the shift register and held buttons clear but the turbo settings stay
+    pub fn power_on(&mut self) {
+        *self = Joypad { turbo: self.turbo, ..Joypad::new() };
+    }
+
+    // Called once per frame, drives the turbo toggling
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    // Held buttons with any turbo button in its released half of the cycle masked out
+    // Each press lasts half a period, e.g. 15 presses per second is 2 frames on and 2 off
+    fn effective_buttons(&self) -> u8 {
+        let mut buttons = self.buttons;
+        for (bit, &rate) in self.turbo.iter().enumerate() {
+            if rate > 0 {
+                let half_period = (FRAMES_PER_SECOND / (rate as u32 * 2)).max(1);
+                if (self.frame / half_period) & 1 == 1 {
+                    buttons &= !(1 << bit);
+                }
+            }
+        }
+        buttons
+    }
+
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+}
+
+// Four Score adapter for 3-4 players
+// Each port reads 24 bits: its first controller, the controller behind it, then a signature identifying the port
+//   $4016: player 1, player 3, signature 0 0 0 1 0 0 0 0
+//   $4017: player 2, player 4, signature 0 0 1 0 0 0 0 0
+// Reads past the 24th bit return 1, like a standard controller past its 8th.
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0b0000_1000, 0b0000_0100];
+
+pub struct FourScore {
+    strobe: bool,
+    bit_index: [Cell<u8>; 2],
+}
+
+impl Default for FourScore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FourScore {
+    pub fn new() -> Self {
+        FourScore {
+            strobe: false,
+            bit_index: [Cell::new(0), Cell::new(0)],
+        }
+    }
+
+    // Write to $4016, the strobe restarts both ports
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.bit_index[0].set(0);
+            self.bit_index[1].set(0);
+        }
+    }
+
+    // Read the next bit from port 0 ($4016) or 1 ($4017), given the two controllers plugged into it
+    pub fn read(&self, port: usize, first: &Joypad, second: &Joypad) -> u8 {
+        let response = self.peek(port, first, second);
+
+        let index = &self.bit_index[port];
+        if !self.strobe && index.get() < 24 {
+            index.set(index.get() + 1);
+        }
+
+        response
+    }
+
+    pub fn peek(&self, port: usize, first: &Joypad, second: &Joypad) -> u8 {
+        match self.bit_index[port].get() {
+            index @ 0..=7 => first.button_bit(index),
+            index @ 8..=15 => second.button_bit(index - 8),
+            index @ 16..=23 => (FOUR_SCORE_SIGNATURES[port] >> (index - 16)) & 1,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strobe the controller and read all eight buttons, A first
+    fn read_all(joypad: &mut Joypad) -> Vec<u8> {
+        joypad.write(1);
+        joypad.write(0);
+        (0..8).map(|_| joypad.read()).collect()
+    }
+
+    #[test]
+    fn turbo_button_alternates_at_its_rate() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo(BUTTON_A, 15);
+        joypad.set_button(BUTTON_A | BUTTON_B, true);
+
+        // 15 presses per second is 2 frames pressed then 2 released, B is held throughout
+        let mut a = Vec::new();
+        for _ in 0..8 {
+            let buttons = read_all(&mut joypad);
+            assert_eq!(buttons[1], 1);
+            a.push(buttons[0]);
+            joypad.next_frame();
+        }
+        assert_eq!(a, [1, 1, 0, 0, 1, 1, 0, 0]);
+
+        // 30 presses per second flips every frame
+        joypad.set_turbo(BUTTON_A, 30);
+        let a: Vec<u8> = (0..4)
+            .map(|_| {
+                let a = read_all(&mut joypad)[0];
+                joypad.next_frame();
+                a
+            })
+            .collect();
+        assert_eq!(a, [1, 0, 1, 0]);
+
+        // Turbo never presses a button that is not held, and a rate of 0 turns it off
+        joypad.set_button(BUTTON_A, false);
+        assert_eq!(read_all(&mut joypad)[0], 0);
+        joypad.set_button(BUTTON_A, true);
+        joypad.set_turbo(BUTTON_A, 0);
+        for _ in 0..4 {
+            joypad.next_frame();
+            assert_eq!(read_all(&mut joypad)[0], 1);
+        }
+    }
+
+    #[test]
+    fn four_score_reads_both_players_then_the_signature() {
+        let mut players: Vec<Joypad> = (0..4).map(|_| Joypad::new()).collect();
+        players[0].set_button(BUTTON_A, true);
+        players[1].set_button(BUTTON_B, true);
+        players[2].set_button(BUTTON_START | BUTTON_RIGHT, true);
+        players[3].set_button(BUTTON_UP, true);
+
+        let mut four_score = FourScore::new();
+        four_score.write(1);
+        four_score.write(0);
+
+        let bits = |byte: u8| (0..8).map(move |bit| (byte >> bit) & 1);
+        let port0: Vec<u8> = (0..26).map(|_| four_score.read(0, &players[0], &players[2])).collect();
+        let port1: Vec<u8> = (0..26).map(|_| four_score.read(1, &players[1], &players[3])).collect();
+
+        // Player 1 or 2, player 3 or 4 from the 9th bit, the port's signature, then 1s
+        let expected = |first: u8, second: u8, signature: [u8; 8]| -> Vec<u8> {
+            bits(first).chain(bits(second)).chain(signature).chain([1, 1]).collect()
+        };
+        assert_eq!(port0, expected(BUTTON_A, BUTTON_START | BUTTON_RIGHT, [0, 0, 0, 1, 0, 0, 0, 0]));
+        assert_eq!(port1, expected(BUTTON_B, BUTTON_UP, [0, 0, 1, 0, 0, 0, 0, 0]));
+
+        // Strobing starts both ports over
+        four_score.write(1);
+        four_score.write(0);
+        assert_eq!(four_score.read(0, &players[0], &players[2]), 1);
+        assert_eq!(four_score.read(1, &players[1], &players[3]), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// Run a game without a window and fingerprint the picture, run with --headless
+// Prints one line, "frames=<n> crc32=<8 hex digits>", so CI can compare it against a golden value.
+// The final frame can also be saved as a PNG for looking at what the hash describes.
+// With --stats the speed counter is printed once a second before it, headless frames run unpaced.
+
+use std::fs::File;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::crc::crc32;
+use crate::machine::Machine;
+use crate::rom::Rom;
+use crate::stats::FrameStats;
+use crate::{HEIGHT, WIDTH};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// Largest block deflate can store uncompressed
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+// Run the machine from power-on for the given number of frames and return the last framebuffer
+pub fn run_frames(rom: Rom, frames: usize, show_stats: bool) -> Vec<u8> {
+    let frame_time = Duration::from_millis(rom.region.frame_millis());
+    let mut stats = show_stats.then(|| FrameStats::new(frame_time));
+    let mut machine = Machine::new(rom);
+    for _ in 0..frames {
+        let start = Instant::now();
+        machine.step_frame();
+        if let Some(summary) = stats.as_mut().and_then(|stats| stats.record_frame(start.elapsed())) {
+            println!("{}", summary);
+        }
+    }
+
+    let framebuffer = machine.ppu.borrow().framebuffer.to_vec();
+    framebuffer
+}
+
+// The stable summary line printed by --headless
+pub fn summary(frames: usize, framebuffer: &[u8]) -> String {
+    format!("frames={} crc32={:08X}", frames, crc32(framebuffer))
+}
+
+// Save an RGB framebuffer as a PNG
+// The image data is stored rather than compressed, which keeps the writer small at the cost of file size
+pub fn write_png(path: &str, framebuffer: &[u8]) -> Result<(), String> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filter and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Every row starts with filter type 0
+    let mut raw = Vec::with_capacity((WIDTH * 3 + 1) * HEIGHT);
+    for row in framebuffer.chunks(WIDTH * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    push_chunk(&mut png, b"IHDR", &header);
+    push_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    push_chunk(&mut png, b"IEND", &[]);
+
+    let mut file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+    file.write_all(&png).map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+// Length, type, data, then a CRC-32 of the type and data
+fn push_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Wrap data in a zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+
+    let blocks: Vec<&[u8]> = data.chunks(STORED_BLOCK_SIZE).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i == blocks.len() - 1;
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::ppu::palette_rgb;
+    use crate::rom::test_image;
+
+    // Sets the backdrop color to $30 and turns the background on, so every pixel is that color
+    fn tiny_rom() -> Rom {
+        let program = assemble(
+            "LDA #$3F\nSTA $2006\nLDA #$00\nSTA $2006\nLDA #$30\nSTA $2007\nLDA #$08\nSTA $2001\nJMP $8014",
+        )
+        .unwrap();
+        Rom::from_bytes(&test_image(&program)).unwrap()
+    }
+
+    #[test]
+    fn headless_run_hashes_the_final_frame() {
+        let framebuffer = run_frames(tiny_rom(), 3, false);
+        assert_eq!(framebuffer.len(), WIDTH * HEIGHT * 3);
+        assert_eq!(framebuffer, palette_rgb(0x30).repeat(WIDTH * HEIGHT));
+
+        let line = summary(3, &framebuffer);
+        assert_eq!(line, format!("frames=3 crc32={:08X}", crc32(&framebuffer)));
+        // Same ROM, same frames, same line
+        assert_eq!(summary(3, &run_frames(tiny_rom(), 3, false)), line);
+    }
+
+    #[test]
+    fn png_holds_the_framebuffer() {
+        let framebuffer = run_frames(tiny_rom(), 2, false);
+        let path = std::env::temp_dir().join(format!("nes-headless-test-{}.png", std::process::id()));
+        write_png(path.to_str().unwrap(), &framebuffer).unwrap();
+        let png = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(png[0..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 1, 0, 0, 0, 0, 240]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // Every stored block of pixels is in the file as is
+        let row: Vec<u8> = std::iter::once(0).chain(palette_rgb(0x30).repeat(WIDTH)).collect();
+        assert!(png.windows(row.len()).any(|window| window == row.as_slice()));
+    }
+
+    #[test]
+    fn zlib_stream_of_stored_blocks() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        assert_eq!(zlib_stored(b"abc")[..], [0x78, 0x01, 0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c', 0x02, 0x4D, 0x01, 0x27]);
+    }
+}
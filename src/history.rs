@@ -0,0 +1,175 @@
+// Instruction level undo for the debugger.
+// Rather than snapshotting the whole machine, each entry keeps the CPU registers from before one instruction
+// and the old value of every byte the instruction wrote, so stepping back restores exactly where it started.
+// Entries sit in a bounded ring buffer, the oldest is dropped once it fills.
+//
+// Only RAM writes are undone. PPU and APU register writes, and the dots and samples those components
+// advanced while the instruction ran, stay as they are.
+
+use std::collections::VecDeque;
+
+use crate::cpu::{Flags, CPU};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub status: u8,
+    pub cycles: usize,
+}
+
+impl Registers {
+    pub fn capture(cpu: &CPU) -> Self {
+        Registers {
+            a: cpu.register_a,
+            x: cpu.register_x,
+            y: cpu.register_y,
+            sp: cpu.register_sp,
+            pc: cpu.register_pc,
+            status: cpu.flags.as_byte(),
+            cycles: cpu.cycles,
+        }
+    }
+
+    pub fn restore(&self, cpu: &mut CPU) {
+        cpu.register_a = self.a;
+        cpu.register_x = self.x;
+        cpu.register_y = self.y;
+        cpu.register_sp = self.sp;
+        cpu.register_pc = self.pc;
+        cpu.flags = Flags::from_byte(self.status);
+        cpu.cycles = self.cycles;
+    }
+}
+
+// What one instruction changed, the writes hold (address, old value) in the order they happened
+pub struct InstructionDelta {
+    pub registers: Registers,
+    pub writes: Vec<(u16, u8)>,
+}
+
+pub struct History {
+    entries: VecDeque<InstructionDelta>,
+    capacity: usize,
+    pending: Option<Registers>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            pending: None,
+        }
+    }
+
+    // Remember the registers and start journaling writes for the instruction about to run
+    pub fn begin_instruction(&mut self, cpu: &mut CPU) {
+        self.pending = Some(Registers::capture(cpu));
+        cpu.bus.start_journal();
+    }
+
+    // Close the entry opened by begin_instruction, if there is one
+    // An entry is dropped when no cycles passed, e.g. when step_frame stopped before running the instruction
+    pub fn end_instruction(&mut self, cpu: &mut CPU) {
+        if let Some(registers) = self.pending.take() {
+            let writes = cpu.bus.take_journal();
+            if registers.cycles == cpu.cycles {
+                return;
+            }
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back(InstructionDelta { registers, writes });
+        }
+    }
+
+    // Undo the most recent instruction, returns false once the history runs out
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        self.end_instruction(cpu);
+
+        match self.entries.pop_back() {
+            Some(delta) => {
+                // Newest write first, so a byte written twice ends up with its oldest value
+                for &(addr, old) in delta.writes.iter().rev() {
+                    cpu.bus.restore_byte(addr, old);
+                }
+                delta.registers.restore(cpu);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::test_cpu as cpu;
+    use crate::cpu::Mem;
+
+    fn step(history: &mut History, cpu: &mut CPU) {
+        history.end_instruction(cpu);
+        history.begin_instruction(cpu);
+        cpu.step();
+    }
+
+    #[test]
+    fn step_back_returns_to_the_previous_instruction() {
+        let mut cpu = cpu("LDA #$11\nSTA $10\nINC $10\nPHA");
+        let mut history = History::new(8);
+        let state = |cpu: &CPU| (Registers::capture(cpu), cpu.mem_read(0x10), cpu.mem_read(0x01FD));
+
+        let mut states = vec![state(&cpu)];
+        for _ in 0..3 {
+            step(&mut history, &mut cpu);
+            states.push(state(&cpu));
+        }
+        assert_eq!(cpu.mem_read(0x10), 0x12);
+
+        assert!(history.step_back(&mut cpu));
+        assert_eq!(state(&cpu), states[2]);
+        assert_eq!(cpu.mem_read(0x10), 0x11);
+
+        // Running forward again after the undo lands on the same state
+        step(&mut history, &mut cpu);
+        assert_eq!(state(&cpu), states[3]);
+
+        for expected in states[..3].iter().rev() {
+            assert!(history.step_back(&mut cpu));
+            assert_eq!(&state(&cpu), expected);
+        }
+        assert!(!history.step_back(&mut cpu));
+    }
+
+    #[test]
+    fn oldest_entries_drop_once_full() {
+        let mut cpu = cpu("INX\nINX\nINX\nINX\nINX");
+        let mut history = History::new(2);
+        for _ in 0..5 {
+            step(&mut history, &mut cpu);
+        }
+
+        assert!(history.step_back(&mut cpu));
+        assert!(history.step_back(&mut cpu));
+        assert_eq!(cpu.register_x, 3);
+        assert!(!history.step_back(&mut cpu));
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+#![cfg_attr(debug_assertions, allow(dead_code))]
+#![cfg_attr(debug_assertions, allow(unused_imports))]
+
+pub mod apu;
+pub mod asm;
+pub mod bus;
+pub mod cartdb;
+pub mod cheats;
+pub mod config;
+pub mod crc;
+pub mod rom;
+pub mod cpu;
+pub mod gamepad;
+pub mod headless;
+pub mod history;
+pub mod machine;
+pub mod mapper;
+pub mod opcodes;
+pub mod pacing;
+pub mod ppu;
+pub mod profiler;
+pub mod selftest;
+pub mod stats;
+pub mod test_harness;
+pub mod trace;
+pub mod watch;
+pub mod zapper;
+
+#[macro_use]
+extern crate lazy_static;
+
+// Visible picture size in pixels
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
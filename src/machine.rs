@@ -0,0 +1,570 @@
+// The Machine ties the CPU, bus, and PPU together into a console that runs one frame at a time.
+// It also owns the controller input for player one, which can be recorded to or replayed from a movie file.
+
+// Movie File Format:
+// Magic:       "NESTAS" followed by 0x1A
+// ROM CRC:     4 bytes, little endian CRC-32 of the PRG and CHR data
+// Frames:      1 byte per frame, the controller button bitmask
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::{bus::Bus, config::Region, cpu::CPU, history::History, mapper, ppu::PPU, rom::{Mirroring, Rom}, trace::TraceLog, watch::Watch};
+
+const MOVIE_MAGIC: [u8; 7] = [b'N', b'E', b'S', b'T', b'A', b'S', 0x1A];
+const MOVIE_HEADER_SIZE: usize = 11;
+
+// Called with the finished RGB framebuffer
+pub type FrameCallback = Box<dyn FnMut(&[u8])>;
+
+// What the loaded cartridge reports about itself, for printing at load time and in bug reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineInfo {
+    pub mapper: u8,
+    pub mapper_name: &'static str,
+    // Sizes in bytes
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub chr_ram: bool,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+    pub crc32: u32,
+}
+
+impl fmt::Display for MachineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Mapper:    {} ({})", self.mapper, self.mapper_name)?;
+        writeln!(f, "PRG ROM:   {}KB", self.prg_size / 1024)?;
+        if self.chr_ram {
+            writeln!(f, "CHR RAM:   {}KB", self.chr_size / 1024)?;
+        } else {
+            writeln!(f, "CHR ROM:   {}KB", self.chr_size / 1024)?;
+        }
+        writeln!(f, "Mirroring: {:?}", self.mirroring)?;
+        writeln!(f, "Battery:   {}", if self.battery { "yes" } else { "no" })?;
+        writeln!(f, "Region:    {:?}", self.region)?;
+        write!(f, "CRC-32:    {:08X}", self.crc32)
+    }
+}
+
+pub struct Machine {
+    pub cpu: CPU,
+    pub ppu: Rc<RefCell<PPU>>,
+    pub rom_crc: u32,
+    rom: Rom,
+    buttons: u8,
+    recording: Option<BufWriter<File>>,
+    playback: Option<Vec<u8>>,
+    playback_frame: usize,
+    trace: Option<TraceLog>,
+    watch: Option<Watch>,
+    frame_callback: Option<FrameCallback>,
+    ram_seed: Option<u64>,
+    history: Option<History>,
+}
+
+impl Machine {
+    pub fn new(rom: Rom) -> Self {
+        let rom_crc = rom.crc32();
+
+        let (cpu, ppu) = Machine::build(rom.clone());
+
+        Machine {
+            cpu,
+            ppu,
+            rom_crc,
+            rom,
+            buttons: 0,
+            recording: None,
+            playback: None,
+            playback_frame: 0,
+            trace: None,
+            watch: None,
+            frame_callback: None,
+            ram_seed: None,
+            history: None,
+        }
+    }
+
+    // Wire up a fresh CPU, bus, and PPU for the cartridge and run the reset vector
+    // A fresh bus starts with work RAM cleared to zero
+    fn build(rom: Rom) -> (CPU, Rc<RefCell<PPU>>) {
+        let ppu = Rc::new(RefCell::new(PPU::new(rom.c_rom.clone(), rom.mirroring)));
+        let bus = Bus::new(ppu.clone(), rom);
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+
+        (cpu, ppu)
+    }
+
+    // Cartridge details from the ROM header and the mapper running it
+    pub fn info(&self) -> MachineInfo {
+        let chr_ram = self.rom.c_rom.is_empty();
+        MachineInfo {
+            mapper: self.rom.mapper,
+            mapper_name: mapper::name(self.rom.mapper),
+            prg_size: self.rom.p_rom.len(),
+            // Cartridges without CHR ROM get 8KB of CHR RAM
+            chr_size: if chr_ram { self.ppu.borrow().chr_rom.len() } else { self.rom.c_rom.len() },
+            chr_ram,
+            mirroring: self.rom.mirroring,
+            battery: self.rom.battery,
+            region: self.rom.region,
+            crc32: self.rom_crc,
+        }
+    }
+
+    // Power cycle, every component starts over and RAM is cleared, or filled from the seed when one is set
+    // The chips are powered on in place, so options set by the front-end (profilers, cheats, accessories) carry over
+    pub fn power_on(&mut self) {
+        self.ppu.borrow_mut().power_on();
+        self.cpu.bus.power_on();
+        if let Some(seed) = self.ram_seed {
+            // Seeded before the reset vector runs again, so every power on with the same seed starts identically
+            let mut rng = StdRng::seed_from_u64(seed);
+            self.cpu.bus.randomize_ram(&mut rng);
+        }
+        self.cpu.reset();
+
+        // Undo entries refer to the old RAM
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    // Randomize power-on RAM from a fixed seed, or go back to cleared RAM with None
+    // Takes effect immediately through a power cycle
+    pub fn set_ram_seed(&mut self, seed: Option<u64>) {
+        self.ram_seed = seed;
+        self.power_on();
+    }
+
+    // Soft reset, the CPU jumps through the reset vector again and the PPU clears its registers, RAM and VRAM keep their state
+    pub fn reset(&mut self) {
+        self.ppu.borrow_mut().reset();
+        self.cpu.reset();
+    }
+
+    // Press or release a button on the live controller
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+
+    // Copy the live or movie input into the controller the game reads through $4016
+    // Happens once per frame at the start of VBlank, so a frame never sees input change partway through
+    // and a recorded movie replays on exactly the same frames
+    fn latch_input(&mut self) {
+        let buttons = self.next_input();
+        self.cpu.bus.joypad1.buttons = buttons;
+        self.cpu.bus.joypad1.next_frame();
+
+        if let Some(writer) = self.recording.as_mut() {
+            if writer.write_all(&[buttons]).is_err() {
+                println!("Failed to write movie frame, recording stopped");
+                self.recording = None;
+            }
+        }
+    }
+
+    // Run the CPU until the PPU finishes drawing a frame
+    // Every frame after the first starts at the VBlank that ended the one before, so input is latched here
+    pub fn step_frame(&mut self) {
+        self.latch_input();
+
+        let ppu = self.ppu.clone();
+        let trace = &mut self.trace;
+        let history = &mut self.history;
+        let watch = &mut self.watch;
+        self.cpu.run_with_callback(|cpu| {
+            if let Some(watch) = watch.as_mut() {
+                watch.end_instruction(cpu);
+            }
+
+            // An NMI belongs to the entry of the instruction that follows it
+            if let Some(history) = history.as_mut() {
+                history.end_instruction(cpu);
+                history.begin_instruction(cpu);
+            }
+
+            if ppu.borrow().nmi_triggered {
+                cpu.trigger_nmi();
+                ppu.borrow_mut().nmi_triggered = false;
+            }
+
+            if ppu.borrow().is_new_frame {
+                ppu.borrow_mut().is_new_frame = false;
+                return false;
+            }
+
+            if let Some(log) = trace.as_mut() {
+                log.log(cpu);
+            }
+            if let Some(watch) = watch.as_mut() {
+                watch.begin_instruction(cpu);
+            }
+            true
+        });
+
+        if let Some(callback) = self.frame_callback.as_mut() {
+            callback(&self.ppu.borrow().framebuffer);
+        }
+    }
+
+    // Run exactly one instruction, taking a pending NMI first like step_frame does
+    pub fn step_instruction(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.end_instruction(&mut self.cpu);
+            history.begin_instruction(&mut self.cpu);
+        }
+
+        if self.ppu.borrow().nmi_triggered {
+            self.cpu.trigger_nmi();
+            self.ppu.borrow_mut().nmi_triggered = false;
+        }
+        self.ppu.borrow_mut().is_new_frame = false;
+
+        if let Some(watch) = self.watch.as_mut() {
+            watch.begin_instruction(&self.cpu);
+        }
+        self.cpu.step();
+        if let Some(watch) = self.watch.as_mut() {
+            watch.end_instruction(&self.cpu);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.end_instruction(&mut self.cpu);
+        }
+    }
+
+    // Run instructions until the PPU reaches the next scanline
+    // The CPU cannot stop mid-instruction, so the PPU ends a few dots into the new line
+    pub fn step_scanline(&mut self) {
+        let scanline = self.ppu.borrow().scanline;
+        while self.ppu.borrow().scanline == scanline {
+            self.step_instruction();
+        }
+    }
+
+    // Run instructions until VBlank begins, stopping before the NMI is taken
+    pub fn step_to_vblank(&mut self) {
+        loop {
+            let before = self.ppu.borrow().scanline;
+            self.step_instruction();
+            if before < 241 && self.ppu.borrow().scanline >= 241 {
+                break;
+            }
+        }
+    }
+
+    // Keep undo information for the last `capacity` instructions, or stop with None
+    pub fn set_history(&mut self, capacity: Option<usize>) {
+        self.history = capacity.map(History::new);
+        if self.history.is_none() {
+            self.cpu.bus.take_journal();
+        }
+    }
+
+    // Undo the last instruction, false when there is no history left to undo
+    pub fn step_back(&mut self) -> bool {
+        match self.history.as_mut() {
+            Some(history) => history.step_back(&mut self.cpu),
+            None => false,
+        }
+    }
+
+    // Receive the RGB framebuffer (256x240, 3 bytes per pixel) every time a frame completes
+    pub fn set_frame_callback(&mut self, callback: FrameCallback) {
+        self.frame_callback = Some(callback);
+    }
+
+    pub fn clear_frame_callback(&mut self) {
+        self.frame_callback = None;
+    }
+
+    // Log every executed instruction to a trace file
+    pub fn set_trace(&mut self, trace: Option<TraceLog>) {
+        self.trace = trace;
+    }
+
+    // Check every instruction against the CPU invariants and an optional oracle, or stop with None
+    pub fn set_watch(&mut self, watch: Option<Watch>) {
+        self.watch = watch;
+    }
+
+    pub fn watch(&self) -> Option<&Watch> {
+        self.watch.as_ref()
+    }
+
+    // Start recording controller input to a movie file
+    // Emulation restarts from power-on so the movie can be replayed deterministically
+    pub fn record(&mut self, path: &str) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| format!("Could not create movie {}: {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(&MOVIE_MAGIC).map_err(|e| e.to_string())?;
+        writer.write_all(&self.rom_crc.to_le_bytes()).map_err(|e| e.to_string())?;
+
+        self.stop();
+        self.power_on();
+        self.recording = Some(writer);
+        Ok(())
+    }
+
+    // Replay controller input from a movie file, overriding the live controller until it runs out
+    pub fn play(&mut self, path: &str) -> Result<(), String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Could not read movie {}: {}", path, e))?;
+
+        if bytes.len() < MOVIE_HEADER_SIZE || bytes[0..7] != MOVIE_MAGIC {
+            return Err(format!("{} is not a movie file", path));
+        }
+
+        let crc = u32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+        if crc != self.rom_crc {
+            return Err(format!("Movie was recorded on a different ROM (CRC {:08X}, loaded {:08X})", crc, self.rom_crc));
+        }
+
+        self.stop();
+        self.power_on();
+        self.playback = Some(bytes[MOVIE_HEADER_SIZE..].to_vec());
+        self.playback_frame = 0;
+        Ok(())
+    }
+
+    // Finish any recording or playback in progress and save the trace
+    pub fn stop(&mut self) {
+        if let Some(mut writer) = self.recording.take() {
+            if let Err(e) = writer.flush() {
+                println!("Failed to save movie: {}", e);
+            }
+        }
+        self.playback = None;
+
+        if let Some(log) = self.trace.as_mut() {
+            log.flush();
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    // Buttons for the coming frame, from the movie while one is playing
+    fn next_input(&mut self) -> u8 {
+        if let Some(frames) = self.playback.as_ref() {
+            if let Some(&buttons) = frames.get(self.playback_frame) {
+                self.playback_frame += 1;
+                return buttons;
+            }
+
+            println!("Movie finished after {} frames", self.playback_frame);
+            self.playback = None;
+        }
+
+        self.buttons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::Mem;
+    use crate::profiler::InstructionProfiler;
+    use crate::rom::test_image;
+    use crate::test_harness::TestHarness;
+    use crate::gamepad::{BUTTON_A, BUTTON_LEFT, BUTTON_START};
+    use std::cell::Cell;
+
+    fn machine(source: &str) -> Machine {
+        let program = assemble(source).unwrap();
+        Machine::new(Rom::from_bytes(&test_image(&program)).unwrap())
+    }
+
+    #[test]
+    fn reset_keeps_ram_and_power_on_clears_it() {
+        let mut machine = machine("JMP $8000");
+        machine.cpu.mem_write(0x0010, 0xAB);
+
+        machine.reset();
+        assert_eq!(machine.cpu.mem_read(0x0010), 0xAB);
+
+        machine.power_on();
+        assert_eq!(machine.cpu.mem_read(0x0010), 0x00);
+        assert_eq!(machine.cpu.register_pc, 0x8000);
+    }
+
+    #[test]
+    fn power_on_keeps_front_end_options() {
+        let mut machine = machine("JMP $8000");
+        machine.cpu.bus.enable_profiler();
+        machine.cpu.bus.test_harness = Some(TestHarness::new());
+        machine.cpu.bus.add_cheat("9000:42").unwrap();
+        machine.cpu.strict_opcodes = false;
+        machine.cpu.instruction_profiler = Some(InstructionProfiler::new());
+        let ppu = machine.ppu.clone();
+
+        machine.power_on();
+        assert!(machine.cpu.bus.access_stats().is_some());
+        assert!(machine.cpu.bus.test_harness.is_some());
+        assert!(!machine.cpu.strict_opcodes);
+        assert!(machine.cpu.instruction_profiler.is_some());
+        assert_eq!(machine.cpu.mem_read(0x9000), 0x42);
+        // The PPU is powered on in place, so anything holding it still sees the running one
+        assert!(Rc::ptr_eq(&ppu, &machine.ppu));
+    }
+
+    #[test]
+    fn a_recorded_movie_replays_to_the_same_state() {
+        // Read the controller in a loop, folding every read into a running sum at $11
+        let source = "LDA #$01\nSTA $4016\nLDA #$00\nSTA $4016\nLDX #$08\n\
+                      LDA $4016\nLSR A\nROL $10\nDEX\nBNE $800C\n\
+                      LDA $10\nCLC\nADC $11\nSTA $11\nJMP $8000";
+        let inputs = [0, BUTTON_A, BUTTON_A | BUTTON_LEFT, BUTTON_START, 0, BUTTON_LEFT];
+        let path = std::env::temp_dir().join(format!("nes-movie-test-{}.tas", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let state = |machine: &Machine| (machine.cpu.mem_read(0x10), machine.cpu.mem_read(0x11), machine.cpu.cycles, machine.cpu.register_pc);
+
+        let mut recorder = machine(source);
+        recorder.record(path).unwrap();
+        for &buttons in inputs.iter() {
+            recorder.set_button(0xFF, false);
+            recorder.set_button(buttons, true);
+            recorder.step_frame();
+        }
+        recorder.stop();
+        let recorded = state(&recorder);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), (MOVIE_HEADER_SIZE + inputs.len()) as u64);
+
+        // Live input is ignored while the movie plays
+        let mut player = machine(source);
+        player.set_button(BUTTON_START, true);
+        player.play(path).unwrap();
+        for _ in inputs.iter() {
+            player.step_frame();
+        }
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(state(&player), recorded);
+        assert_ne!(recorded.1, 0);
+    }
+
+    #[test]
+    fn frame_callback_sees_every_completed_frame() {
+        let mut machine = machine("JMP $8000");
+        let frames = Rc::new(Cell::new(0));
+        let seen = frames.clone();
+        machine.set_frame_callback(Box::new(move |framebuffer| {
+            assert_eq!(framebuffer.len(), 256 * 240 * 3);
+            seen.set(seen.get() + 1);
+        }));
+
+        for _ in 0..5 {
+            machine.step_frame();
+        }
+        assert_eq!(frames.get(), 5);
+
+        machine.clear_frame_callback();
+        machine.step_frame();
+        assert_eq!(frames.get(), 5);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_power_on_ram() {
+        let ram = |machine: &Machine| machine.cpu.bus.dump_range(0x0000, 0x07FF);
+
+        let mut first = machine("JMP $8000");
+        first.set_ram_seed(Some(1234));
+        let mut second = machine("JMP $8000");
+        second.set_ram_seed(Some(1234));
+        assert_eq!(ram(&first), ram(&second));
+        assert!(ram(&first).iter().any(|&byte| byte != 0));
+
+        // A power cycle with the seed still set comes back to the same RAM
+        first.cpu.mem_write(0x0010, ram(&first)[0x10] ^ 0xFF);
+        first.power_on();
+        assert_eq!(ram(&first), ram(&second));
+
+        second.set_ram_seed(Some(5678));
+        assert_ne!(ram(&first), ram(&second));
+        second.set_ram_seed(None);
+        assert!(ram(&second).iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn info_reports_a_hand_built_header() {
+        // 16KB of PRG, no CHR ROM, vertical mirroring with a battery, PAL
+        let image = test_image(&[]);
+        let mut raw = image[..16].to_vec();
+        raw[4] = 1;
+        raw[5] = 0;
+        raw[6] = 0b0000_0011;
+        raw[9] = 0b1;
+        raw.extend_from_slice(&image[16 + 0x4000..16 + 0x8000]);
+        let rom = Rom::from_bytes(&raw).unwrap();
+        let crc = rom.crc32();
+
+        let info = Machine::new(rom).info();
+        assert_eq!(info.mapper, 0);
+        assert_eq!(info.mapper_name, "NROM");
+        assert_eq!(info.prg_size, 0x4000);
+        assert!(info.chr_ram);
+        assert_eq!(info.chr_size, 0x2000);
+        assert_eq!(info.mirroring, Mirroring::VERTICAL);
+        assert!(info.battery);
+        assert_eq!(info.region, Region::PAL);
+        assert_eq!(info.crc32, crc);
+
+        let text = info.to_string();
+        assert!(text.contains("Mapper:    0 (NROM)"), "{}", text);
+        assert!(text.contains("PRG ROM:   16KB"), "{}", text);
+        assert!(text.contains("CHR RAM:   8KB"), "{}", text);
+        assert!(text.ends_with(&format!("CRC-32:    {:08X}", crc)), "{}", text);
+    }
+
+    #[test]
+    fn input_changes_show_up_from_the_next_vblank_latch() {
+        // Poll the A button into $10 over and over
+        let mut machine = machine("
+            LDA #$01
+            STA $4016
+            LDA #$00
+            STA $4016
+            LDA $4016
+            AND #$01
+            STA $10
+            JMP $8000
+        ");
+        machine.step_frame();
+        assert_eq!(machine.cpu.mem_read(0x0010), 0);
+
+        // Pressed partway through a frame, the game keeps reading the latched state
+        machine.set_button(BUTTON_A, true);
+        for _ in 0..200 {
+            machine.step_instruction();
+        }
+        assert_eq!(machine.cpu.mem_read(0x0010), 0);
+
+        // The next frame starts with the latch
+        machine.step_frame();
+        assert_eq!(machine.cpu.mem_read(0x0010), 1);
+
+        machine.set_button(BUTTON_A, false);
+        for _ in 0..200 {
+            machine.step_instruction();
+        }
+        assert_eq!(machine.cpu.mem_read(0x0010), 1);
+        machine.step_frame();
+        assert_eq!(machine.cpu.mem_read(0x0010), 0);
+    }
+}
@@ -1,66 +1,283 @@
 #![cfg_attr(debug_assertions, allow(dead_code))]
 #![cfg_attr(debug_assertions, allow(unused_imports))]
 
-pub mod apu;
-pub mod bus;
-pub mod rom;
-pub mod cpu;
-pub mod gamepad;
-pub mod opcodes;
-pub mod ppu;
-pub mod trace;
-
-use bus::Bus;
-use cpu::CPU;
-use cpu::Mem;
-use rand::Rng;
-use rom::Rom;
-use ppu::PPU;
+use nes::{apu, config, headless, pacing, rom, selftest, stats, test_harness, trace, WIDTH, HEIGHT};
+use nes::bus::Bus;
+use nes::cartdb::CartDb;
+use nes::config::Config;
+use nes::cpu::CPU;
+use nes::cpu::Mem;
+use nes::gamepad::*;
+use nes::machine::Machine;
+use nes::test_harness::TestStatus;
+use nes::profiler::InstructionProfiler;
+use nes::zapper::Zapper;
+use nes::rom::Rom;
+use nes::ppu::PPU;
 
+#[cfg(feature = "sdl")]
+use sdl2::audio::AudioSpecDesired;
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
+#[cfg(feature = "sdl")]
 use sdl2::EventPump;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
+#[cfg(feature = "sdl")]
+use sdl2::keyboard::Mod;
+#[cfg(feature = "sdl")]
+use sdl2::mouse::MouseButton;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::Color;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::PixelFormatEnum;
+#[cfg(feature = "sdl")]
+use sdl2::rect::Rect;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::time::Duration;
 use std::io::Write;
 use std::time::Instant;
 
-#[macro_use]
-extern crate lazy_static;
-
-const WIDTH: usize = 256;
-const HEIGHT: usize = 240;
+const OVERSCAN: usize = 8;
+// NES pixels are 8:7, slightly wider than tall, on a 4:3 TV
+const PIXEL_ASPECT: f32 = 8.0 / 7.0;
+// Instructions that can be stepped back through with --debug
+const DEBUG_HISTORY: usize = 10_000;
+// Range accepted by --speed
+const MIN_SPEED: f64 = 0.05;
+const MAX_SPEED: f64 = 4.0;
+// Frames a --test-rom run gets to report a result, about a minute of emulated time
+const TEST_ROM_FRAMES: usize = 3600;
+// Rows in each table of the --profile-instructions report
+const PROFILE_TOP: usize = 20;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    // Headless renderer check, exits nonzero on mismatch
+    if args.iter().any(|arg| arg == "--self-test") {
+        let passed = selftest::run();
+        ::std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // Print what the ROM header says and exit without running it
+    if args.iter().any(|arg| arg == "--info") {
+        match rom::read_rom_file(rom_path(&args)).and_then(|bytes| rom::Header::read(&bytes)) {
+            Ok(header) => println!("{}", header),
+            Err(e) => {
+                eprintln!("Could not read ROM: {}", e);
+                ::std::process::exit(1);
+            }
+        }
+        ::std::process::exit(0);
+    }
+
+    // Run frames without a window and print the frame hash, e.g. `--headless --frames 60 --png out.png game.nes`
+    if args.iter().any(|arg| arg == "--headless") {
+        let frames = parse_arg(&args, "--frames").unwrap_or(60);
+        let show_stats = args.iter().any(|arg| arg == "--stats");
+        let framebuffer = headless::run_frames(load_rom(&args), frames, show_stats);
+        println!("{}", headless::summary(frames, &framebuffer));
+        if let Some(path) = arg_value(&args, "--png") {
+            exit_on_error(headless::write_png(path, &framebuffer));
+        }
+        ::std::process::exit(0);
+    }
+
+    // Run a blargg test ROM until it reports through $6000, exits nonzero unless it passed
+    if args.iter().any(|arg| arg == "--test-rom") {
+        let frames = parse_arg(&args, "--frames").unwrap_or(TEST_ROM_FRAMES);
+        let passed = match test_harness::run(load_rom(&args), frames) {
+            TestStatus::Passed(message) => {
+                println!("{}", message);
+                println!("blargg test PASSED");
+                true
+            }
+            TestStatus::Failed(code, message) => {
+                println!("{}", message);
+                println!("blargg test FAILED with code {:02X}", code);
+                false
+            }
+            _ => {
+                println!("blargg test did not finish within {} frames", frames);
+                false
+            }
+        };
+        ::std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    run_window(&args);
+}
+
+// Without SDL only the headless modes above are available
+#[cfg(not(feature = "sdl"))]
+fn run_window(_args: &[String]) {
+    eprintln!("This build has no window, rebuild with the sdl feature or use --self-test, --info, --headless or --test-rom");
+    ::std::process::exit(1);
+}
+
+// Play a game in a window, with sound and keyboard, mouse and controller input
+#[cfg(feature = "sdl")]
+fn run_window(args: &[String]) {
+    // User settings
+    let config_path = arg_value(args, "--config").map(|path| path.as_str()).unwrap_or(config::DEFAULT_PATH);
+    let config = exit_on_error(Config::load(config_path));
+
+    // Hide the rows and columns a TV would have cut off, toggled with O while running
+    let mut crop_overscan = args.iter().any(|arg| arg == "--overscan");
+
+    // Square pixels by default, --integer rounds the scale down to whole pixels and --aspect stretches them to 8:7
+    let mut scale = config.scale;
+    if args.iter().any(|arg| arg == "--integer") {
+        scale = scale.floor().max(1.0);
+    }
+    let scale_x = if args.iter().any(|arg| arg == "--aspect") { scale * PIXEL_ASPECT } else { scale };
+    let scale = (scale_x, scale);
+
     // Init SDL2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let (window_width, window_height) = window_size(crop_overscan, scale);
     let window = video_subsystem
-        .window("NES Test", WIDTH as u32, HEIGHT as u32)
+        .window("NES Test", window_width, window_height)
         .position_centered()
         .build().unwrap();
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    // Pacing is done by the governor below, --vsync also holds each present for the display's refresh
+    let mut canvas = if args.iter().any(|arg| arg == "--vsync") {
+        window.into_canvas().present_vsync().build().unwrap()
+    } else {
+        window.into_canvas().build().unwrap()
+    };
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
+    canvas.set_scale(scale.0, scale.1).unwrap();
+
+    // Audio Queue, fed with the APU's samples once per frame
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio_spec = AudioSpecDesired {
+        freq: Some(apu::SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio_queue = audio_subsystem.open_queue::<f32, _>(None, &audio_spec).unwrap();
+    audio_queue.resume();
 
     // Render Texture
     let creator = canvas.texture_creator();
     let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, WIDTH as u32, HEIGHT as u32).unwrap();
 
     // Load Game
-    let bytes: Vec<u8> = std::fs::read("color_test.nes").unwrap();
-    let rom = rom::Rom::new(&bytes).unwrap();
+    let rom = load_rom(args);
+    let mut machine = Machine::new(rom);
+    println!("{}", machine.info());
 
-    let ppu = Rc::new(RefCell::new(PPU::new()));
-    let bus = bus::Bus::new(ppu.clone(), rom);
-    let mut cpu = cpu::CPU::new(bus);
+    // Seeded power-on RAM, the command line overrides the config
+    let seed = parse_arg(args, "--seed").or(config.seed);
+    if seed.is_some() {
+        machine.set_ram_seed(seed);
+    }
+
+    // Movie recording and playback
+    if let Some(path) = arg_value(args, "--record") {
+        exit_on_error(machine.record(path));
+    } else if let Some(path) = arg_value(args, "--play") {
+        exit_on_error(machine.play(path));
+    }
+
+    machine.cpu.bus.apu.set_master_volume(config.volume);
+
+    // Custom colors from a .pal file, a bad file keeps the default colors rather than stopping the game
+    if let Some(path) = arg_value(args, "--palette") {
+        let loaded = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| machine.ppu.borrow_mut().load_palette(&bytes));
+        if let Err(e) = loaded {
+            eprintln!("Could not load palette {}: {}, using the default palette", path, e);
+        }
+    }
+
+    // Instruction trace
+    if let Some(path) = arg_value(args, "--trace") {
+        let max_lines = parse_arg(args, "--trace-max-lines");
+        machine.set_trace(Some(exit_on_error(trace::TraceLog::create(path, max_lines))));
+    }
+
+    // Memory access profiling, printed on exit
+    if args.iter().any(|arg| arg == "--profile") {
+        machine.cpu.bus.enable_profiler();
+    }
+
+    // Hottest instruction addresses and opcodes, printed on exit or with F9
+    if args.iter().any(|arg| arg == "--profile-instructions") {
+        machine.cpu.instruction_profiler = Some(InstructionProfiler::new());
+    }
+
+    // Instruction history for stepping backwards while paused
+    if args.iter().any(|arg| arg == "--debug") {
+        machine.set_history(Some(DEBUG_HISTORY));
+    }
+
+    // Run unknown opcodes as NOPs instead of stopping, for debugging ROMs that go off the rails
+    if args.iter().any(|arg| arg == "--lenient-opcodes") {
+        machine.cpu.strict_opcodes = false;
+    }
+
+    // Cheat codes, --cheat can be given more than once
+    for code in args.windows(2).filter(|pair| pair[0] == "--cheat").map(|pair| &pair[1]) {
+        exit_on_error(machine.cpu.bus.add_cheat(code));
+    }
+
+    // Four Score adapter, players 3 and 4 are only driven through the bus's joypad3 and joypad4
+    if args.iter().any(|arg| arg == "--fourscore") {
+        machine.cpu.bus.enable_four_score();
+    }
+
+    // Zapper in port 2, aimed with the mouse and fired with the left button
+    if args.iter().any(|arg| arg == "--zapper") {
+        machine.cpu.bus.zapper = Some(Zapper::new());
+    }
+
+    // Autofire on A and B, in presses per second
+    if let Some(rate) = parse_arg(args, "--turbo") {
+        machine.cpu.bus.joypad1.set_turbo(BUTTON_A | BUTTON_B, rate);
+    }
+
+    // Controller Key Bindings
+    let keys = &config.keys;
+    let mut key_map = HashMap::new();
+    for (name, button) in [
+        (&keys.down, BUTTON_DOWN),
+        (&keys.up, BUTTON_UP),
+        (&keys.right, BUTTON_RIGHT),
+        (&keys.left, BUTTON_LEFT),
+        (&keys.select, BUTTON_SELECT),
+        (&keys.start, BUTTON_START),
+        (&keys.a, BUTTON_A),
+        (&keys.b, BUTTON_B),
+    ] {
+        match Keycode::from_name(name) {
+            Some(key) => key_map.insert(key, button),
+            None => {
+                eprintln!("Unknown key {} in {}", name, config_path);
+                ::std::process::exit(1);
+            }
+        };
+    }
 
     // Main Loop
-    cpu.reset();
-    let frame_time = Duration::from_millis(16); // 60 FPS
+    let console_frame_time = Duration::from_millis(config.region.frame_millis()); // 60 FPS NTSC, 50 FPS PAL
+
+    // Emulation is paced by CPU cycles against the wall clock, not by the display's refresh
+    // --speed scales the cycle rate, every frame still runs a full frame of cycles
+    let speed = parse_arg::<f64>(args, "--speed").unwrap_or(1.0).clamp(MIN_SPEED, MAX_SPEED);
+    let mut governor = pacing::Governor::new(config.region.cpu_clock() * speed, Instant::now(), machine.cpu.cycles as u64);
+    // Paused frames have no cycles to pace by, they are held for a frame's time instead
+    let frame_time = console_frame_time.div_f64(speed);
+    let mut paused = false;
+    let mut stats = args.iter().any(|arg| arg == "--stats").then(|| stats::FrameStats::new(console_frame_time));
+    // Busy-wait the last millisecond of each frame for steadier frame times, at the cost of some CPU
+    let spin_wait = args.iter().any(|arg| arg == "--spin-wait");
 
     loop {
         let start = Instant::now();
@@ -68,40 +285,237 @@ fn main() {
         // Handle events
         for event in event_pump.poll_iter() {
             match event {
-                Event::Quit { .. } => {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    machine.stop();
+                    if let Some(stats) = machine.cpu.bus.access_stats() {
+                        print!("{}", stats);
+                    }
+                    if let Some(profiler) = machine.cpu.instruction_profiler.as_ref() {
+                        print!("{}", profiler.report(PROFILE_TOP));
+                    }
                     ::std::process::exit(0);
                 }
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    ::std::process::exit(0);
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                    match machine.cpu.instruction_profiler.as_ref() {
+                        Some(profiler) => print!("{}", profiler.report(PROFILE_TOP)),
+                        None => println!("Instruction profiling is off, run with --profile-instructions to turn it on"),
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => {
+                    machine.reset();
+                }
+                Event::KeyDown { keycode: Some(Keycode::P), .. } => {
+                    machine.power_on();
+                }
+                Event::KeyDown { keycode: Some(Keycode::O), .. } => {
+                    crop_overscan = !crop_overscan;
+                    let (width, height) = window_size(crop_overscan, scale);
+                    canvas.window_mut().set_size(width, height).unwrap();
+                }
+                // Tab pauses, then period steps one instruction forward and comma one back,
+                // L runs to the next scanline and V to the start of VBlank
+                Event::KeyDown { keycode: Some(Keycode::Tab), .. } => {
+                    paused = !paused;
+                }
+                Event::KeyDown { keycode: Some(Keycode::Period), .. } if paused => {
+                    machine.step_instruction();
+                    println!("{}", trace::trace(&mut machine.cpu));
+                }
+                Event::KeyDown { keycode: Some(Keycode::L), .. } if paused => {
+                    machine.step_scanline();
+                    let status = machine.cpu.machine_status();
+                    println!("Scanline {}, dot {}", status.scanline, status.dot);
+                }
+                Event::KeyDown { keycode: Some(Keycode::V), .. } if paused => {
+                    machine.step_to_vblank();
+                    let status = machine.cpu.machine_status();
+                    println!("Scanline {}, dot {}", status.scanline, status.dot);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Comma), .. } if paused => {
+                    if machine.step_back() {
+                        println!("{}", trace::trace(&mut machine.cpu));
+                    } else {
+                        println!("No instruction history to step back through, run with --debug to record it");
+                    }
+                }
+                // 1-5 toggle muting pulse 1, pulse 2, triangle, noise and DMC, shift+1-5 solos the channel instead
+                Event::KeyDown { keycode: Some(key), keymod, .. } if channel_key(key).is_some() => {
+                    let channel = channel_key(key).unwrap();
+                    let apu = &mut machine.cpu.bus.apu;
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        apu.solo_channel(channel);
+                    } else {
+                        apu.set_channel_muted(channel, !apu.is_channel_muted(channel));
+                    }
+                }
+                Event::KeyDown { keycode: Some(key), .. } => {
+                    if let Some(button) = key_map.get(&key) {
+                        machine.set_button(*button, true);
+                    }
+                }
+                Event::MouseMotion { x, y, .. } => {
+                    if let Some(zapper) = machine.cpu.bus.zapper.as_mut() {
+                        let (width, height) = canvas.window().size();
+                        zapper.aim = screen_to_nes(x, y, width, height, crop_overscan);
+                    }
+                }
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } | Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                    if let Some(zapper) = machine.cpu.bus.zapper.as_mut() {
+                        zapper.trigger = matches!(event, Event::MouseButtonDown { .. });
+                    }
+                }
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(button) = key_map.get(&key) {
+                        machine.set_button(*button, false);
+                    }
                 }
                 _ => {}
             }
         }
 
-        // Set up frame count
-        let frame_deadline = Instant::now() + frame_time;
-        
-        // Step CPU n times, can be corrected with a timer later
-        while !ppu.borrow().is_new_frame && Instant::now() < frame_deadline {
-            cpu.step();
-            if cpu.bus.ppu.borrow().nmi_triggered {
-                cpu.trigger_nmi();
-                cpu.bus.ppu.borrow_mut().nmi_triggered = false;
-            }
+        if !paused {
+            machine.step_frame();
         }
 
         // On New Frame, Update SDL graphics
-        if ppu.borrow().is_new_frame {
-            texture.update(None, &ppu.borrow().framebuffer, WIDTH * 3).unwrap();
+        let ppu = machine.ppu.borrow();
+        if crop_overscan {
+            let width = WIDTH - OVERSCAN * 2;
+            let height = HEIGHT - OVERSCAN * 2;
+            let visible = Rect::new(0, 0, width as u32, height as u32);
+            let cropped = ppu.visible_framebuffer(OVERSCAN, OVERSCAN, OVERSCAN, OVERSCAN);
+            texture.update(visible, &cropped, width * 3).unwrap();
+            canvas.copy(&texture, visible, None).unwrap();
+        } else {
+            texture.update(None, &ppu.framebuffer, WIDTH * 3).unwrap();
             canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-            ppu.borrow_mut().is_new_frame = false;
         }
+        canvas.present();
+        drop(ppu);
 
-        // Sleep to maintain frame rate
+        let samples: Vec<f32> = machine.cpu.bus.apu.drain_samples().into_iter().map(|(_, sample)| sample).collect();
+        // Samples are produced at the console's rate, so audio is muted rather than pitched when the speed changes
+        if speed == 1.0 {
+            audio_queue.queue_audio(&samples).unwrap();
+        }
+
+        // Wait out the rest of the frame to maintain frame rate
         let elapsed_time = start.elapsed();
-        if elapsed_time < frame_time {
-            ::std::thread::sleep(frame_time - elapsed_time);
+        if let Some(summary) = stats.as_mut().and_then(|stats| stats.record_frame(elapsed_time)) {
+            canvas.window_mut().set_title(&format!("NES Test - {}", summary)).unwrap();
+        }
+        if paused {
+            pacing::wait_until(start + frame_time, spin_wait);
+        } else {
+            governor.wait(machine.cpu.cycles as u64, spin_wait);
+        }
+    }
+}
+
+// Map a window position to an NES pixel, for aiming the Zapper
+#[cfg(feature = "sdl")]
+fn screen_to_nes(x: i32, y: i32, window_width: u32, window_height: u32, crop_overscan: bool) -> Option<(usize, usize)> {
+    let (width, height, offset) = if crop_overscan {
+        (WIDTH - OVERSCAN * 2, HEIGHT - OVERSCAN * 2, OVERSCAN)
+    } else {
+        (WIDTH, HEIGHT, 0)
+    };
+    if x < 0 || y < 0 || x as u32 >= window_width || y as u32 >= window_height {
+        return None;
+    }
+
+    let nes_x = x as usize * width / window_width as usize + offset;
+    let nes_y = y as usize * height / window_height as usize + offset;
+    Some((nes_x, nes_y))
+}
+
+// Window size for the presented picture, 240 lines or 224 with overscan cropped
+// Scale is (horizontal, vertical)
+#[cfg(feature = "sdl")]
+fn window_size(crop_overscan: bool, scale: (f32, f32)) -> (u32, u32) {
+    let (width, height) = if crop_overscan {
+        (WIDTH - OVERSCAN * 2, HEIGHT - OVERSCAN * 2)
+    } else {
+        (WIDTH, HEIGHT)
+    };
+    ((width as f32 * scale.0).round() as u32, (height as f32 * scale.1).round() as u32)
+}
+
+// APU channel controlled by a number key
+#[cfg(feature = "sdl")]
+fn channel_key(key: Keycode) -> Option<apu::Channel> {
+    match key {
+        Keycode::Num1 => Some(apu::Channel::Pulse1),
+        Keycode::Num2 => Some(apu::Channel::Pulse2),
+        Keycode::Num3 => Some(apu::Channel::Triangle),
+        Keycode::Num4 => Some(apu::Channel::Noise),
+        Keycode::Num5 => Some(apu::Channel::DMC),
+        _ => None,
+    }
+}
+
+// The ROM is the last argument when it names a .nes or .zip file
+fn rom_path(args: &[String]) -> &str {
+    match args.last() {
+        Some(path) if args.len() > 1 && [".nes", ".zip"].iter().any(|ext| path.to_ascii_lowercase().ends_with(ext)) => path,
+        _ => "color_test.nes",
+    }
+}
+
+// Load the ROM named on the command line, or exit with a message saying why it cannot run
+// With --db a known bad header is corrected first, `--db file.toml` adds entries to the built-in database
+fn load_rom(args: &[String]) -> Rom {
+    let db = args.iter().any(|arg| arg == "--db").then(|| {
+        let mut db = CartDb::builtin();
+        if let Some(file) = arg_value(args, "--db").filter(|file| file.ends_with(".toml")) {
+            if let Err(e) = db.load(file) {
+                eprintln!("{}", e);
+                ::std::process::exit(1);
+            }
+        }
+        db
+    });
+
+    let loaded = rom::read_rom_file(rom_path(args)).and_then(|bytes| rom::Rom::parse(&bytes)).and_then(|mut rom| {
+        if let Some(correction) = db.as_ref().and_then(|db| db.correct(&mut rom)) {
+            println!("{}", correction);
+        }
+        rom.check_mapper().map(|_| rom)
+    });
+    match loaded {
+        Ok(rom) => rom,
+        Err(rom::RomError::UnsupportedMapper(mapper)) => {
+            let supported: Vec<String> = rom::SUPPORTED_MAPPERS.iter().map(|m| m.to_string()).collect();
+            eprintln!("This game uses mapper {}, which is not supported yet.", mapper);
+            eprintln!("Supported mappers: {}", supported.join(", "));
+            ::std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not load ROM: {}", e);
+            ::std::process::exit(1);
         }
     }
-}
\ No newline at end of file
+}
+
+// Value following a command line flag, e.g. `--record movie.tas`
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1))
+}
+
+// Value following a command line flag parsed as a number, exits with a message when it is not one
+fn parse_arg<T: std::str::FromStr>(args: &[String], flag: &str) -> Option<T> {
+    arg_value(args, flag).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value {} for {}", value, flag);
+            ::std::process::exit(1);
+        })
+    })
+}
+
+// The value of a fallible command line option, or exit with its error message rather than a panic
+fn exit_on_error<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    })
+}
@@ -0,0 +1,59 @@
+// Cartridge hardware that sits between the CPU and the PRG ROM
+// Writes to $8000-$FFFF never reach the ROM chip, the mapper decodes them as its own registers,
+// so bank switching boards (UxROM, MMC1, ...) each get an implementation of this trait.
+
+use crate::apu::ExpansionAudio;
+use crate::rom::Rom;
+
+pub trait Mapper {
+    // A CPU read from $8000-$FFFF
+    fn cpu_read(&self, prg: &[u8], addr: u16) -> u8;
+    // A CPU write to $8000-$FFFF
+    fn cpu_write(&mut self, addr: u16, data: u8);
+    // Sound channels on the cartridge, mixed in by the APU, most boards have none
+    fn expansion_audio(&self) -> Option<Box<dyn ExpansionAudio>> {
+        None
+    }
+}
+
+// Mapper 0, no registers, 16KB carts are mirrored into both halves of $8000-$FFFF
+pub struct Nrom;
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, prg: &[u8], addr: u16) -> u8 {
+        let mut addr = addr - 0x8000;
+        if prg.len() == 0x4000 {
+            addr %= 0x4000;
+        }
+        prg[addr as usize]
+    }
+
+    // There is nothing to write to, games that write to ROM anyway are ignored
+    fn cpu_write(&mut self, _addr: u16, _data: u8) {}
+}
+
+// Board name for a mapper number, for display
+pub fn name(mapper: u8) -> &'static str {
+    match mapper {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        66 => "GxROM",
+        _ => "Unknown",
+    }
+}
+
+// The mapper for a cartridge
+pub fn for_rom(rom: &Rom) -> Box<dyn Mapper> {
+    match rom.mapper {
+        0 => Box::new(Nrom),
+        // Rom::check_mapper rejects these before a game starts, NROM is the closest guess for anything that gets here
+        _ => Box::new(Nrom),
+    }
+}
@@ -1,7 +1,6 @@
 // Collection of all OPCodes for the NES
 
 use crate::cpu::AddressingMode;
-use std::collections::HashMap;
 
 pub struct OPCode {
     pub code: u8,
@@ -308,19 +307,7 @@ lazy_static! {
         OPCode::new(0xe3, "*ISB", 2,8, AddressingMode::IndirectX),
         OPCode::new(0xf3, "*ISB", 2,8, AddressingMode::IndirectY),
 
-        OPCode::new(0x02, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x12, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x22, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x32, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x42, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x52, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x62, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x72, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0x92, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0xb2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0xd2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OPCode::new(0xf2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-
+        // The KIL/JAM bytes ($02, $12, ... $F2) are left out, they halt a real CPU and are no instruction at all
         OPCode::new(0x1a, "*NOP", 1,2, AddressingMode::NoneAddressing),
         OPCode::new(0x3a, "*NOP", 1,2, AddressingMode::NoneAddressing),
         OPCode::new(0x5a, "*NOP", 1,2, AddressingMode::NoneAddressing),
@@ -331,7 +318,7 @@ lazy_static! {
 
         OPCode::new(0xab, "*LXA", 2, 3, AddressingMode::Immediate), 
         OPCode::new(0x8b, "*XAA", 2, 3, AddressingMode::Immediate), 
-        OPCode::new(0xbb, "*LAS", 3, 2, AddressingMode::AbsoluteY), 
+        OPCode::new(0xbb, "*LAS", 3, 4, AddressingMode::AbsoluteY), 
         OPCode::new(0x9b, "*TAS", 3, 2, AddressingMode::AbsoluteY), 
         OPCode::new(0x93, "*AHX", 2,  8, AddressingMode::IndirectY), 
         OPCode::new(0x9f, "*AHX", 3,  4, AddressingMode::AbsoluteY), 
@@ -352,11 +339,42 @@ lazy_static! {
 
     ];
 
-    pub static ref OPCodes_MAP: HashMap<u8, &'static OPCode> = {
-        let mut map = HashMap::new();
+    // Indexed directly by the opcode byte, step() looks one up for every instruction
+    // A code listed twice keeps its last entry
+    pub static ref OPCODE_TABLE: [Option<&'static OPCode>; 256] = {
+        let mut table = [None; 256];
         for cpuop in &*CPU_OPCodeS {
-            map.insert(cpuop.code, cpuop);
+            table[cpuop.code as usize] = Some(cpuop);
         }
-        return map;
+        table
     };
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn table_matches_the_opcode_map() {
+        // The lookup step() used to do, later entries replace earlier ones
+        let mut map: HashMap<u8, &'static OPCode> = HashMap::new();
+        for cpuop in &*CPU_OPCodeS {
+            map.insert(cpuop.code, cpuop);
+        }
+
+        for code in 0..=255u8 {
+            match (map.get(&code), OPCODE_TABLE[code as usize]) {
+                (Some(expected), Some(actual)) => {
+                    assert_eq!(actual.code, code);
+                    assert_eq!(actual.name, expected.name, "{:02X}", code);
+                    assert_eq!(actual.len, expected.len, "{:02X}", code);
+                    assert_eq!(actual.cycles, expected.cycles, "{:02X}", code);
+                    assert_eq!(actual.mode, expected.mode, "{:02X}", code);
+                }
+                (None, None) => {}
+                _ => panic!("{:02X} resolves differently", code),
+            }
+        }
+    }
+}
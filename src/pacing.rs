@@ -0,0 +1,126 @@
+// Waiting out the rest of a frame so the game runs at the console's frame rate
+// Sleeping alone can wake up late, the OS timer is only good to a millisecond or worse on some platforms.
+// With --spin-wait the wait sleeps until the last millisecond and then busy-waits on the clock for it,
+// trading a little CPU time each frame for frames that land much closer to their deadline.
+//
+// The Governor decides what the deadline is. It paces emulation by emulated CPU cycles against the wall clock,
+// so the game runs at the console's speed whatever the display's refresh rate.
+
+use std::time::{Duration, Instant};
+
+// How much of the wait is spun rather than slept
+pub const SPIN_TAIL: Duration = Duration::from_millis(1);
+
+// Block until the deadline, returns immediately when it has already passed
+pub fn wait_until(deadline: Instant, spin: bool) {
+    let now = Instant::now();
+    if now >= deadline {
+        return;
+    }
+
+    if !spin {
+        std::thread::sleep(deadline - now);
+        return;
+    }
+
+    let remaining = deadline - now;
+    if remaining > SPIN_TAIL {
+        std::thread::sleep(remaining - SPIN_TAIL);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+// Running this far behind, e.g. after a pause or a stall, starts pacing afresh instead of racing to catch up
+pub const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub struct Governor {
+    // Emulated CPU cycles per second of wall-clock time
+    rate: f64,
+    // The wall-clock time and cycle count pacing is measured from
+    anchor: Instant,
+    anchor_cycles: u64,
+}
+
+impl Governor {
+    pub fn new(rate: f64, now: Instant, cycles: u64) -> Self {
+        Governor { rate, anchor: now, anchor_cycles: cycles }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    // Change the rate for fast-forward or slow motion, cycles already run keep the pace they ran at
+    pub fn set_rate(&mut self, rate: f64, now: Instant, cycles: u64) {
+        self.rate = rate;
+        self.anchor = now;
+        self.anchor_cycles = cycles;
+    }
+
+    // The wall-clock time the CPU should reach this cycle count
+    pub fn deadline(&self, cycles: u64) -> Instant {
+        let ahead = cycles.saturating_sub(self.anchor_cycles) as f64 / self.rate;
+        self.anchor + Duration::from_secs_f64(ahead)
+    }
+
+    // Wait until the wall clock catches up with the emulated cycles
+    // Re-anchors instead when the cycle count went backwards (a power cycle) or pacing fell more than MAX_LAG behind
+    pub fn wait(&mut self, cycles: u64, spin: bool) {
+        let now = Instant::now();
+        if cycles < self.anchor_cycles || now > self.deadline(cycles) + MAX_LAG {
+            self.anchor = now;
+            self.anchor_cycles = cycles;
+            return;
+        }
+        wait_until(self.deadline(cycles), spin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NTSC_RATE: f64 = 1_789_773.0;
+
+    #[test]
+    fn deadline_follows_cycles_at_the_rate() {
+        let start = Instant::now();
+        let governor = Governor::new(NTSC_RATE, start, 1000);
+
+        assert_eq!(governor.deadline(1000), start);
+        assert_eq!(governor.deadline(1000 + NTSC_RATE as u64), start + Duration::from_secs(1));
+
+        // One NTSC frame of cycles is due about 16.64ms in
+        let frame = governor.deadline(1000 + 29_781) - start;
+        assert!(frame > Duration::from_micros(16_630) && frame < Duration::from_micros(16_650), "{:?}", frame);
+
+        // Cycles from before the anchor are already due
+        assert_eq!(governor.deadline(0), start);
+    }
+
+    #[test]
+    fn set_rate_paces_from_the_new_anchor() {
+        let start = Instant::now();
+        let mut governor = Governor::new(NTSC_RATE, start, 0);
+
+        let later = start + Duration::from_secs(2);
+        governor.set_rate(NTSC_RATE / 2.0, later, 5000);
+        assert_eq!(governor.rate(), NTSC_RATE / 2.0);
+        assert_eq!(governor.deadline(5000), later);
+        assert_eq!(governor.deadline(5000 + NTSC_RATE as u64), later + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn wait_reanchors_when_cycles_go_backwards() {
+        let start = Instant::now();
+        let mut governor = Governor::new(NTSC_RATE, start, 1_000_000);
+
+        // A power cycle starts the count over, waiting must not block on the old anchor
+        governor.wait(10, false);
+        assert!(governor.deadline(10) >= start);
+        assert!(Instant::now() - start < Duration::from_millis(100));
+        assert_eq!(governor.deadline(10 + NTSC_RATE as u64) - governor.deadline(10), Duration::from_secs(1));
+    }
+}
@@ -1,8 +1,50 @@
 // This module's primary goal is to draw the current state of a game on a TV Screen.
 
-use sdl2::pixels::Color;
+use crate::{cpu, rom::Mirroring, WIDTH, HEIGHT};
 
-use crate::{cpu, WIDTH, HEIGHT};
+// The 2C02's 64 colors as RGB, indexed by the values stored in palette RAM
+pub const NES_PALETTE: [(u8, u8, u8); 64] = [
+    (0x54, 0x54, 0x54), (0x00, 0x1E, 0x74), (0x08, 0x10, 0x90), (0x30, 0x00, 0x88), (0x44, 0x00, 0x64), (0x5C, 0x00, 0x30), (0x54, 0x04, 0x00), (0x3C, 0x18, 0x00),
+    (0x20, 0x2A, 0x00), (0x08, 0x3A, 0x00), (0x00, 0x40, 0x00), (0x00, 0x3C, 0x00), (0x00, 0x32, 0x3C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0x98, 0x96, 0x98), (0x08, 0x4C, 0xC4), (0x30, 0x32, 0xEC), (0x5C, 0x1E, 0xE4), (0x88, 0x14, 0xB0), (0xA0, 0x14, 0x64), (0x98, 0x22, 0x20), (0x78, 0x3C, 0x00),
+    (0x54, 0x5A, 0x00), (0x28, 0x72, 0x00), (0x08, 0x7C, 0x00), (0x00, 0x76, 0x28), (0x00, 0x66, 0x78), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xEC, 0xEE, 0xEC), (0x4C, 0x9A, 0xEC), (0x78, 0x7C, 0xEC), (0xB0, 0x62, 0xEC), (0xE4, 0x54, 0xEC), (0xEC, 0x58, 0xB4), (0xEC, 0x6A, 0x64), (0xD4, 0x88, 0x20),
+    (0xA0, 0xAA, 0x00), (0x74, 0xC4, 0x00), (0x4C, 0xD0, 0x20), (0x38, 0xCC, 0x6C), (0x38, 0xB4, 0xCC), (0x3C, 0x3C, 0x3C), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+    (0xEC, 0xEE, 0xEC), (0xA8, 0xCC, 0xEC), (0xBC, 0xBC, 0xEC), (0xD4, 0xB2, 0xEC), (0xEC, 0xAE, 0xEC), (0xEC, 0xAE, 0xD4), (0xEC, 0xB4, 0xB0), (0xE4, 0xC4, 0x90),
+    (0xCC, 0xD2, 0x78), (0xB4, 0xDE, 0x78), (0xA8, 0xE2, 0x90), (0x98, 0xE2, 0xB4), (0xA0, 0xD6, 0xE4), (0xA0, 0xA2, 0xA0), (0x00, 0x00, 0x00), (0x00, 0x00, 0x00),
+];
+
+// RGB of one of the 2C02's colors, only the low 6 bits of the index count like in palette RAM
+pub fn palette_rgb(index: u8) -> [u8; 3] {
+    master_palette_rgb(&NES_PALETTE, index)
+}
+
+// The same lookup in any 64 color master palette, the PPU's may have been replaced by a .pal file
+fn master_palette_rgb(master_palette: &[(u8, u8, u8); 64], index: u8) -> [u8; 3] {
+    let (r, g, b) = master_palette[(index & 0x3F) as usize];
+    [r, g, b]
+}
+
+// One of the 64 OAM entries, decoded from its 4 bytes
+// attr holds the palette (bits 0-1), priority behind the background (bit 5), and horizontal and vertical flip (bits 6-7)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    pub y: u8,
+    pub tile: u8,
+    pub attr: u8,
+    pub x: u8,
+}
+
+// A sprite picked for the line being drawn, with its row of pattern already fetched and flipped
+#[derive(Debug, Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+    // OAM entry 0, the one that can set the sprite 0 hit flag
+    zero: bool,
+}
 
 pub struct PPU {
     pub cycles: usize,
@@ -11,8 +53,16 @@ pub struct PPU {
     pub is_new_frame: bool,
 
     // Memory
-    pub vram: [u8; 0x800],
+    // Pattern tables from the cartridge, 8KB of blank CHR RAM when the cartridge has no CHR ROM
+    pub chr_rom: Vec<u8>,
+    // The cartridge has CHR RAM, so $2007 writes to $0000-$1FFF land in chr_rom
+    pub chr_ram: bool,
+    // 2KB of nametable RAM, or 4KB when the cartridge supplies the extra RAM for four-screen mirroring
+    pub vram: Vec<u8>,
+    pub mirroring: Mirroring,
     pub palette_table: [u8; 32],
+    // RGB for each of the 64 colors, the built-in palette unless a .pal file replaced it
+    pub master_palette: [(u8, u8, u8); 64],
     pub oam_data: [u8; 256],
     pub framebuffer: [u8; WIDTH * HEIGHT * 3],
 
@@ -21,10 +71,12 @@ pub struct PPU {
     pub mask: u8,
     pub status: u8,
     pub oam_addr: u8,
-    pub scroll: (u8, u8),
-    pub addr: u16,
-    pub addr_latch: bool,
     pub nmi_triggered: bool,
+    // Set by a $2002 read one dot before VBlank, the flag and NMI are skipped for that frame
+    pub suppress_vblank: bool,
+    pub open_bus: u8,
+    // $2007 reads below the palettes return this, then refill it from the address just read
+    pub read_buffer: u8,
 
     // Additional Registers for Scrolling
     pub  vram_addr: u16,
@@ -37,28 +89,44 @@ pub struct PPU {
     pub next_tile_attr: u8,
     pub next_tile_lsb: u8,
     pub next_tile_msb: u8,
-    
+
+    // Background Shift Registers, the pixel being drawn is at bit 15
+    pub bg_pattern_lo: u16,
+    pub bg_pattern_hi: u16,
+    pub bg_attr_lo: u16,
+    pub bg_attr_hi: u16,
+
+    // Up to 8 sprites on the current line, frontmost first
+    line_sprites: Vec<LineSprite>,
 }
 
 impl PPU {
-    pub fn new() -> Self {
+    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let chr_ram = chr_rom.is_empty();
+        let chr_rom = if chr_ram { vec![0; 0x2000] } else { chr_rom };
+        let vram_size = if mirroring == Mirroring::FOURSCREEN { 0x1000 } else { 0x800 };
+
         PPU {
             cycles: 0,
             scanline: 0,
             frame: 0,
             is_new_frame: false,
-            vram: [0; 0x800],
+            chr_rom,
+            chr_ram,
+            vram: vec![0; vram_size],
+            mirroring,
             palette_table: [0; 32],
+            master_palette: NES_PALETTE,
             oam_data: [0; 256],
             framebuffer: [0; (WIDTH * HEIGHT * 3)],
             control: 0,
             mask: 0,
             status: 0,
             oam_addr: 0,
-            scroll: (0, 0),
-            addr: 0,
-            addr_latch: false,
             nmi_triggered: false,
+            suppress_vblank: false,
+            open_bus: 0,
+            read_buffer: 0,
             vram_addr: 0,
             temp_addr: 0,
             fine_x: 0,
@@ -67,76 +135,83 @@ impl PPU {
             next_tile_attr: 0,
             next_tile_lsb: 0,
             next_tile_msb: 0,
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attr_lo: 0,
+            bg_attr_hi: 0,
+            line_sprites: Vec::with_capacity(8),
         }
     }
 
-    pub fn step(&mut self) {
+    // Warm reset from the console's reset button
+    // PPUCTRL, PPUMASK, the scroll and write toggle, and the odd frame parity are cleared
+    // VRAM, palette RAM, OAM, and PPUADDR survive, only a power cycle (a new PPU) loses them
+    pub fn reset(&mut self) {
+        self.control = 0;
+        self.mask = 0;
+        self.write_toggle = false;
+        self.fine_x = 0;
+        self.temp_addr = 0;
+        self.frame = 0;
+        self.nmi_triggered = false;
+        self.read_buffer = 0;
+    }
+
+    // Power cycle, everything the PPU holds is lost except the master palette, which is the front-end's choice
+    // CHR ROM comes back from the cartridge, CHR RAM starts cleared
+    pub fn power_on(&mut self) {
+        let chr_rom = if self.chr_ram { Vec::new() } else { std::mem::take(&mut self.chr_rom) };
+        *self = PPU { master_palette: self.master_palette, ..PPU::new(chr_rom, self.mirroring) };
+    }
+
+    // Advance the PPU by a single dot
+    pub fn step_dot(&mut self) {
         // Increment Cycles
         self.cycles += 1;
 
-        // Clear Framebuffer at the start of each frame
-        if self.scanline == -1 && self.cycles == 1 {
-            self.framebuffer.fill(0);
-            self.is_new_frame = true;
-        }
-
-        // Every 8 PPU cycles, fetch data for background rendering
-        if self.scanline >= 0 && self.scanline < 240 && (self.cycles >= 1 && self.cycles <= 256) {
-            let cycle_in_tile = (self.cycles - 1) % 8;
+        // Background fetches run on the visible lines and the pre-render line, which prefetches the first two tiles
+        let fetch_line = self.scanline >= -1 && self.scanline < 240;
+        if self.is_rendering_enabled() && fetch_line && ((self.cycles >= 2 && self.cycles <= 257) || (self.cycles >= 321 && self.cycles <= 337)) {
+            self.shift_background();
 
-            match cycle_in_tile {
-                1 => { // Fetch tile ID
+            match (self.cycles - 1) % 8 {
+                0 => { // Previous tile into the shifters, fetch tile ID
+                    self.load_background_shifters();
                     let nametable_addr = 0x2000 | (self.vram_addr & 0x0FFF);
-                    self.next_tile_id = self.vram[nametable_addr as usize & 0x7FF];
+                    self.next_tile_id = self.vram[self.mirror_vram_addr(nametable_addr)];
                 }
-                3 => { // Fetch attribute byte
+                2 => { // Fetch attribute byte and keep the 2 bits for this tile's quadrant
                     let attr_addr = 0x23C0 | (self.vram_addr & 0x0C00) | ((self.vram_addr >> 4) & 0x38) | ((self.vram_addr >> 2) & 0x07);
-                    self.next_tile_attr = self.vram[attr_addr as usize & 0x7FF];
+                    let attr = self.vram[self.mirror_vram_addr(attr_addr)];
+                    let shift = ((self.vram_addr >> 4) & 0x04) | (self.vram_addr & 0x02);
+                    self.next_tile_attr = (attr >> shift) & 0x03;
                 }
-                5 => { // Fetch low byte of pattern
+                4 => { // Fetch low byte of pattern
                     let fine_y = (self.vram_addr >> 12) & 0x7;
-                    let pattern_table_addr = ((self.control as u16 & 0x10) << 8) + (self.next_tile_id as u16 * 16) + fine_y;
-                    self.next_tile_lsb = self.vram[pattern_table_addr as usize & 0x7FF];
+                    let pattern_table_addr = self.background_pattern_base() + (self.next_tile_id as u16 * 16) + fine_y;
+                    self.next_tile_lsb = self.read_pattern(pattern_table_addr);
                 }
-                7 => { // Fetch high byte of pattern
+                6 => { // Fetch high byte of pattern
                     let fine_y = (self.vram_addr >> 12) & 0x7;
-                    let pattern_table_addr = ((self.control as u16 & 0x10) << 8) + (self.next_tile_id as u16 * 16) + fine_y + 8;
-                    self.next_tile_msb = self.vram[pattern_table_addr as usize & 0x7FF];
-                }
-                0 => { // Tile data shift: render one pixel column for current tile
-                    let fine_x = self.fine_x as usize;
-
-                    for bit in 0..8 {
-                        let bit_index = 7 - bit;
-                        let plane0 = (self.next_tile_lsb >> bit_index) & 1;
-                        let plane1 = (self.next_tile_msb >> bit_index) & 1;
-                        let color_idx = (plane1 << 1) | plane0;
-
-                        let cycle_base = if self.cycles >= 8 { self.cycles - 8 } else { 0 };
-                        let x = (cycle_base + bit) as usize;
-                        let y = self.scanline as usize;
-
-                        if x < WIDTH && y < HEIGHT {
-                            let offset = (y * WIDTH + x) * 3;
-
-                            // Force any non-zero color_idx to bright color
-                            if color_idx != 0 {
-                                self.framebuffer[offset] = 0xFF;          // R
-                                self.framebuffer[offset + 1] = 0x00;      // G
-                                self.framebuffer[offset + 2] = 0x00;      // B
-                            }
-                        }
-                    }
-
-                    self.increment_x();
+                    let pattern_table_addr = self.background_pattern_base() + (self.next_tile_id as u16 * 16) + fine_y + 8;
+                    self.next_tile_msb = self.read_pattern(pattern_table_addr);
                 }
+                7 => self.increment_x(),
                 _ => {}
             }
+        }
 
-            // Increment X position
-            if self.cycles == 256 {
-                self.vram_addr = (self.vram_addr & 0xFBE0) | ((self.vram_addr + 1) & 0x041F);
+        // One pixel per dot on the visible lines
+        if self.scanline >= 0 && self.scanline < 240 && self.cycles >= 1 && self.cycles <= 256 {
+            if self.cycles == 1 {
+                self.evaluate_sprites();
             }
+            self.render_pixel();
+        }
+
+        // Odd frames skip the last dot of the pre-render line while rendering
+        if self.is_rendering_enabled() && self.scanline == -1 && self.cycles == 340 && self.frame % 2 == 1 {
+            self.cycles += 1;
         }
 
         // Finish scanline
@@ -147,55 +222,294 @@ impl PPU {
             if self.scanline > 261 {
                 self.scanline = -1;
                 self.frame += 1;
+                // Each frame is drawn over a blank picture
+                self.framebuffer.fill(0);
             }
         }
 
         // Increments Y at the end of each scanline
-        if (self.scanline >= 0 && self.scanline < 240) || self.scanline == -1 {
-            if self.cycles == 256 {
-                self.increment_y();
-            }
+        // Scroll updates only happen while rendering, so vram_addr survives with rendering off
+        if self.is_rendering_enabled() && ((self.scanline >= 0 && self.scanline < 240) || self.scanline == -1) && self.cycles == 256 {
+            self.increment_y();
         }
 
         // During pre-render or visible lines, reload horizontal bits at certain cycles
-        if self.scanline == -1 || (self.scanline >= 0 && self.scanline < 240) {
-            if self.cycles == 257 {
-                self.transfer_horizontal();
-            }
+        if self.is_rendering_enabled() && (self.scanline == -1 || (self.scanline >= 0 && self.scanline < 240)) && self.cycles == 257 {
+            self.transfer_horizontal();
+        }
+
+        // Pre-render line reloads the vertical bits so the next frame starts at the top scroll position
+        if self.is_rendering_enabled() && self.scanline == -1 && self.cycles >= 280 && self.cycles <= 304 {
+            self.transfer_vertical();
         }
 
-        // VBlank begin
+        // VBlank begin, the picture for this frame is complete
+        // The NMI is only latched here, the machine takes it before the next instruction
         if self.scanline == 241 && self.cycles == 1 {
-            self.status |= 0x80;
-            if self.control & 0x80 != 0 {
-                self.nmi_triggered = true;
+            self.is_new_frame = true;
+            if !self.suppress_vblank {
+                self.status |= 0x80;
+                if self.control & 0x80 != 0 {
+                    self.nmi_triggered = true;
+                }
             }
+            self.suppress_vblank = false;
         }
 
-        // VBlank end
+        // VBlank end, the sprite 0 hit and sprite overflow flags clear on the same dot
         if self.scanline == -1 && self.cycles == 1 {
-            self.status &= 0x7F;
+            self.status &= 0x1F;
         }
     }
 
+    // Shift the background registers along by one pixel
+    fn shift_background(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attr_lo <<= 1;
+        self.bg_attr_hi <<= 1;
+    }
+
+    // Move the tile fetched over the last 8 dots into the low byte of the shifters
+    // The attribute latch is spread across all 8 pixels of the tile
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.next_tile_lsb as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.next_tile_msb as u16;
+        self.bg_attr_lo = (self.bg_attr_lo & 0xFF00) | if self.next_tile_attr & 0x01 != 0 { 0xFF } else { 0x00 };
+        self.bg_attr_hi = (self.bg_attr_hi & 0xFF00) | if self.next_tile_attr & 0x02 != 0 { 0xFF } else { 0x00 };
+    }
+
+    // Find the first 8 sprites in OAM that cover the current line and fetch their pattern rows
+    // A sprite's Y is one less than the first line it appears on
+    fn evaluate_sprites(&mut self) {
+        self.line_sprites.clear();
+        if !self.is_rendering_enabled() {
+            return;
+        }
+
+        let height = self.sprite_height() as isize;
+        for index in 0..64 {
+            let sprite = self.sprite(index);
+            let row = self.scanline - (sprite.y as isize + 1);
+            if row < 0 || row >= height {
+                continue;
+            }
+            if self.line_sprites.len() == 8 {
+                break;
+            }
+
+            let addr = self.sprite_pattern_addr(sprite.tile, row as u8, sprite.attr & 0x80 != 0);
+            let (mut lo, mut hi) = (self.read_pattern(addr), self.read_pattern(addr + 8));
+            if sprite.attr & 0x40 != 0 {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+            self.line_sprites.push(LineSprite { x: sprite.x, attr: sprite.attr, lo, hi, zero: index == 0 });
+        }
+    }
+
+    // Background pixel value and palette for the current dot, from the top of the shifters offset by fine X
+    fn background_pixel(&self, x: usize) -> (u8, u8) {
+        // PPUMASK bit 3 shows the background, bit 1 includes the leftmost 8 pixels
+        if self.mask & 0x08 == 0 || (x < 8 && self.mask & 0x02 == 0) {
+            return (0, 0);
+        }
+
+        let bit = 0x8000 >> self.fine_x;
+        let lo = (self.bg_pattern_lo & bit != 0) as u8;
+        let hi = (self.bg_pattern_hi & bit != 0) as u8;
+        let pixel = (hi << 1) | lo;
+
+        let lo = (self.bg_attr_lo & bit != 0) as u8;
+        let hi = (self.bg_attr_hi & bit != 0) as u8;
+        (pixel, (hi << 1) | lo)
+    }
+
+    // The frontmost opaque sprite pixel at this x, lower OAM indexes are in front
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, LineSprite)> {
+        // PPUMASK bit 4 shows sprites, bit 2 includes the leftmost 8 pixels
+        if self.mask & 0x10 == 0 || (x < 8 && self.mask & 0x04 == 0) {
+            return None;
+        }
+
+        self.line_sprites.iter().find_map(|sprite| {
+            let column = x.checked_sub(sprite.x as usize).filter(|&column| column < 8)?;
+            let bit = 7 - column;
+            let pixel = (((sprite.hi >> bit) & 1) << 1) | ((sprite.lo >> bit) & 1);
+            (pixel != 0).then_some((pixel, *sprite))
+        })
+    }
+
+    // Draw the pixel for the current dot, choosing between the background and the frontmost sprite
+    fn render_pixel(&mut self) {
+        let x = self.cycles - 1;
+        let y = self.scanline as usize;
+        if !self.is_rendering_enabled() {
+            return;
+        }
+
+        let (bg_pixel, bg_palette) = self.background_pixel(x);
+        let (pixel, palette) = match self.sprite_pixel(x) {
+            Some((pixel, sprite)) => {
+                // Sprite 0 hits wherever it overlaps opaque background, whichever of the two is shown
+                // The hit never happens on the last column
+                if sprite.zero && bg_pixel != 0 && x != 255 {
+                    self.status |= 0x40;
+                }
+
+                // Attribute bit 5 puts the sprite behind the background, it only shows through transparent pixels
+                if bg_pixel == 0 || sprite.attr & 0x20 == 0 {
+                    (pixel, 4 + (sprite.attr & 0x03))
+                } else {
+                    (bg_pixel, bg_palette)
+                }
+            }
+            None => (bg_pixel, bg_palette),
+        };
+
+        let rgb = self.palette_color(palette, pixel);
+        let offset = (y * WIDTH + x) * 3;
+        self.framebuffer[offset..offset + 3].copy_from_slice(&rgb);
+    }
+
+    // Advance the PPU by a number of dots
+    // With rendering off a dot past dot 1 only moves the position, the VBlank and pre-render flag changes land on dot 1
+    // and the line and frame changes on dot 0, so the rest of each line is skipped in one step
+    pub fn step_dots(&mut self, dots: usize) {
+        let mut remaining = dots;
+        while remaining > 0 {
+            if !self.is_rendering_enabled() && self.cycles >= 1 && self.cycles < 340 {
+                let skip = (340 - self.cycles).min(remaining);
+                self.cycles += skip;
+                self.line_sprites.clear();
+                remaining -= skip;
+            } else {
+                self.step_dot();
+                remaining -= 1;
+            }
+        }
+    }
+
+    // Run to dot 0 of the next scanline
+    pub fn step_scanline(&mut self) {
+        let scanline = self.scanline;
+        while self.scanline == scanline {
+            self.step_dot();
+        }
+    }
+
+    // Run until VBlank begins, stopping on the dot that sets the VBlank flag
+    pub fn step_to_vblank(&mut self) {
+        self.step_dot();
+        while !(self.scanline == 241 && self.cycles == 1) {
+            self.step_dot();
+        }
+    }
+
+    // Value a CPU read of a PPU register returns, without any side effects
+    // The write-only registers ($2000, $2001, $2003, $2005, $2006) return the open bus latch,
+    // the last value written to or read from any PPU register
     pub fn read_register(&self, addr: u16) -> u8 {
         match addr {
-            0x2000 => self.control,
-            0x2001 => self.mask,
-            0x2002 => self.status,
-            0x2003 => self.oam_addr,
+            // Only the top three bits are driven, the rest is stale open bus
+            0x2002 => (self.status & 0xE0) | (self.open_bus & 0x1F),
+            // While rendering, dots 1-64 of each visible line clear secondary OAM and reads see the $FF being written
+            // The rest of the line really returns whatever sprite evaluation is looking at, the stored byte stands in for it
+            0x2004 if self.is_rendering_enabled() && (0..240).contains(&self.scanline) && (1..=64).contains(&self.cycles) => 0xFF,
+            // Bits 2-4 of a sprite's attribute byte don't exist in OAM and read back as 0
+            0x2004 if self.oam_addr & 0x03 == 2 => self.oam_data[self.oam_addr as usize] & 0xE3,
             0x2004 => self.oam_data[self.oam_addr as usize],
-            0x2005 => { if !self.addr_latch { self.scroll.0 } else { self.scroll.1 } },
-            0x2007 => self.vram[self.addr as usize & 0x7FF],
-            _ => 0,
+            0x2007 => match self.vram_addr & 0x3FFF {
+                // Palette entries are 6 bits, the top two come from open bus
+                0x3F00..=0x3FFF => (self.read_vram(self.vram_addr) & 0x3F) | (self.open_bus & 0xC0),
+                // Everything else arrives one read late, through the read buffer
+                _ => self.read_buffer,
+            },
+            _ => self.open_bus,
         }
     }
+
+    // A CPU read of a PPU register, the readable registers refresh the open bus latch with what they drove
+    pub fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x2002 => {
+                let data = self.read_status();
+                self.open_bus = (self.open_bus & 0x1F) | (data & 0xE0);
+                data
+            }
+            0x2004 => {
+                let data = self.read_register(addr);
+                self.open_bus = data;
+                data
+            }
+            0x2007 => {
+                let data = self.read_register(addr);
+                self.open_bus = data;
+
+                // Palette reads are answered immediately, but the buffer still fills from the nametable
+                // byte the palette sits over, $3F00-$3FFF reads $2F00-$2FFF
+                let addr = self.vram_addr & 0x3FFF;
+                self.read_buffer = match addr {
+                    0x3F00..=0x3FFF => self.read_vram(addr & 0x2FFF),
+                    _ => self.read_vram(addr),
+                };
+                self.vram_addr = self.vram_addr.wrapping_add(self.vram_increment()) & 0x3FFF;
+                data
+            }
+            _ => self.read_register(addr),
+        }
+    }
+
+    // Read $2002 with its side effects, VBlank and the write toggle are cleared
+    // Racing the start of VBlank drops the NMI: one dot early the flag never sets,
+    // on the dot it sets or the one after the flag reads set but the NMI is cancelled
+    pub fn read_status(&mut self) -> u8 {
+        let data = self.read_register(0x2002);
+        self.status &= 0x7F;
+        self.write_toggle = false;
+
+        if self.scanline == 241 {
+            match self.cycles {
+                0 => self.suppress_vblank = true,
+                1 | 2 => self.nmi_triggered = false,
+                _ => {}
+            }
+        }
+
+        data
+    }
+
     pub fn write_register(&mut self, addr: u16, data: u8) {
+        // Every register write lands on the PPU's internal data bus
+        self.open_bus = data;
+
         match addr & 0x2007 {
-            0x2000 => self.control = data,
+            0x2000 => {
+                // The NMI output is edge triggered: enabling NMIs while the VBlank flag is already set
+                // raises it immediately, disabling them drops one that has not been taken yet
+                let was_enabled = self.control & 0x80 != 0;
+                let enabled = data & 0x80 != 0;
+                if !was_enabled && enabled && self.status & 0x80 != 0 {
+                    self.nmi_triggered = true;
+                } else if was_enabled && !enabled {
+                    self.nmi_triggered = false;
+                }
+                self.control = data;
+                // Nametable select bits are shared with the scroll address
+                self.temp_addr = (self.temp_addr & 0xF3FF) | (((data as u16) & 0x03) << 10);
+            },
             0x2001 => self.mask = data,
             0x2003 => self.oam_addr = data,
-            0x2004 => self.oam_data[self.oam_addr as usize] = data,
+            0x2004 => {
+                // While rendering OAM belongs to sprite evaluation, the write is lost and the address
+                // only bumps its sprite index, the glitchy increment real hardware does
+                if self.is_rendering_enabled() && self.scanline < 240 {
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                } else {
+                    self.oam_data[self.oam_addr as usize] = data;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                }
+            }
             0x2005 => {
                 if !self.write_toggle {
                     self.fine_x = data & 0x07;
@@ -216,17 +530,275 @@ impl PPU {
                 self.write_toggle = !self.write_toggle;
             },
             0x2007 => {
-                self.vram[self.addr as usize & 0x7FF] = data;
-                self.addr = self.addr.wrapping_add(self.vram_increment());
+                self.write_vram(self.vram_addr, data);
+                self.vram_addr = self.vram_addr.wrapping_add(self.vram_increment()) & 0x3FFF;
             },
             _ => {}
         }
     }
 
+    // Fill OAM from a DMA transfer, starting at the current OAM address
+    pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
+        for byte in data.iter() {
+            self.oam_data[self.oam_addr as usize] = *byte;
+            self.oam_addr = self.oam_addr.wrapping_add(1);
+        }
+    }
+
+    // All of OAM at once for tooling, skipping the $2003/$2004 interface and the OAM address it moves
+    pub fn oam(&self) -> &[u8; 256] {
+        &self.oam_data
+    }
+
+    pub fn set_oam(&mut self, data: [u8; 256]) {
+        self.oam_data = data;
+    }
+
+    // Nametable RAM followed by the 32 bytes of palette RAM, for snapshotting a scene
+    pub fn dump_vram(&self) -> Vec<u8> {
+        let mut dump = self.vram.clone();
+        dump.extend_from_slice(&self.palette_table);
+        dump
+    }
+
+    // Load a dump_vram snapshot, it must come from a PPU with the same amount of nametable RAM
+    pub fn load_vram(&mut self, dump: &[u8]) -> Result<(), String> {
+        let vram_size = self.vram.len();
+        if dump.len() != vram_size + self.palette_table.len() {
+            return Err(format!("VRAM dump is {} bytes, expected {}", dump.len(), vram_size + self.palette_table.len()));
+        }
+        self.vram.copy_from_slice(&dump[..vram_size]);
+        self.palette_table.copy_from_slice(&dump[vram_size..]);
+        Ok(())
+    }
+
+    pub fn dump_oam(&self) -> Vec<u8> {
+        self.oam_data.to_vec()
+    }
+
+    pub fn load_oam(&mut self, dump: &[u8]) -> Result<(), String> {
+        self.oam_data = dump.try_into().map_err(|_| format!("OAM dump is {} bytes, expected 256", dump.len()))?;
+        Ok(())
+    }
+
+    // Decoded OAM entry 0-63
+    pub fn sprite(&self, index: usize) -> Sprite {
+        let entry = &self.oam_data[index * 4..index * 4 + 4];
+        Sprite { y: entry[0], tile: entry[1], attr: entry[2], x: entry[3] }
+    }
+
+    // Replace the master palette with a .pal file, 64 RGB triples
+    // Files with the 8 emphasis variants appended (512 colors) are accepted, only the first 64 are used
+    pub fn load_palette(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() != 64 * 3 && bytes.len() != 512 * 3 {
+            return Err(format!("Palette must be 192 or 1536 bytes, got {}", bytes.len()));
+        }
+
+        for (color, rgb) in self.master_palette.iter_mut().zip(bytes.chunks(3)) {
+            *color = (rgb[0], rgb[1], rgb[2]);
+        }
+        Ok(())
+    }
+
+    // Copy the framebuffer with the given number of pixels cropped from each edge
+    // Real TVs hid roughly 8 pixels on every side, so (8, 8, 8, 8) gives the typical 240x224 picture
+    pub fn visible_framebuffer(&self, top: usize, bottom: usize, left: usize, right: usize) -> Vec<u8> {
+        let width = WIDTH.saturating_sub(left + right);
+        let height = HEIGHT.saturating_sub(top + bottom);
+        let mut cropped = Vec::with_capacity(width * height * 3);
+
+        for y in top..(top + height) {
+            let start = (y * WIDTH + left) * 3;
+            cropped.extend_from_slice(&self.framebuffer[start..start + width * 3]);
+        }
+
+        cropped
+    }
+
+    // Index into chr_rom for a pattern table address
+    // Every CHR access, reads, CHR RAM writes, and rendering fetches, goes through here, so tile data written
+    // through $2007 is what the very next fetch sees, and CHR bank switching only has to change this mapping
+    fn chr_index(&self, addr: u16) -> usize {
+        (addr & 0x1FFF) as usize % self.chr_rom.len()
+    }
+
+    // A byte of pattern table data, $0000-$1FFF
+    pub fn read_pattern(&self, addr: u16) -> u8 {
+        self.chr_rom[self.chr_index(addr)]
+    }
+
+    // Read from the PPU address space
+    // $0000-$1FFF is the cartridge's CHR
+    fn read_vram(&self, addr: u16) -> u8 {
+        match addr & 0x3FFF {
+            0x0000..=0x1FFF => self.read_pattern(addr),
+            0x2000..=0x3EFF => self.vram[self.mirror_vram_addr(addr)],
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    fn write_vram(&mut self, addr: u16, data: u8) {
+        match addr & 0x3FFF {
+            // CHR ROM ignores writes
+            0x0000..=0x1FFF => {
+                if self.chr_ram {
+                    let index = self.chr_index(addr);
+                    self.chr_rom[index] = data;
+                }
+            }
+            0x2000..=0x3EFF => {
+                let index = self.mirror_vram_addr(addr);
+                self.vram[index] = data;
+            }
+            _ => self.palette_table[palette_index(addr)] = data,
+        }
+    }
+
+    // Debug view of one pattern table ($0000 or $1000) as 16x16 tiles, 128x128 RGB
+    // Pixels are colored with one of the eight palettes (0-3 background, 4-7 sprites)
+    pub fn render_pattern_table(&self, table: u8, palette: u8) -> Vec<u8> {
+        const SIZE: usize = 128;
+        let mut image = vec![0; SIZE * SIZE * 3];
+        let base = (table as u16 & 1) * 0x1000;
+
+        for tile in 0..256usize {
+            let tile_x = (tile % 16) * 8;
+            let tile_y = (tile / 16) * 8;
+
+            for y in 0..8 {
+                for x in 0..8 {
+                    let pixel = self.pattern_pixel(base, tile as u8, x, y);
+                    let offset = ((tile_y + y) * SIZE + tile_x + x) * 3;
+                    image[offset..offset + 3].copy_from_slice(&self.palette_color(palette & 0x07, pixel));
+                }
+            }
+        }
+
+        image
+    }
+
+    // Debug view of all four nametables as 512x480 RGB, laid out as they are addressed
+    // [ $2000 ] [ $2400 ]
+    // [ $2800 ] [ $2C00 ]
+    pub fn render_nametable(&self) -> Vec<u8> {
+        const WIDTH: usize = 512;
+        const HEIGHT: usize = 480;
+        let mut image = vec![0; WIDTH * HEIGHT * 3];
+        let pattern_base = self.background_pattern_base();
+
+        for nametable in 0..4u16 {
+            let nametable_addr = 0x2000 + nametable * 0x400;
+            let origin_x = (nametable as usize % 2) * 256;
+            let origin_y = (nametable as usize / 2) * 240;
+
+            for row in 0..30u16 {
+                for col in 0..32u16 {
+                    let tile = self.vram[self.mirror_vram_addr(nametable_addr + row * 32 + col)];
+
+                    // Each attribute byte covers a 4x4 tile block, two bits per 2x2 quadrant
+                    let attr = self.vram[self.mirror_vram_addr(nametable_addr + 0x3C0 + (row / 4) * 8 + col / 4)];
+                    let shift = ((row % 4) / 2) * 4 + ((col % 4) / 2) * 2;
+                    let palette = (attr >> shift) & 0x03;
+
+                    for y in 0..8 {
+                        for x in 0..8 {
+                            let pixel = self.pattern_pixel(pattern_base, tile, x, y);
+                            let offset = ((origin_y + row as usize * 8 + y) * WIDTH + origin_x + col as usize * 8 + x) * 3;
+                            image[offset..offset + 3].copy_from_slice(&self.palette_color(palette, pixel));
+                        }
+                    }
+                }
+            }
+        }
+
+        image
+    }
+
+    // Two bit color of one pixel of a tile, read straight from CHR
+    fn pattern_pixel(&self, table_base: u16, tile: u8, x: usize, y: usize) -> u8 {
+        let addr = table_base + tile as u16 * 16 + y as u16;
+        let lo = self.read_pattern(addr);
+        let hi = self.read_pattern(addr + 8);
+
+        let bit = 7 - x;
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    // RGB for a pixel value within a palette, pixel 0 is always the shared backdrop color
+    // Every color the PPU draws, on screen and in the debug views, is looked up here
+    fn palette_color(&self, palette: u8, pixel: u8) -> [u8; 3] {
+        let index = if pixel == 0 { 0 } else { palette as usize * 4 + pixel as usize };
+        master_palette_rgb(&self.master_palette, self.palette_table[index])
+    }
+
+    // Map a nametable address ($2000-$3EFF) to its index in VRAM
+    // Horizontal:   Vertical:     Four Screen:
+    //   [ A ] [ a ]   [ A ] [ B ]   [ A ] [ B ]
+    //   [ B ] [ b ]   [ a ] [ b ]   [ C ] [ D ]
+    pub fn mirror_vram_addr(&self, addr: u16) -> usize {
+        let index = (addr & 0x0FFF) as usize;
+        let nametable = index / 0x400;
+
+        match (self.mirroring, nametable) {
+            (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => index - 0x800,
+            (Mirroring::HORIZONTAL, 1) | (Mirroring::HORIZONTAL, 2) => index - 0x400,
+            (Mirroring::HORIZONTAL, 3) => index - 0x800,
+            _ => index,
+        }
+    }
+
+    // PPUCTRL bit 4 selects the background pattern table, $0000 or $1000
+    pub fn background_pattern_base(&self) -> u16 {
+        (self.control as u16 & 0x10) << 8
+    }
+
+    // PPUCTRL bit 3 selects the pattern table for 8x8 sprites, $0000 or $1000
+    // 8x16 sprites ignore it and pick the table from bit 0 of their tile index
+    pub fn sprite_pattern_base(&self) -> u16 {
+        (self.control as u16 & 0x08) << 9
+    }
+
+    // PPUCTRL bit 5 switches sprites from 8x8 to 8x16
+    pub fn sprite_height(&self) -> u8 {
+        if self.control & 0x20 != 0 { 16 } else { 8 }
+    }
+
+    // Address of the low pattern byte for one row of a sprite, the high byte is 8 bytes later
+    // 8x16 sprites take their table from bit 0 of the tile index and draw the top half from the even tile
+    // and the bottom half from the tile after it
+    pub fn sprite_pattern_addr(&self, tile: u8, row: u8, flip_vertical: bool) -> u16 {
+        let height = self.sprite_height();
+        let row = if flip_vertical { height - 1 - row } else { row };
+
+        if height == 16 {
+            let table = (tile as u16 & 0x01) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + (row / 8) as u16;
+            table + tile * 16 + (row % 8) as u16
+        } else {
+            self.sprite_pattern_base() + tile as u16 * 16 + row as u16
+        }
+    }
+
     fn vram_increment(&self) -> u16 {
         if self.control & 0b00000100 != 0 { 32 } else { 1 }
     }
 
+    // Current (scanline, dot), scanline -1 is the pre-render line
+    pub fn position(&self) -> (isize, usize) {
+        (self.scanline, self.cycles)
+    }
+
+    // Frames completed since power on
+    pub fn frame_count(&self) -> usize {
+        self.frame
+    }
+
+    // Background or sprite rendering is turned on in PPUMASK
+    // Every check of the rendering bits should go through here
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.mask & 0b0001_1000 != 0
+    }
+
     fn increment_x(&mut self) {
         if (self.vram_addr & 0x001F) == 31 {
             self.vram_addr &= !0x001F;           
@@ -257,19 +829,948 @@ impl PPU {
     fn transfer_horizontal(&mut self) {
         self.vram_addr = (self.vram_addr & 0x7BE0) | (self.temp_addr & 0x041F);
     }
+
+    fn transfer_vertical(&mut self) {
+        self.vram_addr = (self.vram_addr & 0x041F) | (self.temp_addr & 0x7BE0);
+    }
+}
+
+// Palette RAM is mirrored every 32 bytes, and the sprite backdrop entries $3F10/$3F14/$3F18/$3F1C share the background's
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    if index & 0x13 == 0x10 { index - 0x10 } else { index }
 }
 
-// Return a Color based on a bytye
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::{test_image, Rom};
+
+    // A PPU with 8KB of CHR RAM and horizontal mirroring
+    fn ppu() -> PPU {
+        PPU::new(Vec::new(), Mirroring::HORIZONTAL)
+    }
+
+    #[test]
+    fn palette_spot_checks() {
+        assert_eq!(palette_rgb(0x0F), [0x00, 0x00, 0x00]);
+        assert_eq!(palette_rgb(0x30), [0xEC, 0xEE, 0xEC]);
+        assert_eq!(palette_rgb(0x00), [0x54, 0x54, 0x54]);
+        assert_eq!(palette_rgb(0x16), [0x98, 0x22, 0x20]);
+        // Only the low 6 bits pick the color
+        assert_eq!(palette_rgb(0xF0), palette_rgb(0x30));
+    }
+
+    #[test]
+    fn renderer_and_debug_views_share_the_palette_lookup() {
+        let mut ppu = ppu();
+        // Tile 0 row 0 is pixel value 1 across, palette 0 color 1 is $30
+        ppu.chr_rom[0] = 0xFF;
+        ppu.palette_table[1] = 0x30;
+
+        assert_eq!(ppu.palette_color(0, 1), palette_rgb(0x30));
+        assert_eq!(ppu.render_pattern_table(0, 0)[0..3], palette_rgb(0x30));
+        assert_eq!(ppu.render_nametable()[0..3], palette_rgb(0x30));
+
+        // A loaded .pal file replaces the colors everywhere
+        let mut pal = vec![0; 192];
+        pal[0x30 * 3..0x30 * 3 + 3].copy_from_slice(&[1, 2, 3]);
+        ppu.load_palette(&pal).unwrap();
+        assert_eq!(ppu.render_pattern_table(0, 0)[0..3], [1, 2, 3]);
+        assert_eq!(ppu.render_nametable()[0..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn visible_framebuffer_crops_each_edge() {
+        let mut ppu = ppu();
+        // Every pixel holds its own coordinates
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let i = (y * WIDTH + x) * 3;
+                ppu.framebuffer[i..i + 3].copy_from_slice(&[x as u8, y as u8, 0]);
+            }
+        }
+
+        assert_eq!(ppu.visible_framebuffer(0, 0, 0, 0), ppu.framebuffer.to_vec());
+
+        let cropped = ppu.visible_framebuffer(8, 8, 8, 8);
+        assert_eq!(cropped.len(), 240 * 224 * 3);
+        assert_eq!(cropped[0..3], [8, 8, 0]);
+        let last = cropped.len() - 3;
+        assert_eq!(cropped[last..], [247, 231, 0]);
+
+        // Uneven edges, 2 from the top and 3 from the left
+        let cropped = ppu.visible_framebuffer(2, 5, 3, 1);
+        let width = WIDTH - 4;
+        assert_eq!(cropped.len(), width * (HEIGHT - 7) * 3);
+        assert_eq!(cropped[0..3], [3, 2, 0]);
+        assert_eq!(cropped[width * 3..width * 3 + 3], [3, 3, 0]);
+    }
+
+    #[test]
+    fn control_write_sets_the_temp_nametable_bits() {
+        let mut ppu = ppu();
+        ppu.temp_addr = 0x7FFF;
+        ppu.write_register(0x2000, 0x02);
+        assert_eq!(ppu.temp_addr, 0x7BFF);
+        assert_eq!(ppu.control, 0x02);
+
+        ppu.temp_addr = 0x0000;
+        ppu.write_register(0x2000, 0xFD);
+        assert_eq!(ppu.temp_addr, 0x0400);
+
+        // The nametable reaches vram_addr once the $2006 pair copies temp_addr over
+        ppu.write_register(0x2000, 0x03);
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2006, 0x00);
+        assert_eq!(ppu.vram_addr, 0x0000);
+        ppu.write_register(0x2000, 0x03);
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2005, 0x00);
+        assert_eq!(ppu.temp_addr & 0x0C00, 0x0C00);
+    }
+
+    #[test]
+    fn status_low_bits_come_from_open_bus() {
+        let mut ppu = ppu();
+        ppu.status = 0xE0;
+        ppu.write_register(0x2003, 0x1B);
+
+        let data = ppu.cpu_read(0x2002);
+        assert_eq!(data, 0xFB);
+        // The read clears VBlank but the stale low bits stay on the bus
+        assert_eq!(ppu.cpu_read(0x2002), 0x7B);
+
+        ppu.write_register(0x2001, 0x04);
+        assert_eq!(ppu.cpu_read(0x2002) & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn scroll_position_survives_a_frame_with_rendering_off() {
+        let mut ppu = ppu();
+        ppu.write_register(0x2006, 0x23);
+        ppu.write_register(0x2006, 0x45);
+        ppu.temp_addr = 0x0000;
+
+        // A whole frame one dot at a time, then another through the skipping fast path
+        let frame = ppu.frame;
+        while ppu.frame < frame + 2 {
+            ppu.step_dot();
+        }
+        ppu.step_dots(2 * 341 * 262);
+        assert!(ppu.frame >= frame + 3);
+        assert_eq!(ppu.vram_addr, 0x2345);
+
+        // The same frame with the background on walks the address and reloads it from temp_addr
+        ppu.write_register(0x2001, 0x08);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+        assert_ne!(ppu.vram_addr, 0x2345);
+    }
+
+    #[test]
+    fn pre_render_line_reloads_the_vertical_scroll() {
+        let mut ppu = ppu();
+        ppu.write_register(0x2000, 0x02);
+        ppu.write_register(0x2005, 0x00);
+        ppu.write_register(0x2005, 0x5D);
+        assert_eq!(ppu.temp_addr, 0x5960);
+
+        ppu.write_register(0x2001, 0x08);
+        ppu.scanline = 240;
+        ppu.vram_addr = 0x0000;
+        while !(ppu.scanline == -1 && ppu.cycles == 279) {
+            ppu.step_dot();
+        }
+        assert_ne!(ppu.vram_addr & 0x7BE0, ppu.temp_addr & 0x7BE0);
+
+        ppu.step_dots(304 - 279);
+        assert_eq!(ppu.vram_addr & 0x7BE0, ppu.temp_addr & 0x7BE0);
+    }
+
+    #[test]
+    fn fetches_stop_on_the_dot_rendering_is_disabled() {
+        let mut ppu = ppu();
+        ppu.vram.fill(0x01);
+        ppu.chr_rom[16..32].fill(0xA5);
+        ppu.write_register(0x2001, 0x08);
+        ppu.scanline = 0;
+        ppu.step_dots(100);
+        assert!(ppu.is_rendering_enabled());
+        let coarse_x = ppu.vram_addr & 0x1F;
+        assert_ne!(coarse_x, 0);
+
+        ppu.write_register(0x2001, 0x00);
+        assert!(!ppu.is_rendering_enabled());
+        let state = (ppu.vram_addr, ppu.bg_pattern_lo, ppu.next_tile_lsb);
+        for _ in 0..100 {
+            ppu.step_dot();
+            assert_eq!((ppu.vram_addr, ppu.bg_pattern_lo, ppu.next_tile_lsb), state);
+        }
+
+        // Sprites alone count as rendering too, the next tile boundary moves coarse X again
+        ppu.write_register(0x2001, 0x10);
+        ppu.step_dots(8);
+        assert_eq!(ppu.vram_addr & 0x1F, coarse_x + 1);
+    }
+
+    #[test]
+    fn four_screen_nametables_are_independent() {
+        let mut image = test_image(&[]);
+        image[6] |= 0x08;
+        let rom = Rom::from_bytes(&image).unwrap();
+        assert_eq!(rom.mirroring, Mirroring::FOURSCREEN);
+
+        let mut ppu = PPU::new(rom.c_rom, rom.mirroring);
+        assert_eq!(ppu.vram.len(), 0x1000);
+        for (i, base) in [0x2000u16, 0x2400, 0x2800, 0x2C00].into_iter().enumerate() {
+            ppu.write_register(0x2006, (base >> 8) as u8 | 0x01);
+            ppu.write_register(0x2006, 0x23);
+            ppu.write_register(0x2007, 0x10 + i as u8);
+        }
+
+        for (i, base) in [0x2000u16, 0x2400, 0x2800, 0x2C00].into_iter().enumerate() {
+            assert_eq!(ppu.read_vram(base + 0x123), 0x10 + i as u8);
+            // $3000-$3EFF mirrors the same four tables
+            assert_eq!(ppu.read_vram(base + 0x1123), 0x10 + i as u8);
+        }
+
+        // Horizontal mirroring folds the same writes onto two tables
+        let mut horizontal = self::ppu();
+        for (i, base) in [0x2000u16, 0x2400, 0x2800, 0x2C00].into_iter().enumerate() {
+            horizontal.write_vram(base + 0x123, 0x10 + i as u8);
+        }
+        assert_eq!(horizontal.read_vram(0x2123), 0x11);
+        assert_eq!(horizontal.read_vram(0x2923), 0x13);
+    }
+
+    #[test]
+    fn scroll_and_address_writes_share_one_latch() {
+        // The mid-frame split scroll sequence: $2006, $2005, $2005, $2006
+        let mut ppu = ppu();
+        ppu.write_register(0x2006, 0x04);
+        assert_eq!(ppu.temp_addr, 0x0400);
+        ppu.write_register(0x2005, 0x3E);
+        assert_eq!(ppu.temp_addr, 0x64E0);
+        ppu.write_register(0x2005, 0x7D);
+        assert_eq!((ppu.temp_addr, ppu.fine_x), (0x64EF, 5));
+        assert!(ppu.write_toggle);
+        ppu.write_register(0x2006, 0xEF);
+
+        assert_eq!(ppu.vram_addr, 0x64EF);
+        assert!(!ppu.write_toggle);
+
+        // A $2002 read resets the shared toggle, so the next $2006 write is a high byte again
+        ppu.write_register(0x2006, 0x21);
+        ppu.cpu_read(0x2002);
+        ppu.write_register(0x2006, 0x23);
+        ppu.write_register(0x2006, 0x45);
+        assert_eq!(ppu.vram_addr, 0x2345);
+    }
+
+    #[test]
+    fn debug_views_show_a_known_tile() {
+        let mut ppu = ppu();
+        // Tile 1 of the $1000 table is a diagonal of color 1 with a color 3 pixel at its bottom right
+        for row in 0..8 {
+            ppu.chr_rom[0x1010 + row] = 0x80 >> row;
+        }
+        ppu.chr_rom[0x1017] |= 0x01;
+        ppu.chr_rom[0x101F] = 0x01;
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[0x11] = 0x30;
+        ppu.palette_table[0x13] = 0x16;
+
+        let image = ppu.render_pattern_table(1, 4);
+        assert_eq!(image.len(), 128 * 128 * 3);
+        let pixel = |x: usize, y: usize| image[(y * 128 + x) * 3..(y * 128 + x) * 3 + 3].to_vec();
+        for i in 0..7 {
+            assert_eq!(pixel(8 + i, i), palette_rgb(0x30));
+            assert_eq!(pixel(9 + i, i), palette_rgb(0x0F));
+        }
+        assert_eq!(pixel(15, 7), palette_rgb(0x16));
+        // The other table and the neighbouring tiles are blank
+        assert_eq!(pixel(0, 0), palette_rgb(0x0F));
+        assert!(ppu.render_pattern_table(0, 4).chunks(3).all(|rgb| rgb == palette_rgb(0x0F)));
+
+        // The same tile as the second entry of the lower nametable, with the background using the $1000 table
+        ppu.write_register(0x2000, 0x10);
+        ppu.palette_table[0x01] = 0x30;
+        ppu.vram[0x0401] = 0x01;
+        let image = ppu.render_nametable();
+        assert_eq!(image.len(), 512 * 480 * 3);
+        // Horizontal mirroring shows the second 1KB of VRAM as $2800, the bottom left of the view
+        let offset = (240 * 512 + 8) * 3;
+        assert_eq!(image[offset..offset + 3], palette_rgb(0x30));
+        assert_eq!(image[offset + 3..offset + 6], palette_rgb(0x0F));
+    }
+
+    #[test]
+    fn pattern_table_select_bits_move_fetches_by_0x1000() {
+        let mut ppu = ppu();
+        ppu.chr_rom[0x0000] = 0x11;
+        ppu.chr_rom[0x1000] = 0x22;
+        ppu.write_register(0x2001, 0x18);
+
+        // Bit 4 picks the background table, bit 3 the sprite table, neither touches the other
+        for (control, background, sprite) in [(0x00, 0x11, 0x11), (0x10, 0x22, 0x11), (0x08, 0x11, 0x22), (0x18, 0x22, 0x22)] {
+            ppu.write_register(0x2000, control);
+            assert_eq!(ppu.background_pattern_base(), if background == 0x22 { 0x1000 } else { 0 });
+            assert_eq!(ppu.sprite_pattern_addr(5, 3, false), if sprite == 0x22 { 0x1053 } else { 0x0053 });
+
+            // The low pattern byte of tile 0 is fetched on dot 5
+            ppu.scanline = 0;
+            ppu.cycles = 0;
+            ppu.step_dots(5);
+            assert_eq!(ppu.next_tile_lsb, background, "control {:02X}", control);
+
+            // Sprite 0 at the top left, tile 0, evaluated for the first line it covers
+            ppu.oam_data[0..4].copy_from_slice(&[0, 0, 0, 0]);
+            ppu.scanline = 1;
+            ppu.evaluate_sprites();
+            assert_eq!(ppu.line_sprites[0].lo, sprite, "control {:02X}", control);
+        }
+    }
+
+    #[test]
+    fn tall_sprites_take_both_halves_from_the_tile_pair() {
+        let mut ppu = ppu();
+        // Tile index 3 means the $1000 table, tile 2 on top and tile 3 below
+        ppu.chr_rom[0x1020] = 0xA1;
+        ppu.chr_rom[0x1027] = 0xA7;
+        ppu.chr_rom[0x1030] = 0xB1;
+        ppu.chr_rom[0x1037] = 0xB7;
+        ppu.chr_rom[0x0020] = 0xEE;
+        // 8x16 mode with the 8x8 sprite table bit pointing at $0000, which tall sprites ignore
+        ppu.write_register(0x2000, 0x20);
+        ppu.write_register(0x2001, 0x10);
+        ppu.oam_data[0..4].copy_from_slice(&[9, 0x03, 0x00, 0]);
+
+        let row = |ppu: &mut PPU, scanline: isize| {
+            ppu.scanline = scanline;
+            ppu.evaluate_sprites();
+            ppu.line_sprites.first().map(|sprite| sprite.lo)
+        };
+        assert_eq!(row(&mut ppu, 10), Some(0xA1));
+        assert_eq!(row(&mut ppu, 17), Some(0xA7));
+        assert_eq!(row(&mut ppu, 18), Some(0xB1));
+        assert_eq!(row(&mut ppu, 25), Some(0xB7));
+        assert_eq!(row(&mut ppu, 26), None);
+
+        // Flipped vertically the bottom tile's last row comes first
+        ppu.oam_data[2] = 0x80;
+        assert_eq!(row(&mut ppu, 10), Some(0xB7));
+        assert_eq!(row(&mut ppu, 25), Some(0xA1));
+    }
+
+    #[test]
+    fn step_scanline_moves_to_the_start_of_the_next_line() {
+        let mut ppu = ppu();
+        ppu.scanline = 5;
+        ppu.step_dots(100);
+        ppu.step_scanline();
+        assert_eq!((ppu.scanline, ppu.cycles), (6, 0));
+        ppu.step_scanline();
+        assert_eq!((ppu.scanline, ppu.cycles), (7, 0));
+
+        // The last line wraps to the pre-render line of the next frame
+        ppu.scanline = 261;
+        let frame = ppu.frame;
+        ppu.step_scanline();
+        assert_eq!((ppu.scanline, ppu.cycles, ppu.frame), (-1, 0, frame + 1));
+
+        ppu.step_to_vblank();
+        assert_eq!((ppu.scanline, ppu.cycles), (241, 1));
+        assert_eq!(ppu.status & 0x80, 0x80);
+    }
+
+    #[test]
+    fn overscan_crop_drops_the_top_and_bottom_rows_of_a_frame() {
+        let mut ppu = ppu();
+        // Solid tile 1 across the first and last tile rows, the 8 lines at each edge
+        ppu.chr_rom[0x10..0x18].fill(0xFF);
+        ppu.vram[0..32].fill(0x01);
+        ppu.vram[29 * 32..30 * 32].fill(0x01);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_register(0x2001, 0x0A);
+
+        // Finish the frame in progress, then draw a whole one
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+        let row = |image: &[u8], y: usize, width: usize| image[y * width * 3..(y + 1) * width * 3].to_vec();
+        let edge: Vec<u8> = palette_rgb(0x30).repeat(WIDTH);
+        let middle: Vec<u8> = palette_rgb(0x0F).repeat(WIDTH);
+        assert_eq!(row(&ppu.framebuffer, 0, WIDTH), edge);
+        assert_eq!(row(&ppu.framebuffer, 7, WIDTH), edge);
+        assert_eq!(row(&ppu.framebuffer, 8, WIDTH), middle);
+        assert_eq!(row(&ppu.framebuffer, 232, WIDTH), edge);
+
+        let cropped = ppu.visible_framebuffer(8, 8, 0, 0);
+        assert_eq!(cropped.len(), WIDTH * 224 * 3);
+        assert!((0..224).all(|y| row(&cropped, y, WIDTH) == middle));
+        // The full frame is untouched by cropping
+        assert_eq!(row(&ppu.framebuffer, 239, WIDTH), edge);
+    }
+
+    #[test]
+    fn loaded_palette_recolors_rendered_pixels() {
+        let mut ppu = ppu();
+        ppu.palette_table[0] = 0x21;
+        ppu.write_register(0x2001, 0x0A);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+        assert_eq!(ppu.framebuffer[0..3], palette_rgb(0x21));
+
+        // Every color is its own index, so $21 comes out as (0x21, 0x42, 0x63)
+        let pal: Vec<u8> = (0..64u8).flat_map(|i| [i, i * 2, i * 3]).collect();
+        ppu.load_palette(&pal).unwrap();
+        ppu.step_to_vblank();
+        assert_eq!(ppu.framebuffer[0..3], [0x21, 0x42, 0x63]);
+        let last = ppu.framebuffer.len() - 3;
+        assert_eq!(ppu.framebuffer[last..], [0x21, 0x42, 0x63]);
+
+        // A 512 color file uses its first 64, anything else is refused and leaves the palette alone
+        let mut extended = vec![0xFF; 512 * 3];
+        extended[0x21 * 3..0x21 * 3 + 3].copy_from_slice(&[1, 2, 3]);
+        ppu.load_palette(&extended).unwrap();
+        assert_eq!(ppu.master_palette[0x21], (1, 2, 3));
+        assert_eq!(ppu.load_palette(&[0; 100]), Err("Palette must be 192 or 1536 bytes, got 100".to_string()));
+        assert_eq!(ppu.master_palette[0x21], (1, 2, 3));
+    }
+
+    #[test]
+    fn reading_status_around_vblank_suppresses_the_nmi() {
+        // Stop the PPU on the given dot of line 241 with NMIs enabled and read $2002 there
+        let read_at = |dot: usize| {
+            let mut ppu = ppu();
+            ppu.write_register(0x2000, 0x80);
+            ppu.scanline = 240;
+            while !(ppu.scanline == 241 && ppu.cycles == dot) {
+                ppu.step_dot();
+            }
+            let status = ppu.cpu_read(0x2002);
+            ppu.step_dots(2);
+            (status & 0x80 != 0, ppu.nmi_triggered, ppu.status & 0x80 != 0)
+        };
+
+        // One dot early the flag never appears and there is no NMI that frame
+        assert_eq!(read_at(0), (false, false, false));
+        // On the dot it is set, or just after, the read sees VBlank but the NMI is cancelled
+        assert_eq!(read_at(1), (true, false, false));
+        assert_eq!(read_at(2), (true, false, false));
+        // Later reads only clear the flag, the NMI already went out
+        assert_eq!(read_at(3), (true, true, false));
+
+        // Without any read both happen, and the suppression only lasts one frame
+        let mut ppu = ppu();
+        ppu.write_register(0x2000, 0x80);
+        ppu.scanline = 241;
+        ppu.cpu_read(0x2002);
+        ppu.step_dots(2);
+        assert!(!ppu.nmi_triggered);
+        ppu.step_to_vblank();
+        ppu.step_dot();
+        assert!(ppu.nmi_triggered && ppu.status & 0x80 != 0);
+    }
+
+    #[test]
+    fn mid_scanline_address_write_only_changes_later_pixels() {
+        let mut ppu = ppu();
+        // $2000 is solid tile 1, the separate $2800 table stays blank
+        ppu.chr_rom[0x10..0x18].fill(0xFF);
+        ppu.vram[0..0x3C0].fill(0x01);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_register(0x2001, 0x0A);
+        ppu.scanline = -1;
+        while !(ppu.scanline == 0 && ppu.cycles == 128) {
+            ppu.step_dot();
+        }
+
+        ppu.write_register(0x2006, 0x28);
+        ppu.write_register(0x2006, 0x00);
+        ppu.step_scanline();
+
+        let pixel = |x: usize| ppu.framebuffer[x * 3..x * 3 + 3].to_vec();
+        // Dots 1-128 were drawn before the write, and the two tiles already in the shifters still come out after it
+        assert!((0..128).all(|x| pixel(x) == palette_rgb(0x30)));
+        assert!((160..256).all(|x| pixel(x) == palette_rgb(0x0F)));
+    }
+
+    #[test]
+    fn reset_clears_registers_but_keeps_memory() {
+        let mut ppu = ppu();
+        ppu.write_register(0x2006, 0x21);
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2007, 0x5A);
+        ppu.write_register(0x2003, 0x10);
+        ppu.write_register(0x2004, 0x77);
+        ppu.palette_table[3] = 0x16;
+        ppu.chr_rom[0] = 0xC3;
+        ppu.write_register(0x2000, 0x93);
+        ppu.write_register(0x2001, 0x1E);
+        ppu.write_register(0x2005, 0x7D);
+        ppu.frame = 7;
+        let vram_addr = ppu.vram_addr;
+
+        ppu.reset();
+        assert_eq!((ppu.control, ppu.mask, ppu.fine_x, ppu.temp_addr, ppu.frame), (0, 0, 0, 0, 0));
+        assert!(!ppu.write_toggle);
+        assert_eq!(ppu.vram_addr, vram_addr);
+        assert_eq!(ppu.read_vram(0x2100), 0x5A);
+        assert_eq!(ppu.oam_data[0x10], 0x77);
+        assert_eq!(ppu.palette_table[3], 0x16);
+        assert_eq!(ppu.chr_rom[0], 0xC3);
+
+        // A power cycle loses it all
+        ppu.power_on();
+        assert_eq!(ppu.read_vram(0x2100), 0x00);
+        assert_eq!(ppu.oam_data[0x10], 0x00);
+        assert_eq!(ppu.chr_rom[0], 0x00);
+    }
+
+    #[test]
+    fn write_only_registers_read_back_the_latch() {
+        let mut ppu = ppu();
+        ppu.write_register(0x2000, 0x5A);
+        assert_eq!(ppu.cpu_read(0x2000), 0x5A);
+
+        // Any register write refreshes it, and every write-only register returns it
+        ppu.write_register(0x2003, 0xC3);
+        for addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006] {
+            assert_eq!(ppu.cpu_read(addr), 0xC3, "${:04X}", addr);
+        }
+
+        // Readable registers drive the bus too, a $2004 read is what $2000 returns next
+        ppu.oam_data[0xC3] = 0x81;
+        assert_eq!(ppu.cpu_read(0x2004), 0x81);
+        assert_eq!(ppu.cpu_read(0x2000), 0x81);
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_raises_it() {
+        let mut ppu = self::ppu();
+        ppu.step_to_vblank();
+        assert!(ppu.status & 0x80 != 0 && !ppu.nmi_triggered);
+
+        ppu.write_register(0x2000, 0x80);
+        assert!(ppu.nmi_triggered);
+
+        // Disabling again before the CPU takes it cancels the pending NMI
+        ppu.write_register(0x2000, 0x00);
+        assert!(!ppu.nmi_triggered);
+
+        // Rewriting bit 7 while already set is not a new edge
+        ppu.write_register(0x2000, 0x80);
+        ppu.nmi_triggered = false;
+        ppu.write_register(0x2000, 0x80);
+        assert!(!ppu.nmi_triggered);
+
+        // Outside VBlank there is nothing to raise
+        ppu.write_register(0x2000, 0x00);
+        ppu.read_status();
+        ppu.write_register(0x2000, 0x80);
+        assert!(!ppu.nmi_triggered);
+    }
+
+    #[test]
+    fn chr_ram_tile_uploaded_through_2007_is_rendered() {
+        // A cartridge with no CHR ROM gets 8KB of CHR RAM
+        let mut image = test_image(&[]);
+        image[5] = 0;
+        image.truncate(image.len() - 0x2000);
+        let rom = Rom::from_bytes(&image).unwrap();
+        let mut ppu = PPU::new(rom.c_rom, rom.mirroring);
+        assert!(ppu.chr_ram);
+
+        // Tile 1: the low plane solid, the high plane clear, so every pixel is color 1
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2006, 0x10);
+        for byte in [0xFF; 8].into_iter().chain([0x00; 8]) {
+            ppu.write_register(0x2007, byte);
+        }
+        assert!((0x10..0x18).all(|addr| ppu.read_pattern(addr) == 0xFF));
+        assert!((0x18..0x20).all(|addr| ppu.read_pattern(addr) == 0x00));
+
+        ppu.vram[0..32].fill(0x01);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_register(0x2001, 0x0A);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+        assert_eq!(ppu.framebuffer[..WIDTH * 3], palette_rgb(0x30).repeat(WIDTH)[..]);
+    }
+
+    #[test]
+    fn chr_rom_ignores_2007_writes() {
+        let mut ppu = PPU::new(vec![0x55; 0x2000], Mirroring::HORIZONTAL);
+        ppu.write_register(0x2006, 0x00);
+        ppu.write_register(0x2006, 0x10);
+        ppu.write_register(0x2007, 0xAA);
+        assert_eq!(ppu.read_pattern(0x10), 0x55);
+    }
+
+    #[test]
+    fn status_flags_clear_together_on_pre_render_dot_1() {
+        let mut ppu = self::ppu();
+        ppu.step_to_vblank();
+        ppu.status |= 0x60;
+
+        while !(ppu.scanline == -1 && ppu.cycles == 0) {
+            ppu.step_dot();
+        }
+        assert_eq!(ppu.status & 0xE0, 0xE0);
+
+        ppu.step_dot();
+        assert_eq!((ppu.scanline, ppu.cycles), (-1, 1));
+        assert_eq!(ppu.status & 0xE0, 0);
+    }
+
+    #[test]
+    fn set_oam_is_decoded_by_sprite() {
+        let mut ppu = self::ppu();
+        let mut oam = [0xFF; 256];
+        oam[0..4].copy_from_slice(&[0x20, 0x05, 0x43, 0x80]);
+        oam[252..256].copy_from_slice(&[0xEF, 0xA0, 0x01, 0xF8]);
+        ppu.set_oam(oam);
+
+        assert_eq!(ppu.oam(), &oam);
+        assert_eq!(ppu.sprite(0), Sprite { y: 0x20, tile: 0x05, attr: 0x43, x: 0x80 });
+        assert_eq!(ppu.sprite(63), Sprite { y: 0xEF, tile: 0xA0, attr: 0x01, x: 0xF8 });
+
+        // The serial interface sees the same memory
+        ppu.write_register(0x2003, 0x01);
+        assert_eq!(ppu.read_register(0x2004), 0x05);
+    }
+
+    #[test]
+    fn injected_vram_and_oam_render_on_their_own() {
+        // Build the scene on one PPU and carry it to another through the dumps
+        let mut scene = self::ppu();
+        scene.vram[0..32].fill(0x01);
+        scene.vram[32..64].fill(0x02);
+        scene.palette_table[0] = 0x0F;
+        scene.palette_table[1] = 0x30;
+        scene.palette_table[3] = 0x16;
+        scene.palette_table[0x11] = 0x2A;
+        let mut oam = [0xFF; 256];
+        oam[0..4].copy_from_slice(&[0x3F, 0x01, 0x00, 0x80]);
+        scene.set_oam(oam);
+
+        let mut ppu = self::ppu();
+        ppu.load_vram(&scene.dump_vram()).unwrap();
+        ppu.load_oam(&scene.dump_oam()).unwrap();
+        assert_eq!(ppu.dump_vram(), scene.dump_vram());
+        assert_eq!(ppu.dump_oam(), oam.to_vec());
+
+        // CHR belongs to the cartridge: tile 1 is color 1 throughout, tile 2 color 3
+        ppu.chr_rom[0x10..0x18].fill(0xFF);
+        ppu.chr_rom[0x20..0x30].fill(0xFF);
+        ppu.write_register(0x2001, 0x1E);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+
+        let pixel = |x: usize, y: usize| ppu.framebuffer[(y * WIDTH + x) * 3..(y * WIDTH + x) * 3 + 3].to_vec();
+        assert_eq!(pixel(0, 0), palette_rgb(0x30));
+        assert_eq!(pixel(255, 7), palette_rgb(0x30));
+        assert_eq!(pixel(0, 8), palette_rgb(0x16));
+        assert_eq!(pixel(100, 100), palette_rgb(0x0F));
+        assert_eq!(pixel(0x80, 0x40), palette_rgb(0x2A));
+        assert_eq!(pixel(0x87, 0x47), palette_rgb(0x2A));
+        assert_eq!(pixel(0x88, 0x40), palette_rgb(0x0F));
+    }
+
+    #[test]
+    fn loads_of_the_wrong_size_are_rejected() {
+        let mut ppu = self::ppu();
+        assert!(ppu.load_vram(&[0; 16]).is_err());
+        assert!(ppu.load_oam(&[0; 255]).is_err());
+    }
+
+    #[test]
+    fn palette_read_fills_the_buffer_from_the_nametable_underneath() {
+        let mut ppu = self::ppu();
+        for (addr, data) in [(0x2F05u16, 0xAB), (0x3F05, 0x16), (0x2000, 0x77)] {
+            ppu.write_register(0x2006, (addr >> 8) as u8);
+            ppu.write_register(0x2006, addr as u8);
+            ppu.write_register(0x2007, data);
+        }
+
+        // The palette byte comes back straight away
+        ppu.write_register(0x2006, 0x3F);
+        ppu.write_register(0x2006, 0x05);
+        assert_eq!(ppu.cpu_read(0x2007), 0x16);
+
+        // The buffer now holds $2F05, which the next read hands over before catching up
+        ppu.write_register(0x2006, 0x20);
+        ppu.write_register(0x2006, 0x00);
+        assert_eq!(ppu.cpu_read(0x2007), 0xAB);
+        assert_eq!(ppu.cpu_read(0x2007), 0x77);
+    }
+
+    #[test]
+    fn mid_frame_nametable_switch_takes_effect_on_the_next_line() {
+        // Vertical mirroring keeps $2400 apart from $2000, only it is filled with the solid tile
+        let mut ppu = PPU::new(Vec::new(), Mirroring::VERTICAL);
+        ppu.chr_rom[0x10..0x18].fill(0xFF);
+        ppu.vram[0x400..0x7C0].fill(0x01);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu.write_register(0x2001, 0x0A);
+        ppu.step_to_vblank();
+
+        while !(ppu.scanline == 100 && ppu.cycles == 50) {
+            ppu.step_dot();
+        }
+        ppu.write_register(0x2000, 0x01);
+        assert_eq!(ppu.temp_addr & 0x0C00, 0x0400);
+        // The horizontal copy at dot 257 is what moves the renderer over
+        assert_eq!(ppu.vram_addr & 0x0400, 0);
+        ppu.step_scanline();
+        assert_eq!(ppu.vram_addr & 0x0400, 0x0400);
+        ppu.step_to_vblank();
+
+        let row = |y: usize| ppu.framebuffer[y * WIDTH * 3..(y + 1) * WIDTH * 3].to_vec();
+        assert_eq!(row(100), palette_rgb(0x0F).repeat(WIDTH));
+        assert_eq!(row(101), palette_rgb(0x30).repeat(WIDTH));
+        assert_eq!(row(239), palette_rgb(0x30).repeat(WIDTH));
+    }
+
+    #[test]
+    fn pal_file_replaces_the_master_palette() {
+        // A synthetic .pal file where color n is (n, 0x80, 0xFF - n)
+        let pal: Vec<u8> = (0..64u8).flat_map(|n| [n, 0x80, 0xFF - n]).collect();
+        let path = std::env::temp_dir().join(format!("nes-palette-test-{}.pal", std::process::id()));
+        std::fs::write(&path, &pal).unwrap();
+
+        let mut ppu = self::ppu();
+        ppu.load_palette(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        ppu.chr_rom[0] = 0xFF;
+        ppu.palette_table[1] = 0x2C;
+        assert_eq!(ppu.palette_color(0, 1), [0x2C, 0x80, 0xD3]);
+        assert_eq!(ppu.render_pattern_table(0, 0)[0..3], [0x2C, 0x80, 0xD3]);
+        // The built-in table is still there for anything that asks for it by name
+        assert_eq!(palette_rgb(0x2C), [0x38, 0xB4, 0xCC]);
+
+        // A short file is refused and the colors stay as they were
+        assert!(ppu.load_palette(&pal[..191]).is_err());
+        assert_eq!(ppu.palette_color(0, 1), [0x2C, 0x80, 0xD3]);
+    }
+
+    #[test]
+    fn position_and_frame_count_follow_the_counters() {
+        let mut ppu = self::ppu();
+        assert_eq!(ppu.position(), (ppu.scanline, ppu.cycles));
+        assert_eq!(ppu.frame_count(), 0);
+
+        let (line, dot) = ppu.position();
+        ppu.step_dots(3);
+        assert_eq!(ppu.position(), (line, dot + 3));
+
+        ppu.step_scanline();
+        assert_eq!(ppu.position(), (line + 1, 0));
+
+        // A frame is counted when the pre-render line comes around again
+        ppu.step_to_vblank();
+        while ppu.position() != (-1, 0) {
+            ppu.step_dot();
+            assert_eq!(ppu.position(), (ppu.scanline, ppu.cycles));
+        }
+        assert_eq!(ppu.frame_count(), 1);
+        assert_eq!(ppu.frame_count(), ppu.frame);
+    }
+
+    #[test]
+    fn back_priority_sprite_hides_behind_background_but_still_hits() {
+        let render = |attr: u8| {
+            let mut ppu = self::ppu();
+            // Solid background over the top 128 lines, transparent below
+            ppu.chr_rom[0x10..0x18].fill(0xFF);
+            ppu.chr_rom[0x28..0x30].fill(0xFF);
+            ppu.vram[0..16 * 32].fill(0x01);
+            ppu.palette_table[0] = 0x0F;
+            ppu.palette_table[1] = 0x30;
+            ppu.palette_table[0x12] = 0x16;
+            // Sprite 0 over the background, sprite 1 over the empty part, both tile 2 in color 2
+            let mut oam = [0xFF; 256];
+            oam[0..4].copy_from_slice(&[0x3F, 0x02, attr, 0x40]);
+            oam[4..8].copy_from_slice(&[0x9F, 0x02, attr, 0x40]);
+            ppu.set_oam(oam);
+            ppu.write_register(0x2001, 0x1E);
+            ppu.step_to_vblank();
+            ppu.step_to_vblank();
+            ppu
+        };
+        let pixel = |ppu: &PPU, x: usize, y: usize| ppu.framebuffer[(y * WIDTH + x) * 3..(y * WIDTH + x) * 3 + 3].to_vec();
+
+        let front = render(0x00);
+        assert_eq!(pixel(&front, 0x40, 0x40), palette_rgb(0x16));
+        assert_eq!(pixel(&front, 0x40, 0xA0), palette_rgb(0x16));
+        assert!(front.status & 0x40 != 0);
+
+        let behind = render(0x20);
+        assert_eq!(pixel(&behind, 0x40, 0x40), palette_rgb(0x30));
+        assert_eq!(pixel(&behind, 0x47, 0x47), palette_rgb(0x30));
+        // Where the background is transparent the sprite shows through
+        assert_eq!(pixel(&behind, 0x40, 0xA0), palette_rgb(0x16));
+        // Priority only picks the color, the hit is still detected
+        assert!(behind.status & 0x40 != 0);
+    }
+
+    #[test]
+    fn bulk_stepping_with_rendering_off_keeps_vblank_timing() {
+        let mut single = self::ppu();
+        let mut bulk = self::ppu();
+        for ppu in [&mut single, &mut bulk] {
+            ppu.write_register(0x2000, 0x80);
+        }
+
+        let state = |ppu: &PPU| (ppu.position(), ppu.status, ppu.nmi_triggered, ppu.frame_count());
+        let chunks = [1, 2, 3, 5, 113, 3, 700];
+        let mut vblanks = 0;
+        for (i, &dots) in chunks.iter().cycle().take(3000).enumerate() {
+            // Part way through, turn rendering on and check the two still agree
+            if i == 2000 {
+                single.write_register(0x2001, 0x0A);
+                bulk.write_register(0x2001, 0x0A);
+            }
+            for _ in 0..dots {
+                single.step_dot();
+            }
+            bulk.step_dots(dots);
+            assert_eq!(state(&single), state(&bulk), "after chunk {}", i);
+
+            if single.nmi_triggered {
+                vblanks += 1;
+                single.nmi_triggered = false;
+                bulk.nmi_triggered = false;
+            }
+        }
+        assert!(vblanks >= 3, "{}", vblanks);
+    }
+
+    #[test]
+    fn oam_data_reads_during_vblank_and_rendering() {
+        let mut ppu = self::ppu();
+        let mut oam = [0; 256];
+        oam[5] = 0x42;
+        ppu.set_oam(oam);
+        ppu.write_register(0x2001, 0x18);
+        ppu.write_register(0x2003, 0x05);
+
+        // In VBlank the stored byte comes back, and reading does not move the address
+        ppu.step_to_vblank();
+        assert_eq!(ppu.cpu_read(0x2004), 0x42);
+        assert_eq!(ppu.cpu_read(0x2004), 0x42);
+
+        // Dots 1-64 of a visible line read the $FF clearing secondary OAM
+        while !(ppu.scanline == 10 && ppu.cycles == 30) {
+            ppu.step_dot();
+        }
+        assert_eq!(ppu.cpu_read(0x2004), 0xFF);
+        ppu.step_dots(40);
+        assert_eq!(ppu.cpu_read(0x2004), 0x42);
+
+        // With rendering off the whole line reads the stored byte
+        ppu.write_register(0x2001, 0x00);
+        ppu.step_scanline();
+        ppu.step_dots(30);
+        ppu.write_register(0x2003, 0x05);
+        assert_eq!(ppu.cpu_read(0x2004), 0x42);
+    }
+
+    #[test]
+    fn oam_data_writes_land_at_consecutive_addresses() {
+        let mut ppu = self::ppu();
+        ppu.write_register(0x2003, 0xFE);
+        for data in [0x11, 0x22, 0x33, 0xFF] {
+            ppu.write_register(0x2004, data);
+        }
+        // The address wraps around the end of OAM
+        assert_eq!(ppu.oam()[0xFE..], [0x11, 0x22]);
+        assert_eq!(ppu.oam()[0..2], [0x33, 0xFF]);
+        assert_eq!(ppu.oam_addr, 0x02);
+
+        // Bits 2-4 of an attribute byte read back as 0
+        ppu.write_register(0x2004, 0xFF);
+        ppu.write_register(0x2003, 0x02);
+        assert_eq!(ppu.cpu_read(0x2004), 0xE3);
+
+        // During rendering the write is dropped and the address moves to the next sprite
+        ppu.write_register(0x2001, 0x18);
+        while !(ppu.scanline == 20 && ppu.cycles == 100) {
+            ppu.step_dot();
+        }
+        ppu.write_register(0x2003, 0x10);
+        ppu.write_register(0x2004, 0x99);
+        assert_eq!(ppu.oam()[0x10], 0x00);
+        assert_eq!(ppu.oam_addr, 0x14);
+    }
+
+    #[test]
+    fn tile_rewritten_in_vblank_shows_in_the_next_frame() {
+        let mut ppu = self::ppu();
+        let upload = |ppu: &mut PPU, planes: [u8; 2]| {
+            ppu.write_register(0x2006, 0x00);
+            ppu.write_register(0x2006, 0x10);
+            for byte in [planes[0]; 8].into_iter().chain([planes[1]; 8]) {
+                ppu.write_register(0x2007, byte);
+            }
+            // Point the scroll back at the top left nametable
+            ppu.write_register(0x2006, 0x00);
+            ppu.write_register(0x2006, 0x00);
+        };
+        upload(&mut ppu, [0xFF, 0x00]);
+        ppu.vram[0..32].fill(0x01);
+        ppu.palette_table[1] = 0x30;
+        ppu.palette_table[2] = 0x16;
+        ppu.palette_table[0x11] = 0x2A;
+        ppu.palette_table[0x12] = 0x21;
+        let mut oam = [0xFF; 256];
+        oam[0..4].copy_from_slice(&[0x3F, 0x01, 0x00, 0x80]);
+        ppu.set_oam(oam);
+        ppu.write_register(0x2001, 0x1E);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+
+        let pixel = |ppu: &PPU, x: usize, y: usize| ppu.framebuffer[(y * WIDTH + x) * 3..(y * WIDTH + x) * 3 + 3].to_vec();
+        assert_eq!(pixel(&ppu, 0, 0), palette_rgb(0x30));
+        assert_eq!(pixel(&ppu, 0x80, 0x40), palette_rgb(0x2A));
+
+        // Color 1 becomes color 2 for both the background and the sprite
+        upload(&mut ppu, [0x00, 0xFF]);
+        ppu.step_to_vblank();
+        assert_eq!(pixel(&ppu, 0, 0), palette_rgb(0x16));
+        assert_eq!(pixel(&ppu, 0x80, 0x40), palette_rgb(0x21));
+    }
+
+    #[test]
+    fn tile_1_is_fetched_from_chr_offset_16() {
+        let mut ppu = self::ppu();
+        // CHR offset 16 and the nametable byte 16 disagree, only the CHR pattern may show
+        ppu.chr_rom[16..24].fill(0xA5);
+        ppu.vram[0] = 0x01;
+        ppu.vram[16] = 0x00;
+        ppu.vram[0x7FF] = 0xFF;
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        assert_eq!(ppu.read_pattern(16), 0xA5);
+        // Addresses past $07FF are not folded back onto the first 2KB
+        ppu.chr_rom[0x810] = 0x3C;
+        assert_eq!(ppu.read_pattern(0x810), 0x3C);
+        assert_eq!(ppu.read_pattern(0x010), 0xA5);
+
+        ppu.write_register(0x2001, 0x0A);
+        ppu.step_to_vblank();
+        ppu.step_to_vblank();
+        let row: Vec<u8> = ppu.framebuffer[..8 * 3].to_vec();
+        let (on, off) = (palette_rgb(0x30), palette_rgb(0x0F));
+        let expected: Vec<u8> = [on, off, on, off, off, on, off, on].concat();
+        assert_eq!(row, expected);
+        // The rest of the line is tile 0, which is blank
+        assert!(ppu.framebuffer[8 * 3..WIDTH * 3].chunks(3).all(|rgb| rgb == off));
+    }
+}
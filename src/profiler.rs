@@ -0,0 +1,185 @@
+// Counts CPU bus reads and writes per 256 byte page, to show where a game spends its memory bandwidth.
+// The bus only holds a profiler once one is enabled, so emulation pays nothing for it otherwise.
+// The instruction profiler does the same for the CPU, counting how often each PC and opcode executes.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::opcodes::OPCODE_TABLE;
+
+const PAGES: usize = 256;
+
+// Named address ranges, in pages, used to summarise the per-page counts
+const REGIONS: [(&str, usize, usize); 6] = [
+    ("RAM", 0x00, 0x1F),
+    ("PPU registers", 0x20, 0x3F),
+    ("APU and I/O", 0x40, 0x40),
+    ("Expansion", 0x41, 0x5F),
+    ("SRAM", 0x60, 0x7F),
+    ("PRG-ROM", 0x80, 0xFF),
+];
+
+// Reads are counted through Cells since the bus reads through &self
+pub struct AccessProfiler {
+    reads: [Cell<u64>; PAGES],
+    writes: [u64; PAGES],
+}
+
+impl Default for AccessProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessProfiler {
+    pub fn new() -> Self {
+        AccessProfiler {
+            reads: std::array::from_fn(|_| Cell::new(0)),
+            writes: [0; PAGES],
+        }
+    }
+
+    pub fn record_read(&self, addr: u16) {
+        let page = &self.reads[(addr >> 8) as usize];
+        page.set(page.get() + 1);
+    }
+
+    pub fn record_write(&mut self, addr: u16) {
+        self.writes[(addr >> 8) as usize] += 1;
+    }
+
+    // Copy of the counts so far
+    pub fn stats(&self) -> AccessStats {
+        AccessStats {
+            reads: std::array::from_fn(|page| self.reads[page].get()),
+            writes: self.writes,
+        }
+    }
+}
+
+// Read and write counts indexed by page, the high byte of the address
+pub struct AccessStats {
+    pub reads: [u64; PAGES],
+    pub writes: [u64; PAGES],
+}
+
+impl AccessStats {
+    // Total reads and writes for each named region of the CPU address space
+    pub fn by_region(&self) -> Vec<(&'static str, u64, u64)> {
+        REGIONS
+            .iter()
+            .map(|&(name, first, last)| {
+                let reads = self.reads[first..=last].iter().sum();
+                let writes = self.writes[first..=last].iter().sum();
+                (name, reads, writes)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for AccessStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<14} {:>12} {:>12}", "Region", "Reads", "Writes")?;
+        for (name, reads, writes) in self.by_region() {
+            writeln!(f, "{:<14} {:>12} {:>12}", name, reads, writes)?;
+        }
+        Ok(())
+    }
+}
+
+// Executions per instruction address and per opcode, to find a game's hot loops
+pub struct InstructionProfiler {
+    pub pcs: HashMap<u16, u64>,
+    pub opcodes: [u64; 256],
+}
+
+impl Default for InstructionProfiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstructionProfiler {
+    pub fn new() -> Self {
+        InstructionProfiler {
+            pcs: HashMap::new(),
+            opcodes: [0; 256],
+        }
+    }
+
+    // Called by the CPU for every instruction it fetches
+    pub fn record(&mut self, pc: u16, code: u8) {
+        *self.pcs.entry(pc).or_insert(0) += 1;
+        self.opcodes[code as usize] += 1;
+    }
+
+    // The n most executed addresses, busiest first
+    pub fn top_pcs(&self, n: usize) -> Vec<(u16, u64)> {
+        let mut pcs: Vec<(u16, u64)> = self.pcs.iter().map(|(&pc, &count)| (pc, count)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs.truncate(n);
+        pcs
+    }
+
+    // The n most executed opcodes, busiest first, opcodes that never ran are left out
+    pub fn top_opcodes(&self, n: usize) -> Vec<(u8, u64)> {
+        let mut opcodes: Vec<(u8, u64)> = (0..=255u8)
+            .map(|code| (code, self.opcodes[code as usize]))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        opcodes.truncate(n);
+        opcodes
+    }
+
+    // Two tables of the n hottest addresses and opcodes
+    pub fn report(&self, n: usize) -> String {
+        let total: u64 = self.opcodes.iter().sum();
+        let percent = |count: u64| if total == 0 { 0.0 } else { count as f64 / total as f64 * 100.0 };
+
+        let mut out = format!("{:<8} {:>12} {:>7}\n", "PC", "Executed", "%");
+        for (pc, count) in self.top_pcs(n) {
+            out.push_str(&format!("${:04X}    {:>12} {:>6.2}%\n", pc, count, percent(count)));
+        }
+
+        out.push_str(&format!("\n{:<8} {:>12} {:>7}\n", "Opcode", "Executed", "%"));
+        for (code, count) in self.top_opcodes(n) {
+            let name = OPCODE_TABLE[code as usize].map(|op| op.name).unwrap_or("???");
+            out.push_str(&format!("{:02X} {:<5} {:>12} {:>6.2}%\n", code, name, count, percent(count)));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::test_cpu;
+
+    #[test]
+    fn loop_body_dominates_the_histogram() {
+        // Count X up through 256 values, then start over
+        let mut cpu = test_cpu("LDX #$00\nINX\nBNE $8002\nJMP $8000");
+        cpu.instruction_profiler = Some(InstructionProfiler::new());
+        for _ in 0..2000 {
+            cpu.step();
+        }
+
+        let profiler = cpu.instruction_profiler.as_ref().unwrap();
+        assert_eq!(profiler.opcodes.iter().sum::<u64>(), 2000);
+        let top = profiler.top_pcs(4);
+        let mut hottest: Vec<u16> = top.iter().map(|&(pc, _)| pc).take(2).collect();
+        hottest.sort();
+        assert_eq!(hottest, [0x8002, 0x8003]);
+        assert!(top[0].1 > 100 * profiler.pcs[&0x8000]);
+        let mut hottest: Vec<u8> = profiler.top_opcodes(2).iter().map(|&(code, _)| code).collect();
+        hottest.sort();
+        assert_eq!(hottest, [0xD0, 0xE8]);
+
+        let report = profiler.report(2);
+        assert!(report.contains("$8002"), "{}", report);
+        assert!(report.contains("E8 INX"), "{}", report);
+        assert!(!report.contains("$8000"), "{}", report);
+    }
+}
@@ -2,59 +2,405 @@
 // The former stored a game's video graphics data, the latter stored CPU instructions - the game's code
 // The later version of cartridges carried additional hardware (ROM and RAM) accessible through so-called mappers. 
 
+use serde::Deserialize;
+
+use crate::config::Region;
+use crate::crc::crc32;
+use crate::mapper;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PROM_PAGE_SIZE: usize = 16384;
 const CROM_PAGE_SIZE: usize = 8192;
+const TRAINER_SIZE: usize = 512;
+const ZIP_MAGIC: [u8; 2] = [b'P', b'K'];
+
+// Mappers the emulator can run, NROM only for now
+pub const SUPPORTED_MAPPERS: [u8; 1] = [0];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomError {
+    NotINes,
+    Nes2Unsupported,
+    UnsupportedMapper(u8),
+    Io(String),
+    Archive(String),
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RomError::NotINes => write!(f, "File is not in iNES file format"),
+            RomError::Nes2Unsupported => write!(f, "NES2.0 Format is not supported"),
+            RomError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+            RomError::Io(message) => write!(f, "{}", message),
+            RomError::Archive(message) => write!(f, "{}", message),
+        }
+    }
+}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOURSCREEN,
 }
 
+#[derive(Clone)]
 pub struct Rom {
     pub p_rom: Vec<u8>,
     pub c_rom: Vec<u8>,
+    // 512 bytes the cartridge expects at $7000-$71FF, empty when the image has no trainer
+    pub trainer: Vec<u8>,
     pub mapper: u8,
     pub mirroring: Mirroring,
+    // Battery backed cartridge RAM, the game expects $6000-$7FFF to survive power off
+    pub battery: bool,
+    // TV system from header byte 9, most dumps leave it at NTSC whatever the game
+    pub region: Region,
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        // First 4 bytes should be the NES Tag
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        Rom::from_bytes(raw)
+    }
+
+    // Parse an iNES image already in memory
+    pub fn from_bytes(raw: &[u8]) -> Result<Rom, RomError> {
+        let rom = Rom::parse(raw)?;
+        rom.check_mapper()?;
+        Ok(rom)
+    }
+
+    // Parse an iNES image without checking the mapper is supported, so a cartridge database can correct it first
+    pub fn parse(raw: &[u8]) -> Result<Rom, RomError> {
+        let header = Header::decode(raw)?;
+        if header.nes2 {
+            return Err(RomError::Nes2Unsupported);
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        let ines_ver = (raw[7] * 0b1111_0000) | raw[6] >> 4;
-        if ines_ver != 0 {
-            return Err("NES2.0 Format is not supported".to_string());
+        // A trainer sits between the header and PRG ROM
+        let prom_start = 16 + if header.trainer { TRAINER_SIZE } else { 0 };
+        let crom_start = prom_start + header.prg_size;
+
+        // A truncated image is rejected rather than sliced out of bounds
+        if raw.len() < crom_start + header.chr_size {
+            return Err(RomError::NotINes);
         }
 
-        // Set up mirroring type
-        let four_screen = raw[6] & 0b1000 != 0;
-        let vertical_mirroring = raw[6] & 0b1 != 0;
-        let mirroring = match(four_screen, vertical_mirroring) {
+        Ok(Rom {
+            p_rom: raw[prom_start..crom_start].to_vec(),
+            c_rom: raw[crom_start..(crom_start + header.chr_size)].to_vec(),
+            trainer: raw[16..prom_start].to_vec(),
+            mapper: header.mapper,
+            mirroring: header.mirroring,
+            battery: header.battery,
+            region: header.region,
+        })
+    }
+
+    pub fn check_mapper(&self) -> Result<(), RomError> {
+        if SUPPORTED_MAPPERS.contains(&self.mapper) {
+            Ok(())
+        } else {
+            Err(RomError::UnsupportedMapper(self.mapper))
+        }
+    }
+
+    // CRC-32 of PRG ROM followed by CHR ROM, the header is left out so a fixed header does not change it
+    pub fn crc32(&self) -> u32 {
+        crc32(&[self.p_rom.as_slice(), self.c_rom.as_slice()].concat())
+    }
+
+    // Load the single .nes file inside a zip archive on disk
+    pub fn from_zip(path: &str) -> Result<Rom, RomError> {
+        let bytes = std::fs::read(path).map_err(|e| RomError::Io(format!("Could not read {}: {}", path, e)))?;
+        Rom::from_bytes(&extract_nes_from_zip(&bytes)?)
+    }
+}
+
+// What the 16 byte header says about a cartridge, read without loading it so --info can explain why a ROM won't run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub nes2: bool,
+    pub mapper: u8,
+    // Sizes in bytes, a CHR size of 0 means the board has CHR RAM
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub region: Region,
+    // Whether the emulator can run the image, and if not why
+    pub status: Result<(), RomError>,
+}
+
+impl Header {
+    // The header's fields along with whether the whole image would load
+    pub fn read(raw: &[u8]) -> Result<Header, RomError> {
+        let header = Header::decode(raw)?;
+        Ok(Header { status: Rom::from_bytes(raw).map(|_| ()), ..header })
+    }
+
+    // The fields alone, this is the one place header bytes are decoded and Rom::parse builds on it
+    fn decode(raw: &[u8]) -> Result<Header, RomError> {
+        // First 4 bytes should be the NES Tag
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err(RomError::NotINes);
+        }
+
+        // Bits 2-3 of byte 7 read 0b10 for NES 2.0 headers
+        let nes2 = (raw[7] >> 2) & 0b11 == 2;
+        let mirroring = match (raw[6] & 0b1000 != 0, raw[6] & 0b1 != 0) {
             (true, _) => Mirroring::FOURSCREEN,
             (false, true) => Mirroring::VERTICAL,
             (false, false) => Mirroring::HORIZONTAL,
         };
+        // NES 2.0 moved the TV system to byte 12
+        let pal = if nes2 { raw[12] & 0b11 == 1 } else { raw[9] & 0b1 != 0 };
 
-        let prom_size = raw[4] as usize * PROM_PAGE_SIZE;
-        let crom_size = raw[5] as usize * CROM_PAGE_SIZE;
+        Ok(Header {
+            nes2,
+            mapper: (raw[7] & 0b1111_0000) | (raw[6] >> 4),
+            prg_size: raw[4] as usize * PROM_PAGE_SIZE,
+            chr_size: raw[5] as usize * CROM_PAGE_SIZE,
+            mirroring,
+            battery: raw[6] & 0b10 != 0,
+            trainer: raw[6] & 0b100 != 0,
+            region: if pal { Region::PAL } else { Region::NTSC },
+            status: Ok(()),
+        })
+    }
+}
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        writeln!(f, "Format:    {}", if self.nes2 { "NES 2.0" } else { "iNES" })?;
+        writeln!(f, "Mapper:    {} ({})", self.mapper, mapper::name(self.mapper))?;
+        writeln!(f, "PRG ROM:   {}KB", self.prg_size / 1024)?;
+        if self.chr_size == 0 {
+            writeln!(f, "CHR ROM:   none, the board has CHR RAM")?;
+        } else {
+            writeln!(f, "CHR ROM:   {}KB", self.chr_size / 1024)?;
+        }
+        writeln!(f, "Mirroring: {:?}", self.mirroring)?;
+        writeln!(f, "Battery:   {}", yes_no(self.battery))?;
+        writeln!(f, "Trainer:   {}", yes_no(self.trainer))?;
+        writeln!(f, "Region:    {:?}", self.region)?;
+        match &self.status {
+            Ok(()) => write!(f, "Status:    OK"),
+            Err(e) => write!(f, "Status:    {}", e),
+        }
+    }
+}
 
-        let prom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let crom_start = prom_start + prom_size;
+// Read a ROM image from disk, unpacking it first when the file is a zip archive
+pub fn read_rom_file(path: &str) -> Result<Vec<u8>, RomError> {
+    let bytes = std::fs::read(path).map_err(|e| RomError::Io(format!("Could not read {}: {}", path, e)))?;
 
-        Ok(Rom {
-            p_rom: raw[prom_start..(prom_start + prom_size)].to_vec(),
-            c_rom: raw[crom_start..(crom_start + crom_size)].to_vec(),
-            mapper: mapper,
-            mirroring: mirroring,
-        })
+    if path.to_ascii_lowercase().ends_with(".zip") || bytes.starts_with(&ZIP_MAGIC) {
+        extract_nes_from_zip(&bytes)
+    } else {
+        Ok(bytes)
     }
-}
\ No newline at end of file
+}
+
+// Pull the single .nes file out of a zip archive
+pub fn extract_nes_from_zip(bytes: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| RomError::Archive(format!("Could not open zip archive: {}", e)))?;
+
+    let roms: Vec<String> = archive
+        .file_names()
+        .filter(|name| name.to_ascii_lowercase().ends_with(".nes"))
+        .map(|name| name.to_string())
+        .collect();
+
+    let name = match roms.as_slice() {
+        [name] => name,
+        [] => return Err(RomError::Archive("Zip archive does not contain a .nes file".to_string())),
+        _ => return Err(RomError::Archive(format!("Zip archive contains more than one .nes file: {}", roms.join(", ")))),
+    };
+
+    let mut file = archive.by_name(name).map_err(|e| RomError::Archive(format!("Could not read {}: {}", name, e)))?;
+    let mut raw = Vec::new();
+    std::io::Read::read_to_end(&mut file, &mut raw).map_err(|e| RomError::Archive(format!("Could not extract {}: {}", name, e)))?;
+
+    Ok(raw)
+}
+
+// A 32KB NROM image with the program at $8000 and the reset vector pointing at it, for tests elsewhere in the crate
+// NMI and IRQ go to an RTI at $FFF0 so an interrupt returns straight to the program
+#[cfg(test)]
+pub(crate) fn test_image(program: &[u8]) -> Vec<u8> {
+    let mut prg = vec![0xEA; 2 * PROM_PAGE_SIZE];
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x7FF0] = 0x40;
+    prg[0x7FFA..].copy_from_slice(&[0xF0, 0xFF, 0x00, 0x80, 0xF0, 0xFF]);
+
+    let mut raw = [NES_TAG.as_slice(), &[2, 1], &[0; 10]].concat();
+    raw.extend(prg);
+    raw.extend(vec![0; CROM_PAGE_SIZE]);
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use zip::write::SimpleFileOptions;
+    use crate::bus::Bus;
+    use crate::ppu::PPU;
+
+    #[test]
+    fn header_summary_lists_mapper_and_sizes() {
+        let mut raw = test_image(&[]);
+        // Vertical mirroring, battery, and PAL
+        raw[6] = 0b0000_0011;
+        raw[9] = 0b1;
+
+        let summary = Header::read(&raw).unwrap().to_string();
+        assert!(summary.contains("Format:    iNES"), "{}", summary);
+        assert!(summary.contains("Mapper:    0 (NROM)"), "{}", summary);
+        assert!(summary.contains("PRG ROM:   32KB"), "{}", summary);
+        assert!(summary.contains("CHR ROM:   8KB"), "{}", summary);
+        assert!(summary.contains("Mirroring: VERTICAL"), "{}", summary);
+        assert!(summary.contains("Battery:   yes"), "{}", summary);
+        assert!(summary.contains("Region:    PAL"), "{}", summary);
+        assert!(summary.ends_with("Status:    OK"), "{}", summary);
+    }
+
+    #[test]
+    fn rom_takes_its_fields_from_the_header() {
+        let mut raw = test_image(&[]);
+        raw[6] = 0b0000_1010;
+        raw[9] = 0b1;
+
+        let header = Header::read(&raw).unwrap();
+        let rom = Rom::from_bytes(&raw).unwrap();
+        assert_eq!(rom.mapper, header.mapper);
+        assert_eq!(rom.mirroring, header.mirroring);
+        assert_eq!(rom.battery, header.battery);
+        assert_eq!(rom.region, header.region);
+        assert_eq!(rom.p_rom.len(), header.prg_size);
+        assert_eq!(rom.c_rom.len(), header.chr_size);
+    }
+
+    #[test]
+    fn header_explains_an_image_that_cannot_run() {
+        let mut raw = test_image(&[]);
+        raw[6] = 0x40;
+
+        let header = Header::read(&raw).unwrap();
+        assert_eq!(header.mapper, 4);
+        assert_eq!(header.status, Err(RomError::UnsupportedMapper(4)));
+        assert!(header.to_string().ends_with("Status:    Mapper 4 is not supported"));
+
+        raw[7] = 0b1000;
+        let header = Header::read(&raw).unwrap();
+        assert!(header.nes2);
+        assert_eq!(header.status, Err(RomError::Nes2Unsupported));
+    }
+
+    #[test]
+    fn unsupported_mapper_is_a_typed_error() {
+        let mut raw = test_image(&[]);
+        raw[6] = 0x40;
+        raw[7] = 0x20;
+
+        assert_eq!(Rom::from_bytes(&raw).err(), Some(RomError::UnsupportedMapper(0x24)));
+        assert_eq!(RomError::UnsupportedMapper(0x24).to_string(), "Mapper 36 is not supported");
+
+        // Parsing alone keeps the mapper number so it can be reported or corrected
+        let mut rom = Rom::parse(&raw).unwrap();
+        assert_eq!(rom.mapper, 0x24);
+        assert_eq!(rom.check_mapper(), Err(RomError::UnsupportedMapper(0x24)));
+        rom.mapper = SUPPORTED_MAPPERS[0];
+        assert_eq!(rom.check_mapper(), Ok(()));
+    }
+
+    // A zip archive built in memory holding the given files
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for &(name, data) in files {
+            writer.start_file(name, SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated)).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn zipped_rom_loads_like_the_plain_image() {
+        let raw = test_image(&[0xA9, 0x42]);
+        let archive = zip(&[("readme.txt", b"hello"), ("Game (World).NES", &raw)]);
+        assert!(archive.starts_with(&ZIP_MAGIC));
+
+        assert_eq!(extract_nes_from_zip(&archive).unwrap(), raw);
+        let rom = Rom::from_bytes(&extract_nes_from_zip(&archive).unwrap()).unwrap();
+        assert_eq!(rom.p_rom[0..2], [0xA9, 0x42]);
+
+        // On disk it is recognised by its magic number whatever the extension
+        let path = std::env::temp_dir().join(format!("nes-zip-test-{}.bin", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        let loaded = read_rom_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), raw);
+    }
+
+    #[test]
+    fn zip_must_hold_exactly_one_rom() {
+        let raw = test_image(&[]);
+        let none = zip(&[("readme.txt", b"hello")]);
+        let two = zip(&[("a.nes", &raw), ("b.nes", &raw)]);
+
+        assert_eq!(extract_nes_from_zip(&none), Err(RomError::Archive("Zip archive does not contain a .nes file".to_string())));
+        assert_eq!(extract_nes_from_zip(&two), Err(RomError::Archive("Zip archive contains more than one .nes file: a.nes, b.nes".to_string())));
+        assert!(matches!(extract_nes_from_zip(b"PK not really"), Err(RomError::Archive(_))));
+    }
+
+    #[test]
+    fn trainer_sits_between_the_header_and_prg() {
+        let mut raw = test_image(&[0xA9, 0x42]);
+        raw[6] |= 0x04;
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8).collect();
+        raw.splice(16..16, trainer.iter().cloned());
+
+        let rom = Rom::from_bytes(&raw).unwrap();
+        assert_eq!(rom.trainer, trainer);
+        assert_eq!(rom.p_rom.len(), 2 * PROM_PAGE_SIZE);
+        assert_eq!(rom.p_rom[0..3], [0xA9, 0x42, 0xEA]);
+        assert_eq!(rom.c_rom.len(), CROM_PAGE_SIZE);
+
+        // The bus places it at $7000-$71FF
+        let bus = Bus::new(Rc::new(RefCell::new(PPU::new(rom.c_rom.clone(), rom.mirroring))), rom);
+        assert_eq!(bus.dump_range(0x7000, 0x71FF), trainer);
+        assert_eq!(bus.peek(0x7200), 0x00);
+
+        // Without the flag the same bytes are read as PRG
+        raw[6] &= !0x04;
+        assert_eq!(Rom::from_bytes(&raw).unwrap().p_rom[0..3], [0x00, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn roms_load_from_a_slice_or_a_zip_on_disk() {
+        // Any slice will do, here one in the middle of a bigger buffer
+        let raw = test_image(&[0xA9, 0x42]);
+        let buffer = [b"junk".as_slice(), &raw, b"more junk"].concat();
+        let rom = Rom::new(&buffer[4..4 + raw.len()]).unwrap();
+        assert_eq!(rom.crc32(), Rom::from_bytes(&raw).unwrap().crc32());
+        assert_eq!(rom.p_rom[0..2], [0xA9, 0x42]);
+        // A slice cut short is refused rather than read past its end
+        assert!(matches!(Rom::new(&raw[..raw.len() - 1]), Err(RomError::NotINes)));
+
+        let path = std::env::temp_dir().join(format!("nes-from-zip-test-{}.zip", std::process::id()));
+        std::fs::write(&path, zip(&[("roms/game.nes", &raw)])).unwrap();
+        let zipped = Rom::from_zip(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(zipped.unwrap().crc32(), rom.crc32());
+
+        assert!(matches!(Rom::from_zip(path.to_str().unwrap()), Err(RomError::Io(_))));
+    }
+}
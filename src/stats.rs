@@ -0,0 +1,73 @@
+// Emulation speed counter enabled with --stats
+// Counts frames over one second windows. Speed is emulated time against wall-clock time, so 100% is the console's
+// own pace, and headroom compares the time spent emulating each frame with the frame budget, so 200% headroom
+// means the emulator could run twice as fast as it needs to.
+
+use std::time::{Duration, Instant};
+
+pub struct FrameStats {
+    frame_time: Duration,
+    window_start: Instant,
+    frames: u32,
+    busy: Duration,
+}
+
+impl FrameStats {
+    pub fn new(frame_time: Duration) -> Self {
+        FrameStats {
+            frame_time,
+            window_start: Instant::now(),
+            frames: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    // Count a finished frame and the time spent emulating and presenting it
+    // Returns a summary once per second
+    pub fn record_frame(&mut self, work: Duration) -> Option<String> {
+        self.frames += 1;
+        self.busy += work;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return None;
+        }
+
+        let summary = summary(self.frames, elapsed, self.busy, self.frame_time);
+        self.window_start = Instant::now();
+        self.frames = 0;
+        self.busy = Duration::ZERO;
+
+        Some(summary)
+    }
+}
+
+// FPS, speed, and headroom for a window of frames, each frame standing for frame_time of emulated time
+fn summary(frames: u32, elapsed: Duration, busy: Duration, frame_time: Duration) -> String {
+    let emulated = (frame_time * frames).as_secs_f64();
+    let fps = frames as f64 / elapsed.as_secs_f64();
+    let speed = emulated / elapsed.as_secs_f64() * 100.0;
+    let headroom = if busy.is_zero() { 0.0 } else { emulated / busy.as_secs_f64() * 100.0 };
+
+    format!("{:.1} FPS, {:.0}% speed, {:.0}% headroom", fps, speed, headroom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    #[test]
+    fn speed_is_emulated_time_over_wall_time() {
+        // 62.5 frames of 16ms is one second, at full speed with each frame taking 4ms of work
+        assert_eq!(summary(125, Duration::from_secs(2), FRAME * 125 / 4, FRAME), "62.5 FPS, 100% speed, 400% headroom");
+        // Half the frames in the same time is half speed, however little work they took
+        assert_eq!(summary(62, Duration::from_secs(2), FRAME * 62 / 4, FRAME), "31.0 FPS, 50% speed, 400% headroom");
+    }
+
+    #[test]
+    fn headroom_is_zero_without_work() {
+        assert_eq!(summary(10, Duration::from_secs(1), Duration::ZERO, FRAME), "10.0 FPS, 16% speed, 0% headroom");
+    }
+}
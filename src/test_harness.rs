@@ -0,0 +1,176 @@
+// The $6000 reporting protocol used by blargg's test ROMs, run with --test-rom
+// A test writes $80 to $6000 while it runs, the signature DE B0 61 to $6001-$6003,
+// and a null terminated message from $6004. Its final result code then lands in $6000, 0 meaning passed.
+// The harness only watches writes, the bus enables it for test runs so ordinary games writing to
+// cartridge RAM are never mistaken for a finished test.
+
+use crate::machine::Machine;
+use crate::rom::Rom;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_START: u16 = 0x6004;
+// Window the harness keeps a copy of, the message has to fit in it
+const WINDOW_END: u16 = 0x60FF;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestStatus {
+    // No result yet, either the test has not started or it is still running
+    Running,
+    // The test asked to be reset, the machine should reset after a short delay and keep going
+    NeedsReset,
+    Passed(String),
+    Failed(u8, String),
+}
+
+pub struct TestHarness {
+    window: [u8; (WINDOW_END - STATUS + 1) as usize],
+    pub status: TestStatus,
+}
+
+impl TestHarness {
+    pub fn new() -> Self {
+        TestHarness {
+            window: [0; (WINDOW_END - STATUS + 1) as usize],
+            status: TestStatus::Running,
+        }
+    }
+
+    // Called by the bus for every CPU write
+    pub fn observe_write(&mut self, addr: u16, data: u8) {
+        if !(STATUS..=WINDOW_END).contains(&addr) {
+            return;
+        }
+        self.window[(addr - STATUS) as usize] = data;
+
+        // Result codes only count once the test has identified itself
+        if addr != STATUS || self.window[1..4] != SIGNATURE {
+            return;
+        }
+        self.status = match data {
+            STATUS_RUNNING => TestStatus::Running,
+            STATUS_NEEDS_RESET => TestStatus::NeedsReset,
+            0x00 => TestStatus::Passed(self.message()),
+            code => TestStatus::Failed(code, self.message()),
+        };
+    }
+
+    // The text written from $6004 up to its null terminator
+    pub fn message(&self) -> String {
+        let text = &self.window[(TEXT_START - STATUS) as usize..];
+        let end = text.iter().position(|&byte| byte == 0).unwrap_or(text.len());
+        String::from_utf8_lossy(&text[..end]).into_owned()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status, TestStatus::Passed(_) | TestStatus::Failed(_, _))
+    }
+}
+
+impl Default for TestHarness {
+    fn default() -> Self {
+        TestHarness::new()
+    }
+}
+
+// Run a test ROM headlessly until it reports a result or max_frames pass
+pub fn run(rom: Rom, max_frames: usize) -> TestStatus {
+    let mut machine = Machine::new(rom);
+    machine.cpu.bus.test_harness = Some(TestHarness::new());
+
+    for _ in 0..max_frames {
+        machine.step_frame();
+
+        let status = match machine.cpu.bus.test_harness.as_ref() {
+            Some(harness) => harness.status.clone(),
+            None => TestStatus::Running,
+        };
+        match status {
+            TestStatus::Running => {}
+            TestStatus::NeedsReset => {
+                // The message and signature survive a reset, like the cartridge RAM they were written to
+                machine.reset();
+                if let Some(harness) = machine.cpu.bus.test_harness.as_mut() {
+                    harness.status = TestStatus::Running;
+                }
+            }
+            finished => return finished,
+        }
+    }
+
+    TestStatus::Running
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::rom::test_image;
+
+    fn identify(harness: &mut TestHarness, message: &str) {
+        harness.observe_write(STATUS, STATUS_RUNNING);
+        for (i, &byte) in SIGNATURE.iter().enumerate() {
+            harness.observe_write(STATUS + 1 + i as u16, byte);
+        }
+        for (i, byte) in message.bytes().chain(std::iter::once(0)).enumerate() {
+            harness.observe_write(TEXT_START + i as u16, byte);
+        }
+    }
+
+    #[test]
+    fn protocol_bytes_report_passed() {
+        let mut harness = TestHarness::new();
+        identify(&mut harness, "Passed\n");
+        assert_eq!(harness.status, TestStatus::Running);
+        assert!(!harness.is_finished());
+
+        harness.observe_write(STATUS, 0x00);
+        assert_eq!(harness.status, TestStatus::Passed("Passed\n".to_string()));
+        assert!(harness.is_finished());
+    }
+
+    #[test]
+    fn result_codes_report_failed_and_reset() {
+        let mut harness = TestHarness::new();
+        identify(&mut harness, "bad flags");
+        harness.observe_write(STATUS, STATUS_NEEDS_RESET);
+        assert_eq!(harness.status, TestStatus::NeedsReset);
+        harness.observe_write(STATUS, 0x03);
+        assert_eq!(harness.status, TestStatus::Failed(3, "bad flags".to_string()));
+    }
+
+    #[test]
+    fn status_without_the_signature_is_ignored() {
+        let mut harness = TestHarness::new();
+        harness.observe_write(STATUS, 0x00);
+        harness.observe_write(STATUS, 0x01);
+        assert_eq!(harness.status, TestStatus::Running);
+    }
+
+    #[test]
+    fn run_returns_the_result_a_rom_writes() {
+        // Identify, write "ok", then report code 0 and spin
+        let program = assemble("
+            LDA #$80
+            STA $6000
+            LDA #$DE
+            STA $6001
+            LDA #$B0
+            STA $6002
+            LDA #$61
+            STA $6003
+            LDA #$6F
+            STA $6004
+            LDA #$6B
+            STA $6005
+            LDA #$00
+            STA $6006
+            STA $6000
+            JMP $8026
+        ").unwrap();
+        let rom = Rom::from_bytes(&test_image(&program)).unwrap();
+        assert_eq!(run(rom, 5), TestStatus::Passed("ok".to_string()));
+    }
+}
@@ -2,15 +2,18 @@ use crate::cpu::AddressingMode;
 use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 
 pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OPCode> = *opcodes::OPCodes_MAP;
-
     let code = cpu.mem_read(cpu.register_pc);
-    let ops = opscodes.get(&code).unwrap();
-
     let begin = cpu.register_pc;
+
+    // The KIL/JAM bytes have no table entry, lenient mode runs them as a 1 byte NOP
+    let Some(ops) = opcodes::OPCODE_TABLE[code as usize] else {
+        return with_registers(format!("{:04x}  {:02x}        ???", begin, code), cpu);
+    };
+
     let mut hex_dump = vec![];
     hex_dump.push(code);
 
@@ -18,7 +21,8 @@ pub fn trace(cpu: &mut CPU) -> String {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
             let addr = cpu.get_absolute_address(&ops.mode, begin + 1, false);
-            (addr, cpu.mem_read(addr))
+            // Peek so tracing an I/O register read does not trigger its side effects
+            (addr, cpu.bus.peek(addr))
         }
     };
 
@@ -82,15 +86,7 @@ pub fn trace(cpu: &mut CPU) -> String {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_16(address)
-                        };
-
-                        // let jmp_addr = cpu.mem_read_u16(address);
+                        let jmp_addr = cpu.read_indirect_buggy(address);
                         format!("(${:04x}) = {:04x}", address, jmp_addr)
                     } else {
                         format!("${:04x}", address)
@@ -123,9 +119,119 @@ pub fn trace(cpu: &mut CPU) -> String {
         .trim()
         .to_string();
 
+    with_registers(asm_str, cpu)
+}
+
+// The disassembly padded out and followed by the registers, in nestest.log's upper case
+fn with_registers(asm_str: String, cpu: &CPU) -> String {
     format!(
         "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.flags.bits, cpu.register_sp,
+        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.flags.as_byte(), cpu.register_sp,
     )
     .to_ascii_uppercase()
-}
\ No newline at end of file
+}
+
+// Writes one trace line per executed instruction to a file, in the same format as nestest.log
+pub struct TraceLog {
+    writer: BufWriter<File>,
+    lines: usize,
+    max_lines: Option<usize>,
+}
+
+impl TraceLog {
+    // Open a trace file, logging stops once max_lines lines have been written
+    pub fn create(path: &str, max_lines: Option<usize>) -> Result<TraceLog, String> {
+        let file = File::create(path).map_err(|e| format!("Could not create trace file {}: {}", path, e))?;
+
+        Ok(TraceLog {
+            writer: BufWriter::new(file),
+            lines: 0,
+            max_lines,
+        })
+    }
+
+    // Append the trace line for the instruction about to execute
+    pub fn log(&mut self, cpu: &mut CPU) {
+        if self.is_full() {
+            return;
+        }
+
+        if let Err(e) = writeln!(self.writer, "{}", trace(cpu)) {
+            println!("Failed to write trace: {}", e);
+            self.max_lines = Some(self.lines);
+            return;
+        }
+
+        self.lines += 1;
+        if self.is_full() {
+            println!("Trace reached {} lines, logging stopped", self.lines);
+            self.flush();
+        }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            println!("Failed to save trace: {}", e);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_lines.is_some_and(|max| self.lines >= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::test_cpu;
+
+    // Trace n instructions of a counting loop and return the file's lines
+    fn trace_lines(n: usize, max_lines: Option<usize>) -> Vec<String> {
+        let mut cpu = test_cpu("INX\nINY\nJMP $8000");
+
+        let path = std::env::temp_dir().join(format!("nes-trace-test-{}-{}.log", std::process::id(), n));
+        let mut log = TraceLog::create(path.to_str().unwrap(), max_lines).unwrap();
+        for _ in 0..n {
+            log.log(&mut cpu);
+            cpu.step();
+        }
+        log.flush();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn one_line_per_instruction() {
+        let lines = trace_lines(10, None);
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("8000  E8"), "{}", lines[0]);
+        assert!(lines[2].starts_with("8002  4C 00 80  JMP $8000"), "{}", lines[2]);
+        assert!(lines[3].starts_with("8000  E8"), "{}", lines[3]);
+    }
+
+    #[test]
+    fn logging_stops_at_the_line_cap() {
+        assert_eq!(trace_lines(12, Some(4)).len(), 4);
+    }
+
+    #[test]
+    fn trace_shows_the_target_jmp_indirect_takes() {
+        // The vector sits at the end of a page, so its high byte comes from $0200, not $0300
+        let mut cpu = test_cpu("JMP ($02FF)");
+        cpu.mem_write(0x02FF, 0x34);
+        cpu.mem_write(0x0200, 0x12);
+        cpu.mem_write(0x0300, 0x56);
+
+        assert_eq!(cpu.read_indirect_buggy(0x02FF), 0x1234);
+        let line = trace(&mut cpu);
+        assert!(line.contains("JMP ($02FF) = 1234"), "{}", line);
+        cpu.step();
+        assert_eq!(cpu.register_pc, 0x1234);
+    }
+}
@@ -0,0 +1,173 @@
+// Per-instruction checking for differential fuzzing against another 6502 core.
+// After every instruction the watch checks a few invariants that hold for any correct CPU:
+// the unused status bit stays set, the stack pointer moves by exactly what the opcode pushes or pulls,
+// and the PC lands after the instruction unless the opcode changes the flow of control.
+// An optional oracle closure then gets the registers from before the instruction and the CPU after it,
+// so a reference implementation stepped in lockstep can compare its own result.
+//
+// The machine holds the watch in an `Option`, so without one emulation only pays for checking it before and after each instruction.
+// The first failure is kept along with the registers around it, later instructions are still checked and counted.
+
+use crate::cpu::CPU;
+use crate::history::Registers;
+use crate::opcodes::OPCODE_TABLE;
+
+// Compares the CPU after an instruction against a reference, given the registers from before it
+pub type Oracle = Box<dyn FnMut(&Registers, &CPU) -> Result<(), String>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchFailure {
+    pub before: Registers,
+    pub after: Registers,
+    pub message: String,
+}
+
+pub struct Watch {
+    oracle: Option<Oracle>,
+    // Registers and opcode from before the instruction being run, and whether an IRQ was about to be taken
+    before: Option<(Registers, u8, bool)>,
+    pub checked: u64,
+    pub failure: Option<WatchFailure>,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Watch::new()
+    }
+}
+
+impl Watch {
+    pub fn new() -> Self {
+        Watch { oracle: None, before: None, checked: 0, failure: None }
+    }
+
+    pub fn with_oracle(oracle: Oracle) -> Self {
+        Watch { oracle: Some(oracle), ..Watch::new() }
+    }
+
+    // Called right before the CPU runs an instruction
+    pub fn begin_instruction(&mut self, cpu: &CPU) {
+        let code = cpu.bus.peek(cpu.register_pc);
+        let irq = cpu.bus.irq_line() && !cpu.flags.int();
+        self.before = Some((Registers::capture(cpu), code, irq));
+    }
+
+    // Called once the instruction has run, before anything else (like an NMI) touches the CPU
+    pub fn end_instruction(&mut self, cpu: &CPU) {
+        let Some((before, code, irq)) = self.before.take() else {
+            return;
+        };
+        self.checked += 1;
+
+        let result = check_invariants(&before, code, irq, cpu).and_then(|_| match self.oracle.as_mut() {
+            Some(oracle) => oracle(&before, cpu),
+            None => Ok(()),
+        });
+        if let Err(message) = result {
+            if self.failure.is_none() {
+                self.failure = Some(WatchFailure { before, after: Registers::capture(cpu), message });
+            }
+        }
+    }
+}
+
+fn check_invariants(before: &Registers, code: u8, irq: bool, cpu: &CPU) -> Result<(), String> {
+    // BRK stops the CPU where it is instead of taking the interrupt, there is nothing to check
+    if code == 0x00 && !irq {
+        return Ok(());
+    }
+    if cpu.flags.as_byte() & 0x20 == 0 {
+        return Err(format!("Unused status bit cleared after opcode {:02X}", code));
+    }
+    if cpu.cycles <= before.cycles {
+        return Err(format!("Opcode {:02X} took no cycles", code));
+    }
+
+    // An IRQ taken first pushes 3 bytes and runs the handler's first instruction instead, opcodes
+    // missing from the table run as NOPs in lenient mode, neither can be checked against the opcode
+    let Some(opcode) = OPCODE_TABLE[code as usize] else {
+        return Ok(());
+    };
+    if irq {
+        return Ok(());
+    }
+
+    let expected_sp = match opcode.name {
+        "PHA" | "PHP" => before.sp.wrapping_sub(1),
+        "PLA" | "PLP" => before.sp.wrapping_add(1),
+        "JSR" => before.sp.wrapping_sub(2),
+        "RTS" => before.sp.wrapping_add(2),
+        "RTI" => before.sp.wrapping_add(3),
+        "TXS" => cpu.register_x,
+        "*TAS" => cpu.register_a & cpu.register_x,
+        // LAS loads M & SP into SP, A and X, so A holds the new SP
+        "*LAS" => cpu.register_a,
+        _ => before.sp,
+    };
+    if cpu.register_sp != expected_sp {
+        return Err(format!("{} at {:04X} left SP at {:02X}, expected {:02X}", opcode.name, before.pc, cpu.register_sp, expected_sp));
+    }
+
+    let next = before.pc.wrapping_add(opcode.len as u16);
+    let pc_ok = match opcode.name {
+        "JMP" | "JSR" | "RTS" | "RTI" => true,
+        // A branch either falls through or lands within reach of the next instruction
+        "BPL" | "BMI" | "BVC" | "BVS" | "BCC" | "BCS" | "BNE" | "BEQ" => {
+            cpu.register_pc.wrapping_sub(next).wrapping_add(128) < 256
+        }
+        _ => cpu.register_pc == next,
+    };
+    if !pc_ok {
+        return Err(format!("{} at {:04X} left PC at {:04X}, expected {:04X}", opcode.name, before.pc, cpu.register_pc, next));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::machine::Machine;
+    use crate::rom::{test_image, Rom};
+
+    // A reference for the one instruction that changes X in an INX, INY, JMP loop
+    fn inx_oracle(step: u8) -> Oracle {
+        Box::new(move |before, cpu| {
+            let expected = if before.pc == 0x8000 { before.x.wrapping_add(step) } else { before.x };
+            if cpu.register_x == expected {
+                Ok(())
+            } else {
+                Err(format!("X is {:02X}, reference has {:02X}", cpu.register_x, expected))
+            }
+        })
+    }
+
+    fn watched(oracle: Oracle) -> Machine {
+        let program = assemble("INX\nINY\nJMP $8000").unwrap();
+        let mut machine = Machine::new(Rom::from_bytes(&test_image(&program)).unwrap());
+        machine.set_watch(Some(Watch::with_oracle(oracle)));
+        machine.step_frame();
+        machine
+    }
+
+    #[test]
+    fn matching_oracle_passes_every_instruction() {
+        let machine = watched(inx_oracle(1));
+        let watch = machine.watch().unwrap();
+        assert!(watch.checked > 1000, "{}", watch.checked);
+        assert_eq!(watch.failure, None);
+    }
+
+    #[test]
+    fn diverging_oracle_is_caught_on_the_first_instruction() {
+        let machine = watched(inx_oracle(2));
+        let watch = machine.watch().unwrap();
+        let failure = watch.failure.as_ref().unwrap();
+        assert_eq!(failure.before.pc, 0x8000);
+        assert_eq!((failure.before.x, failure.after.x), (0x00, 0x01));
+        assert_eq!(failure.message, "X is 01, reference has 02");
+        // Checking carries on after the first failure, which is the one kept
+        assert!(watch.checked > 1000);
+    }
+}
@@ -0,0 +1,92 @@
+// The Zapper light gun, plugged into the second controller port and read through $4017.
+// Bit 3 is the light sensor, 0 while it sees a bright pixel, and bit 4 is the trigger, 1 while it is pulled.
+// The real sensor watches the beam as it passes, here it checks the pixel under the aim point in the frame being drawn.
+
+use crate::{HEIGHT, WIDTH};
+
+const LIGHT_NOT_SENSED: u8 = 0b0000_1000;
+const TRIGGER_PULLED: u8 = 0b0001_0000;
+// Sum of the RGB components a pixel needs to register as lit
+const BRIGHTNESS_THRESHOLD: u16 = 0x180;
+
+#[derive(Default)]
+pub struct Zapper {
+    // Aim point in NES pixels, None when pointing off screen
+    pub aim: Option<(usize, usize)>,
+    pub trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper { aim: None, trigger: false }
+    }
+
+    // Value of a $4017 read, given the RGB framebuffer the PPU is drawing
+    pub fn read(&self, framebuffer: &[u8]) -> u8 {
+        let mut data = 0;
+        if !self.senses_light(framebuffer) {
+            data |= LIGHT_NOT_SENSED;
+        }
+        if self.trigger {
+            data |= TRIGGER_PULLED;
+        }
+        data
+    }
+
+    pub fn senses_light(&self, framebuffer: &[u8]) -> bool {
+        match self.aim {
+            Some((x, y)) if x < WIDTH && y < HEIGHT => {
+                let offset = (y * WIDTH + x) * 3;
+                let brightness: u16 = framebuffer[offset..offset + 3].iter().map(|&c| c as u16).sum();
+                brightness >= BRIGHTNESS_THRESHOLD
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framebuffer_with_white_at(x: usize, y: usize) -> Vec<u8> {
+        let mut framebuffer = vec![0; WIDTH * HEIGHT * 3];
+        let offset = (y * WIDTH + x) * 3;
+        framebuffer[offset..offset + 3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+        framebuffer
+    }
+
+    #[test]
+    fn bright_pixel_is_sensed_and_dark_pixel_is_not() {
+        let framebuffer = framebuffer_with_white_at(100, 50);
+        let mut zapper = Zapper::new();
+
+        zapper.aim = Some((100, 50));
+        assert!(zapper.senses_light(&framebuffer));
+        assert_eq!(zapper.read(&framebuffer) & 0x08, 0);
+
+        zapper.aim = Some((101, 50));
+        assert!(!zapper.senses_light(&framebuffer));
+        assert_eq!(zapper.read(&framebuffer) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn off_screen_or_unaimed_senses_nothing() {
+        let framebuffer = vec![0xFF; WIDTH * HEIGHT * 3];
+        let mut zapper = Zapper::new();
+        assert!(!zapper.senses_light(&framebuffer));
+        zapper.aim = Some((WIDTH, 0));
+        assert!(!zapper.senses_light(&framebuffer));
+        zapper.aim = Some((0, HEIGHT));
+        assert_eq!(zapper.read(&framebuffer) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn trigger_sets_bit_4() {
+        let framebuffer = vec![0; WIDTH * HEIGHT * 3];
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(&framebuffer) & 0x10, 0);
+        zapper.trigger = true;
+        assert_eq!(zapper.read(&framebuffer) & 0x10, 0x10);
+    }
+}
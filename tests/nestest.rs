@@ -0,0 +1,46 @@
+// Runs the nestest CPU test ROM in automated mode and compares every executed instruction against the reference log
+// Place nestest.nes and nestest.log in tests/, the test is skipped when either is missing
+// Only the CPU state columns are compared, the log's PPU and cycle columns are ignored
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use nes::bus::Bus;
+use nes::cpu::CPU;
+use nes::ppu::PPU;
+use nes::rom::Rom;
+use nes::trace::trace;
+
+#[test]
+fn nestest_matches_reference_log() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let (Ok(rom_bytes), Ok(log)) = (std::fs::read(dir.join("nestest.nes")), std::fs::read_to_string(dir.join("nestest.log"))) else {
+        println!("Skipping nestest, tests/nestest.nes or tests/nestest.log not found");
+        return;
+    };
+
+    let rom = Rom::new(&rom_bytes).unwrap();
+    let ppu = Rc::new(RefCell::new(PPU::new(rom.c_rom.clone(), rom.mirroring)));
+    let mut cpu = CPU::new(Bus::new(ppu, rom));
+    cpu.reset();
+
+    // Automated mode starts at $C000 instead of the reset vector
+    cpu.register_pc = 0xC000;
+
+    let mut expected = log.lines();
+    let mut line_number = 0;
+    cpu.run_with_callback(|cpu| {
+        let Some(reference) = expected.next() else {
+            return false;
+        };
+        line_number += 1;
+
+        let reference = reference.split(" PPU:").next().unwrap().trim_end();
+        let actual = trace(cpu);
+        assert_eq!(actual.trim_end(), reference, "nestest diverged on line {}", line_number);
+        true
+    });
+
+    println!("nestest matched {} lines", line_number);
+}